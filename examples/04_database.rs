@@ -2,14 +2,9 @@
 use velvet_web::prelude::*;
 
 #[tokio::main]
-async fn main() {
-    let db = sqlite().await;
-    App::new()
-        .route("/", get(index))
-        .inject(db)
-        .start()
-        .await
-        .unwrap();
+async fn main() -> AppResult<()> {
+    let db = sqlite().await?;
+    App::new().route("/", get(index)).inject(db).start().await
 }
 
 async fn index(Extension(db): Extension<Pool<Sqlite>>) -> AppResult<impl IntoResponse> {
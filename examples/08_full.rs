@@ -10,8 +10,7 @@ async fn main() -> AppResult<()> {
     #[folder = "statics"]
     struct S;
     JWT::Secret.setup().await?;
-    let db = sqlite().await;
-    sqlx::migrate!().run(&db).await?;
+    let db = sqlite().await?;
 
     let router = Router::new()
         .route("/", get(index))
@@ -21,6 +20,7 @@ async fn main() -> AppResult<()> {
         )
         .route("/login", get(login));
     App::new()
+        .migrate(&db, sqlx::migrate!())
         .router(router)
         .inject(db)
         .inject(client())
@@ -10,15 +10,15 @@ struct Claims {
 
 #[tokio::main]
 async fn main() -> AppResult<()> {
-    let db = sqlite().await;
-    sqlx::migrate!().run(&db).await?;
+    let db = database().await?;
     let router = Router::new()
         .route("/", get(index))
         // everything above this authorized method will require auth
         .authorized_cookie_claims("/login", |_: Claims| Ok(AuthResult::OK));
     App::new()
+        .migrate(&db, sqlx::migrate!())
         .router(router)
-        .login_flow(&db)
+        .login_flow(&db, LoginConfig::new())
         .await
         .inject(db)
         .start()
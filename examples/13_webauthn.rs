@@ -0,0 +1,37 @@
+#![cfg(all(
+    feature = "sqlite",
+    feature = "auth",
+    feature = "login",
+    feature = "webauthn"
+))]
+
+use velvet_web::prelude::*;
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct Claims {
+    username: String,
+}
+
+#[tokio::main]
+async fn main() -> AppResult<()> {
+    let db = database().await?;
+    let router = Router::new()
+        .route("/", get(index))
+        // everything above this authorized method will require auth
+        .authorized_cookie_claims("/login", |_: Claims| Ok(AuthResult::OK));
+    App::new()
+        .migrate(&db, sqlx::migrate!())
+        .router(router)
+        .login_flow(&db, LoginConfig::new())
+        .await
+        .inject(db)
+        .start()
+        .await
+        .unwrap();
+    Ok(())
+}
+
+async fn index() -> impl IntoResponse {
+    "Hello World, add a passkey at /webauthn/register"
+}
@@ -1,5 +1,6 @@
 use askama_axum::IntoResponse;
 use axum::{
+    http::StatusCode,
     routing::{get, MethodRouter},
     Extension, Router,
 };
@@ -8,10 +9,10 @@ use axum_server::tls_rustls::RustlsConfig;
 use axum_test::{transport_layer::IntoTransportLayer, TestServer};
 use rust_embed::RustEmbed;
 use sentry_tower::{NewSentryLayer, SentryHttpLayer};
-use std::{env, net::SocketAddr, str::FromStr, sync::LazyLock};
+use std::{env, future::Future, net::SocketAddr, pin::Pin, str::FromStr, sync::LazyLock};
 use tokio::net::TcpListener;
 use tower_http::compression::CompressionLayer;
-use tracing::info;
+use tracing::{error, info};
 use tracing_subscriber::{
     filter::EnvFilter,
     fmt::{
@@ -25,16 +26,7 @@ use tracing_subscriber::{
 use crate::errors::AppResult;
 
 #[cfg(feature = "login")]
-#[cfg(feature = "sqlite")]
-type DB = sqlx::Pool<sqlx::Sqlite>;
-
-#[cfg(feature = "login")]
-#[cfg(feature = "mysql")]
-type DB = sqlx::Pool<sqlx::Mysql>;
-
-#[cfg(feature = "login")]
-#[cfg(feature = "postgres")]
-type DB = sqlx::Pool<sqlx::Postgres>;
+use crate::{auth::login::default_flow::LoginConfig, db::DB};
 
 /// An application.
 /// This is handling the main application setup and execution entry point.
@@ -51,8 +43,12 @@ type DB = sqlx::Pool<sqlx::Postgres>;
 #[derive(Default)]
 pub struct App {
     router: Router,
+    migrations: Vec<PendingMigration>,
 }
 
+/// Migrations to run before the server starts listening.
+type PendingMigration = Pin<Box<dyn Future<Output = AppResult<()>> + Send>>;
+
 impl App {
     /// Creates a new application.
     /// Takes care of:
@@ -89,6 +85,7 @@ impl App {
     pub fn router(self, router: Router) -> Self {
         Self {
             router: self.router.merge(router),
+            ..self
         }
     }
 
@@ -98,6 +95,29 @@ impl App {
     pub fn inject<T: Clone + Send + Sync + 'static>(self, t: T) -> Self {
         Self {
             router: self.router.layer(Extension(t)),
+            ..self
+        }
+    }
+
+    #[cfg(feature = "auth")]
+    /// Enables server side sessions, available through the Session extractor.
+    /// Like inject, this applies to the routes added before it, and when the login flow is
+    /// part of them, login and logout will use the session instead of the token cookie.
+    pub fn sessions(self, sessions: crate::auth::session::Sessions) -> Self {
+        self.inject(sessions)
+    }
+
+    #[cfg(feature = "auth")]
+    /// Enables CSRF protection (double submit cookie) on the routes added so far.
+    /// Unsafe methods will require the token, available with the CsrfToken extractor, to be
+    /// sent in the form field "csrf_token" or in the header "x-csrf-token".
+    /// Requests with a bearer token are exempt.
+    pub fn csrf(self) -> Self {
+        Self {
+            router: self
+                .router
+                .layer(axum::middleware::from_fn(crate::auth::csrf::csrf_protect)),
+            ..self
         }
     }
 
@@ -110,12 +130,10 @@ impl App {
             let file = file.as_ref();
             let bytes = T::get(file).unwrap().data.to_vec();
             let mime = mime_guess::from_path(file).first_raw().unwrap_or("");
-            app = Self {
-                router: app.router.route(
-                    format!("/{}", file).as_str(),
-                    get(|| async { ([("Content-Type", mime.to_owned())], bytes).into_response() }),
-                ),
-            };
+            app.router = app.router.route(
+                format!("/{}", file).as_str(),
+                get(|| async { ([("Content-Type", mime.to_owned())], bytes).into_response() }),
+            );
         }
         app
     }
@@ -127,6 +145,27 @@ impl App {
         app
     }
 
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    /// Runs the embedded migrations, usually `sqlx::migrate!()`, before the server starts
+    /// listening. Postgres and MySQL hold an advisory lock meanwhile, so that replicas starting
+    /// together apply them only once, as do the login migrations applied by the login flows.
+    /// A failure is logged, and /status/liveness, /status/readiness and the routes of the
+    /// application answer 503, so that the orchestrator restarts the process to try again.
+    /// Skipped if in .env: MIGRATE_ON_START=false
+    pub fn migrate<D>(self, db: &sqlx::Pool<D>, migrator: sqlx::migrate::Migrator) -> Self
+    where
+        D: sqlx::Database,
+        D::Connection: sqlx::migrate::Migrate,
+    {
+        let db = db.clone();
+        let mut app = self;
+        app.migrations.push(Box::pin(async move {
+            migrator.run(&db).await?;
+            Ok(())
+        }));
+        app
+    }
+
     /// Returns the application as a test harness.
     pub async fn as_test_server(self) -> TestServer {
         TestServer::new(self.build().await.unwrap()).unwrap()
@@ -137,9 +176,10 @@ impl App {
     /// Registration is handled without email confirmation.
     /// Required for setup .env:
     ///  - JWT_SECRET=<secret>
-    pub async fn login_flow(self, db: &DB) -> Self {
-        use crate::auth::login::default_flow::LoginConfig;
-        crate::auth::login::default_flow::add_default_flow(db, LoginConfig::default(), self).await
+    ///
+    /// Paths, redirects, token lifetime and cookie are set with the LoginConfig.
+    pub async fn login_flow(self, db: &DB, config: LoginConfig) -> Self {
+        crate::auth::login::default_flow::add_default_flow(db, config, self).await
     }
 
     #[cfg(feature = "login")]
@@ -152,9 +192,87 @@ impl App {
     ///  - MAIL_USERNAME=user
     ///  - MAIL_PASSWORD=password
     ///  - MAIL_ACCEPT_INVALID_CERTS=true
-    pub async fn login_flow_with_mail(self, db: &DB) -> Self {
-        use crate::auth::login::default_flow::LoginConfig;
-        crate::auth::login::default_flow::add_mail_flow(db, LoginConfig::default(), self).await
+    pub async fn login_flow_with_mail(self, db: &DB, config: LoginConfig) -> Self {
+        crate::auth::login::default_flow::add_mail_flow(db, config, self).await
+    }
+
+    #[cfg(feature = "login")]
+    /// Setup the JSON login api under /api/auth, for single page and mobile apps.
+    /// Registration is handled without email confirmation.
+    /// Required for setup .env:
+    ///  - JWT_SECRET=<secret>
+    pub async fn login_api_flow(self, db: &DB) -> Self {
+        self.login_api_flow_with_config(db, LoginConfig::new())
+            .await
+    }
+
+    #[cfg(feature = "login")]
+    /// Same as login_api_flow, with the token lifetime and lockout of the LoginConfig.
+    pub async fn login_api_flow_with_config(self, db: &DB, config: LoginConfig) -> Self {
+        crate::auth::login::api_flow::add_api_flow(db, config, self).await
+    }
+
+    #[cfg(feature = "login")]
+    /// Setup the JSON login api with registration requiring the mailed code at /api/auth/confirm.
+    /// Required for setup are the same mail environment variables as login_flow_with_mail.
+    pub async fn login_api_flow_with_mail(self, db: &DB) -> Self {
+        self.login_api_flow_with_mail_and_config(db, LoginConfig::new())
+            .await
+    }
+
+    #[cfg(feature = "login")]
+    /// Same as login_api_flow_with_mail, with the token lifetime and lockout of the LoginConfig.
+    pub async fn login_api_flow_with_mail_and_config(self, db: &DB, config: LoginConfig) -> Self {
+        crate::auth::login::api_flow::add_api_mail_flow(db, config, self).await
+    }
+
+    #[cfg(feature = "login")]
+    /// Setup the user administration api under /api/admin/users,
+    /// restricted to bearer tokens having the given role.
+    pub async fn login_admin_api(self, db: &DB, role: &str) -> Self {
+        self.login_admin_api_with_config(db, role, LoginConfig::new())
+            .await
+    }
+
+    #[cfg(feature = "login")]
+    /// Same as login_admin_api, with the user store and the verifier of the LoginConfig.
+    pub async fn login_admin_api_with_config(
+        self,
+        db: &DB,
+        role: &str,
+        config: LoginConfig,
+    ) -> Self {
+        crate::auth::login::admin::add_admin_api(db, role, config, self).await
+    }
+
+    #[cfg(feature = "login")]
+    /// Setup the token introspection (RFC 7662) at /api/auth/introspect,
+    /// for the callers allowed by the config.
+    pub async fn login_introspection(
+        self,
+        db: &DB,
+        config: crate::auth::login::introspection::IntrospectionConfig,
+    ) -> Self {
+        config.setup_verifier().await;
+        crate::auth::login::login_setup(db)
+            .await
+            .expect("Login initialization error");
+        self.router(crate::auth::login::introspection::introspection_router(
+            config,
+        ))
+    }
+
+    #[cfg(feature = "login")]
+    /// Setup the OAuth2 client credentials token endpoint at /api/auth/token,
+    /// for the clients created with create_client.
+    pub async fn login_client_credentials(self, db: &DB) -> Self {
+        crate::auth::jwt::setup_secret()
+            .await
+            .expect("JWT initialization error");
+        crate::auth::login::login_setup(db)
+            .await
+            .expect("Login initialization error");
+        self.router(crate::auth::login::client_credentials::client_credentials_router())
     }
 
     async fn build(self) -> AppResult<BuiltApp> {
@@ -165,9 +283,24 @@ impl App {
             .gzip(true)
             .zstd(true);
         let mut app = self;
+        let failed = app.run_migrations().await;
+        if failed {
+            // The application may not work with the schema, only the status routes answer.
+            app.router = Router::new().fallback(|| async { StatusCode::SERVICE_UNAVAILABLE });
+        }
+        // A failure is reported on liveness as well, so that the orchestrator restarts the
+        // process, and the migrations are tried again.
+        let status = move || async move {
+            if failed {
+                (StatusCode::SERVICE_UNAVAILABLE, "Migrations failed").into_response()
+            } else {
+                "".into_response()
+            }
+        };
         app.router = app
             .router
-            .route("/status/liveness", get(|| async { "".into_response() }));
+            .route("/status/liveness", get(status))
+            .route("/status/readiness", get(status));
         app.router = prometheus(app.router);
         app.router = app
             .router
@@ -202,6 +335,27 @@ impl App {
             })
         }
     }
+
+    /// Runs the pending migrations, returning whether they failed. The error is only logged.
+    async fn run_migrations(&mut self) -> bool {
+        let migrations = std::mem::take(&mut self.migrations);
+        if migrations.is_empty() {
+            return false;
+        }
+        if env::var("MIGRATE_ON_START").is_ok_and(|v| v == "false") {
+            info!("Skipping migrations, MIGRATE_ON_START=false");
+            return false;
+        }
+        info!("Running migrations");
+        for migration in migrations {
+            if let Err(e) = migration.await {
+                error!("Migrations failed: {e}");
+                return true;
+            }
+        }
+        info!("Migrations applied");
+        false
+    }
 }
 
 impl IntoTransportLayer for App {
@@ -232,10 +386,22 @@ impl BuiltApp {
         match self.tls {
             Some(tls_config) => {
                 axum_server::bind_rustls(self.addr, tls_config)
-                    .serve(self.app.router.into_make_service())
+                    .serve(
+                        self.app
+                            .router
+                            .into_make_service_with_connect_info::<SocketAddr>(),
+                    )
                     .await?
             }
-            None => axum::serve(TcpListener::bind(self.addr).await?, self.app.router).await?,
+            None => {
+                axum::serve(
+                    TcpListener::bind(self.addr).await?,
+                    self.app
+                        .router
+                        .into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .await?
+            }
         }
         Ok(())
     }
@@ -275,11 +441,23 @@ impl TestLoginAsCookie for TestServer {
         #[derive(Serialize)]
         struct Claims {
             exp: u64,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            iss: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            aud: Option<String>,
             username: String,
             roles: Vec<String>,
         }
         JWT::Secret.setup().await.unwrap();
+        // The first issuer and audience required by the validation rules.
+        let first_of = |name: &str| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.split(',').next().map(|v| v.trim().to_string()))
+        };
         let token = token_from_claims(&Claims {
+            iss: first_of("JWT_ISSUER"),
+            aud: first_of("JWT_AUDIENCE"),
             exp: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
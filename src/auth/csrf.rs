@@ -0,0 +1,154 @@
+//! CSRF protection for cookie authenticated forms, using the double submit pattern.
+//!
+//! A random token is set in the "csrf" cookie on safe requests, and unsafe requests must send it
+//! back either in the "x-csrf-token" header or in the "csrf_token" field of an urlencoded form.
+//! Requests authenticated with a bearer token are not subject to CSRF and are let through.
+
+use axum::{
+    async_trait,
+    body::{to_bytes, Body},
+    extract::{FromRequestParts, Request},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        request::Parts,
+        Method, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use std::convert::Infallible;
+
+const CSRF_COOKIE: &str = "csrf";
+const CSRF_HEADER: &str = "x-csrf-token";
+const MAX_FORM_SIZE: usize = 1024 * 1024;
+
+/// The CSRF token of the current request, to be rendered in forms as a hidden field named
+/// "csrf_token", or sent by scripts in the "x-csrf-token" header.
+/// Empty if CSRF protection is not enabled with App::csrf.
+#[derive(Debug, Clone, Default)]
+pub struct CsrfToken(pub String);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CsrfToken
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        if let Some(token) = parts.extensions.get::<CsrfToken>() {
+            return Ok(token.clone());
+        }
+        let jar = CookieJar::from_headers(&parts.headers);
+        Ok(CsrfToken(
+            jar.get(CSRF_COOKIE)
+                .map(|c| c.value().to_string())
+                .unwrap_or_default(),
+        ))
+    }
+}
+
+#[derive(Deserialize)]
+struct CsrfForm {
+    csrf_token: Option<String>,
+}
+
+pub(crate) async fn csrf_protect(request: Request, next: Next) -> Response {
+    let jar = CookieJar::from_headers(request.headers());
+    let expected = jar.get(CSRF_COOKIE).map(|c| c.value().to_string());
+    let mut request = request;
+    if is_safe(request.method()) {
+        if let Some(token) = expected {
+            request.extensions_mut().insert(CsrfToken(token));
+            return next.run(request).await;
+        }
+        let token = match new_token() {
+            Ok(token) => token,
+            Err(status) => return status.into_response(),
+        };
+        request.extensions_mut().insert(CsrfToken(token.clone()));
+        let c = Cookie::build((CSRF_COOKIE, token))
+            .path("/")
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .build();
+        return (jar.add(c), next.run(request).await).into_response();
+    }
+    if is_bearer(&request) {
+        return next.run(request).await;
+    }
+    let Some(expected) = expected else {
+        tracing::debug!("CSRF cookie missing");
+        return response_forbidden();
+    };
+    let (submitted, mut request) = match submitted_token(request).await {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    if !submitted.is_some_and(|s| constant_time_eq(s.as_bytes(), expected.as_bytes())) {
+        tracing::debug!("CSRF token missing or not matching");
+        return response_forbidden();
+    }
+    request.extensions_mut().insert(CsrfToken(expected));
+    next.run(request).await
+}
+
+/// Finds the token in the header, or in the form body which then needs to be put back.
+async fn submitted_token(request: Request) -> Result<(Option<String>, Request), Response> {
+    if let Some(token) = request.headers().get(CSRF_HEADER) {
+        let token = token.to_str().ok().map(String::from);
+        return Ok((token, request));
+    }
+    let is_form = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Ok((None, request));
+    }
+    let (parts, body) = request.into_parts();
+    let bytes = to_bytes(body, MAX_FORM_SIZE)
+        .await
+        .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE.into_response())?;
+    let token = serde_urlencoded::from_bytes::<CsrfForm>(&bytes)
+        .ok()
+        .and_then(|f| f.csrf_token);
+    Ok((token, Request::from_parts(parts, Body::from(bytes))))
+}
+
+fn is_safe(method: &Method) -> bool {
+    [Method::GET, Method::HEAD, Method::OPTIONS, Method::TRACE].contains(method)
+}
+
+fn is_bearer(request: &Request) -> bool {
+    request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("Bearer "))
+}
+
+fn new_token() -> Result<String, StatusCode> {
+    let mut token = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut token)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(URL_SAFE_NO_PAD.encode(token))
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn response_forbidden() -> Response {
+    (StatusCode::FORBIDDEN, "403 Forbidden").into_response()
+}
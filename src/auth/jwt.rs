@@ -1,121 +1,551 @@
-use std::{collections::HashMap, env, error::Error, str::FromStr};
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
-use jsonwebtoken::{decode, decode_header, encode, DecodingKey, EncodingKey, Header, Validation};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio::sync::OnceCell;
+use axum::http::Extensions;
+use jsonwebtoken::{
+    decode, decode_header, encode, jwk::JwkSet, Algorithm, DecodingKey, EncodingKey, Header,
+    Validation,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
 
 pub struct VerifiedClaims<T: DeserializeOwned>(pub Header, pub T);
 
+/// Claims of the token, verified by the verifier set up with `JWT` or `set_jwt_verifier`.
 pub fn claims_for<T: DeserializeOwned>(token: &str) -> anyhow::Result<T> {
     Ok(token.parse::<VerifiedClaims<T>>()?.1)
 }
 
 pub(crate) fn token_from_claims<T: Serialize>(claims: &T) -> Result<String, Box<dyn Error>> {
-    let key = JWT_ENCODING_KEY
-        .get()
-        .ok_or("ENCODING_KEY was not initialized")?;
-    let token = encode(&Header::default(), claims, key)?;
-    Ok(token)
+    Ok(jwt_verifier()?.sign(claims)?)
 }
 
-static JWT_DECODING_KEY: OnceCell<DecodingKey> = OnceCell::const_new();
-static JWT_ENCODING_KEY: OnceCell<EncodingKey> = OnceCell::const_new();
-static JWT_DECODING_KEYS_BY_ID: OnceCell<HashMap<String, DecodingKey>> = OnceCell::const_new();
+static JWT_VERIFIER: RwLock<Option<JwtVerifier>> = RwLock::new(None);
+static JWT_VERIFIERS: RwLock<Vec<JwtVerifier>> = RwLock::new(Vec::new());
 
+/// Algorithms accepted from JWK urls when not configured, never the HMAC ones.
+const JWK_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+/// Header `typ` of the client credentials tokens, which only the client extractors accept.
+pub(crate) const CLIENT_TOKEN_TYPE: &str = "client+jwt";
+
+/// Claims validated by the jsonwebtoken crate itself, the others are checked after decoding.
+const SPEC_CLAIMS: &[&str] = &["exp", "nbf", "aud", "iss", "sub"];
+
+/// Rules of the tokens accepted from a key source, besides their signature.
+/// The algorithm in the token header is only used when allowed here.
+/// `JWT::setup` reads them from the environment:
+///  - JWT_ALGORITHMS=HS256 (default, or RS256,PS256,ES256,... for JWK urls)
+///  - JWT_ISSUER=<issuer>[,<issuer>] required issuer
+///  - JWT_AUDIENCE=<audience>[,<audience>] required audience
+///  - JWT_REQUIRED_CLAIMS=exp (default) claims that must be present
+///  - JWT_LEEWAY=60 (default) seconds of clock skew tolerated on exp and nbf
+///
+/// ```rust
+/// use velvet_web::prelude::*;
+/// use std::time::Duration;
+///
+/// let validation = JwtValidation::new()
+///     .algorithms(&[JwtAlgorithm::RS256])
+///     .issuers(&["https://idp.example.com"])
+///     .audiences(&["my-app"])
+///     .required_claims(&["exp", "sub"])
+///     .leeway(Duration::from_secs(30));
+/// ```
+#[derive(Debug, Clone)]
+pub struct JwtValidation {
+    algorithms: Vec<Algorithm>,
+    issuers: Vec<String>,
+    audiences: Vec<String>,
+    required_claims: Vec<String>,
+    leeway: u64,
+}
+
+impl Default for JwtValidation {
+    fn default() -> Self {
+        Self {
+            algorithms: vec![],
+            issuers: vec![],
+            audiences: vec![],
+            required_claims: vec!["exp".to_string()],
+            leeway: 60,
+        }
+    }
+}
+
+impl JwtValidation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The rules configured by the environment variables.
+    pub fn from_env() -> anyhow::Result<Self> {
+        dotenvy::dotenv().ok();
+        let list = |name: &str| -> Vec<String> {
+            env::var(name)
+                .map(|value| {
+                    value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|v| !v.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let mut validation = Self {
+            issuers: list("JWT_ISSUER"),
+            audiences: list("JWT_AUDIENCE"),
+            ..Self::default()
+        };
+        for algorithm in list("JWT_ALGORITHMS") {
+            validation.algorithms.push(Algorithm::from_str(&algorithm)?);
+        }
+        let required = list("JWT_REQUIRED_CLAIMS");
+        if !required.is_empty() {
+            validation.required_claims = required;
+        }
+        if let Ok(leeway) = env::var("JWT_LEEWAY") {
+            validation.leeway = leeway.parse()?;
+        }
+        Ok(validation)
+    }
+
+    /// Algorithms accepted in the token header.
+    /// Default HS256 for the secret, and the asymmetric algorithms for JWK urls.
+    pub fn algorithms(self, algorithms: &[Algorithm]) -> Self {
+        Self {
+            algorithms: algorithms.to_vec(),
+            ..self
+        }
+    }
+
+    /// Issuers accepted in the `iss` claim, which becomes required. Default any.
+    pub fn issuers(self, issuers: &[&str]) -> Self {
+        Self {
+            issuers: issuers.iter().map(|i| i.to_string()).collect(),
+            ..self
+        }
+    }
+
+    /// Audiences accepted in the `aud` claim, which becomes required.
+    /// Default none, rejecting the tokens having an `aud` claim.
+    pub fn audiences(self, audiences: &[&str]) -> Self {
+        Self {
+            audiences: audiences.iter().map(|a| a.to_string()).collect(),
+            ..self
+        }
+    }
+
+    /// Claims that must be present in the token. Default "exp".
+    /// `exp` and `nbf` are validated when present, even if not required.
+    pub fn required_claims(self, claims: &[&str]) -> Self {
+        Self {
+            required_claims: claims.iter().map(|c| c.to_string()).collect(),
+            ..self
+        }
+    }
+
+    /// Clock skew tolerated on the `exp` and `nbf` claims. Default 60 seconds.
+    pub fn leeway(self, leeway: Duration) -> Self {
+        Self {
+            leeway: leeway.as_secs(),
+            ..self
+        }
+    }
+
+    fn validation(
+        &self,
+        defaults: &[Algorithm],
+        algorithm: Algorithm,
+    ) -> anyhow::Result<Validation> {
+        let allowed = match self.algorithms.is_empty() {
+            true => defaults,
+            false => &self.algorithms,
+        };
+        if !allowed.contains(&algorithm) {
+            anyhow::bail!("Algorithm {:?} is not allowed", algorithm);
+        }
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.leeway;
+        let spec = self
+            .required_claims
+            .iter()
+            .filter(|c| SPEC_CLAIMS.contains(&c.as_str()))
+            .collect::<Vec<_>>();
+        validation.set_required_spec_claims(&spec);
+        // Checked whenever present, the required claims only decide whether they may be missing.
+        validation.validate_exp = true;
+        validation.validate_nbf = true;
+        if !self.issuers.is_empty() {
+            validation.set_issuer(&self.issuers);
+        }
+        if !self.audiences.is_empty() {
+            validation.set_audience(&self.audiences);
+        }
+        Ok(validation)
+    }
+
+    fn check_required(&self, claims: &Value) -> anyhow::Result<()> {
+        let iss = (!self.issuers.is_empty()).then_some("iss");
+        let aud = (!self.audiences.is_empty()).then_some("aud");
+        let required = self.required_claims.iter().map(String::as_str);
+        for claim in required.chain(iss).chain(aud) {
+            if claims.get(claim).is_none() {
+                anyhow::bail!("Missing required claim {}", claim);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct SecretKeys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+#[derive(Clone)]
+struct Keys {
+    name: String,
+    secret: Option<SecretKeys>,
+    by_id: HashMap<String, DecodingKey>,
+    secret_rules: JwtValidation,
+    jwk_rules: JwtValidation,
+}
+
+/// Keys and validation rules accepting the tokens of an issuer.
+///
+/// The verifier set up with `JWT` or `set_jwt_verifier` is used by default. Another verifier
+/// injected in the application, or in a part of the router with `Extension`, is used instead by
+/// the claims extractors and the `Authorized*` layers of its routes:
+///
+/// ```rust,no_run
+/// use velvet_web::prelude::*;
+///
+/// # async fn f() -> anyhow::Result<()> {
+/// let idp = JwtVerifier::new("idp")
+///     .jwk_urls(&["https://idp.example.com/.well-known/jwks.json"])
+///     .await?
+///     .jwk_validation(JwtValidation::new().issuers(&["https://idp.example.com"]));
+/// let partners = Router::new()
+///     .route("/partners", get(|| async { "partners" }))
+///     .authorized_bearer_role("partner".to_string())
+///     .layer(Extension(idp));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct JwtVerifier(Arc<Keys>);
+
+impl std::fmt::Debug for JwtVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtVerifier")
+            .field("name", &self.0.name)
+            .field("secret", &self.0.secret.is_some())
+            .field("kids", &self.0.by_id.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl JwtVerifier {
+    /// A verifier without keys, named for the logs.
+    pub fn new(name: &str) -> Self {
+        Self(Arc::new(Keys {
+            name: name.to_string(),
+            secret: None,
+            by_id: HashMap::new(),
+            secret_rules: JwtValidation::default(),
+            jwk_rules: JwtValidation::default(),
+        }))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    /// Signs and verifies the tokens without a known key id with the HMAC secret.
+    pub fn secret(mut self, secret: &[u8]) -> Self {
+        Arc::make_mut(&mut self.0).secret = Some(SecretKeys {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+        });
+        self
+    }
+
+    /// Verifies the tokens whose key id is in the set.
+    pub fn jwk_set(mut self, set: &JwkSet) -> anyhow::Result<Self> {
+        let keys = Arc::make_mut(&mut self.0);
+        for jwk in &set.keys {
+            let kid = jwk
+                .common
+                .key_id
+                .as_ref()
+                .ok_or(anyhow::Error::msg("no kid on jwt response"))?;
+            tracing::debug!(kid, "key id loaded");
+            keys.by_id
+                .insert(kid.to_owned(), DecodingKey::from_jwk(jwk)?);
+        }
+        Ok(self)
+    }
+
+    /// Fetches the key sets of the urls.
+    pub async fn jwk_urls(self, urls: &[&str]) -> anyhow::Result<Self> {
+        let mut verifier = self;
+        for url in urls {
+            tracing::debug!(url, "fetching JWK");
+            let set = crate::client::client()
+                .get(*url)
+                .send()
+                .await?
+                .json::<JwkSet>()
+                .await?;
+            tracing::debug!("fetched {} JWKs", set.keys.len());
+            verifier = verifier.jwk_set(&set)?;
+        }
+        Ok(verifier)
+    }
+
+    /// Rules of the tokens verified with the secret.
+    pub fn secret_validation(mut self, validation: JwtValidation) -> Self {
+        Arc::make_mut(&mut self.0).secret_rules = validation;
+        self
+    }
+
+    /// Rules of the tokens verified with the JWK keys.
+    pub fn jwk_validation(mut self, validation: JwtValidation) -> Self {
+        Arc::make_mut(&mut self.0).jwk_rules = validation;
+        self
+    }
+
+    /// Verifies the signature and the rules of the token.
+    /// Client credentials tokens are rejected, they only pass the client extractors.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str) -> anyhow::Result<VerifiedClaims<T>> {
+        self.verify_typed(token, false)
+    }
+
+    #[cfg(feature = "login")]
+    /// Same as verify, accepting only the client credentials tokens.
+    pub(crate) fn verify_client<T: DeserializeOwned>(
+        &self,
+        token: &str,
+    ) -> anyhow::Result<VerifiedClaims<T>> {
+        self.verify_typed(token, true)
+    }
+
+    fn verify_typed<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        client: bool,
+    ) -> anyhow::Result<VerifiedClaims<T>> {
+        let header = decode_header(token)?;
+        if (header.typ.as_deref() == Some(CLIENT_TOKEN_TYPE)) != client {
+            anyhow::bail!("Unexpected token type {:?}", header.typ);
+        }
+        let jwk = match &header.kid {
+            Some(kid) => {
+                let key = self.0.by_id.get(kid);
+                if key.is_none() {
+                    tracing::debug!(kid, verifier = self.0.name, "key id not loaded");
+                }
+                key
+            }
+            None => None,
+        };
+        let (key, rules, defaults) = match (jwk, &self.0.secret) {
+            (Some(key), _) => (key, &self.0.jwk_rules, JWK_ALGORITHMS),
+            (None, Some(secret)) => (
+                &secret.decoding,
+                &self.0.secret_rules,
+                &[Algorithm::HS256][..],
+            ),
+            (None, None) => anyhow::bail!("No key of verifier {} for the token", self.0.name),
+        };
+        let validation = rules.validation(defaults, header.alg)?;
+        let decoded = decode::<Value>(token, key, &validation)?;
+        rules.check_required(&decoded.claims)?;
+        let claims = serde_json::from_value(decoded.claims)?;
+        Ok(VerifiedClaims(decoded.header, claims))
+    }
+
+    /// Claims of the token, once verified.
+    pub fn claims<T: DeserializeOwned>(&self, token: &str) -> anyhow::Result<T> {
+        Ok(self.verify::<T>(token)?.1)
+    }
+
+    /// Signs the claims with the secret, using HS256.
+    pub fn sign<T: Serialize>(&self, claims: &T) -> anyhow::Result<String> {
+        let secret = self
+            .0
+            .secret
+            .as_ref()
+            .ok_or(anyhow::Error::msg("No secret to sign with"))?;
+        Ok(encode(&Header::default(), claims, &secret.encoding)?)
+    }
+
+    /// Claims of a token signed with the secret, checking only the signature and the expiration.
+    /// Used for internal state that doesn't carry the issuer or audience of the login tokens.
+    #[cfg(feature = "webauthn")]
+    pub(crate) fn own_claims<T: DeserializeOwned>(&self, token: &str) -> anyhow::Result<T> {
+        let keys = self
+            .0
+            .secret
+            .as_ref()
+            .ok_or(anyhow::Error::msg("JWT secret was not set up"))?;
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.validate_aud = false;
+        Ok(decode::<T>(token, &keys.decoding, &validation)?.claims)
+    }
+
+    #[cfg(feature = "login")]
+    /// Same as sign, marking the token as a client credentials token.
+    pub(crate) fn sign_client<T: Serialize>(&self, claims: &T) -> anyhow::Result<String> {
+        let secret = self
+            .0
+            .secret
+            .as_ref()
+            .ok_or(anyhow::Error::msg("No secret to sign with"))?;
+        let header = Header {
+            typ: Some(CLIENT_TOKEN_TYPE.to_string()),
+            ..Header::default()
+        };
+        Ok(encode(&header, claims, &secret.encoding)?)
+    }
+}
+
+/// Replaces the default verifier, also signing the tokens of the login.
+pub fn set_jwt_verifier(verifier: JwtVerifier) {
+    *JWT_VERIFIER.write().unwrap() = Some(verifier);
+}
+
+/// The default verifier.
+pub(crate) fn jwt_verifier() -> anyhow::Result<JwtVerifier> {
+    JWT_VERIFIER
+        .read()
+        .unwrap()
+        .clone()
+        .ok_or(anyhow::Error::msg("JWT was not set up"))
+}
+
+/// The verifier injected for the request, or the default one.
+pub(crate) fn jwt_verifier_for(extensions: &Extensions) -> anyhow::Result<JwtVerifier> {
+    match extensions.get::<JwtVerifier>() {
+        Some(verifier) => Ok(verifier.clone()),
+        None => jwt_verifier(),
+    }
+}
+
+/// Registers a verifier under its name, for the `NamedBearerClaims` and `NamedCookieClaims`
+/// extractors. Replaces the verifier registered with the same name.
+pub fn add_jwt_verifier(verifier: JwtVerifier) {
+    let mut verifiers = JWT_VERIFIERS.write().unwrap();
+    verifiers.retain(|v| v.name() != verifier.name());
+    verifiers.push(verifier);
+}
+
+/// Name of a verifier, selecting it in the `NamedBearerClaims` and `NamedCookieClaims` extractors.
+///
+/// ```
+/// use velvet_web::prelude::*;
+///
+/// struct Idp;
+/// impl VerifierName for Idp {
+///     // JwtVerifier::new("idp")
+///     const NAME: &'static str = "idp";
+/// }
+/// ```
+pub trait VerifierName: Send + Sync + 'static {
+    const NAME: &'static str;
+}
+
+/// The verifier of the name: the one injected for the request when it has the name, otherwise the
+/// registered one, otherwise the default one when it has the name.
+pub(crate) fn jwt_verifier_named(
+    extensions: &Extensions,
+    name: &str,
+) -> anyhow::Result<JwtVerifier> {
+    if let Some(verifier) = extensions.get::<JwtVerifier>().filter(|v| v.name() == name) {
+        return Ok(verifier.clone());
+    }
+    let registered = JWT_VERIFIERS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|v| v.name() == name)
+        .cloned();
+    match registered {
+        Some(verifier) => Ok(verifier),
+        None => jwt_verifier()
+            .ok()
+            .filter(|v| v.name() == name)
+            .ok_or(anyhow::anyhow!("No verifier named {name}")),
+    }
+}
+
+/// Sets up the default verifier from the environment, JWT_SECRET for the secret and
+/// JWK_URLS=<url>[,<url>] for the JWK urls. Each keeps the keys loaded by the other.
 pub enum JWT {
     Secret,
     JwkUrls,
 }
 
-#[derive(Deserialize)]
-struct JWKResponse {
-    keys: Vec<jsonwebtoken::jwk::Jwk>,
-}
-
 impl JWT {
+    /// Loads the keys, with the validation rules of the environment (see `JwtValidation`).
     pub async fn setup(self) -> anyhow::Result<()> {
+        dotenvy::dotenv().ok();
+        self.setup_with(JwtValidation::from_env()?).await
+    }
+
+    /// Loads the keys, accepting the tokens that satisfy the validation rules.
+    /// Each key source keeps its own rules.
+    pub async fn setup_with(self, validation: JwtValidation) -> anyhow::Result<()> {
         dotenvy::dotenv().ok();
         crate::app::logger();
-        match self {
-            JWT::Secret => {
-                let deckey = DecodingKey::from_secret(env::var("JWT_SECRET")?.as_ref());
-                let enckey = EncodingKey::from_secret(env::var("JWT_SECRET")?.as_ref());
-                JWT_DECODING_KEY.get_or_init(|| async move { deckey }).await;
-                JWT_ENCODING_KEY.get_or_init(|| async move { enckey }).await;
-                Ok(())
-            }
+        let current = jwt_verifier().unwrap_or_else(|_| JwtVerifier::new("default"));
+        let verifier = match self {
+            JWT::Secret => current
+                .secret(env::var("JWT_SECRET")?.as_bytes())
+                .secret_validation(validation),
             JWT::JwkUrls => {
                 let urls = env::var("JWK_URLS")?;
-                tracing::debug!(?urls, "fetching JWK from urls");
-                let mut keys_map = HashMap::<String, DecodingKey>::new();
-                for url in urls.split(',') {
-                    load_jwk_from_url(url, &mut keys_map).await?;
-                }
-                JWT_DECODING_KEYS_BY_ID
-                    .get_or_init(|| async move { keys_map })
-                    .await;
-                Ok(())
+                let urls = urls.split(',').collect::<Vec<_>>();
+                current.jwk_urls(&urls).await?.jwk_validation(validation)
             }
-        }
+        };
+        set_jwt_verifier(verifier);
+        Ok(())
     }
 }
 
-async fn load_jwk_from_url(
-    url: &str,
-    keys_map: &mut HashMap<String, DecodingKey>,
-) -> Result<(), anyhow::Error> {
-    let jwk = crate::client::client()
-        .get(url)
-        .send()
-        .await?
-        .json::<JWKResponse>()
-        .await?;
-    tracing::debug!("fetched {} JWKs", jwk.keys.len());
-    for k in jwk.keys {
-        let kid = k
-            .common
-            .key_id
-            .as_ref()
-            .ok_or(anyhow::Error::msg("no kid on jwt response"))?;
-        let dk = DecodingKey::from_jwk(&k)?;
-        tracing::debug!(kid, "key id loaded");
-        keys_map.insert(kid.to_owned(), dk);
+#[cfg(feature = "login")]
+/// Sets up the secret of the default verifier from the environment, unless it has one already,
+/// so that the validation rules given to `JWT::Secret.setup_with` are kept.
+pub(crate) async fn setup_secret() -> anyhow::Result<()> {
+    if jwt_verifier().is_ok_and(|v| v.0.secret.is_some()) {
+        return Ok(());
     }
-    Ok(())
+    JWT::Secret.setup().await
 }
 
 impl<T: DeserializeOwned> FromStr for VerifiedClaims<T> {
     type Err = anyhow::Error;
 
     fn from_str(token: &str) -> Result<Self, Self::Err> {
-        fn get_default_key<'a>() -> Result<&'a DecodingKey, anyhow::Error> {
-            JWT_DECODING_KEY
-                .get()
-                .ok_or(anyhow::Error::msg("DECODING_KEY was not initialized"))
-        }
-
-        let header = decode_header(token)?;
-        let key = match header.kid {
-            Some(kid) => {
-                let map = JWT_DECODING_KEYS_BY_ID.get().ok_or(anyhow::Error::msg(
-                    "JWT_DECODING_KEYS_BY_ID was not initialized",
-                ))?;
-                match map.get(&kid) {
-                    Some(key) => key,
-                    None => {
-                        tracing::debug!(kid, "key id not loaded");
-                        get_default_key()?
-                    }
-                }
-            }
-            None => get_default_key()?,
-        };
-        let mut validation = Validation::new(header.alg);
-        if let Ok(aud) = env::var("JWT_AUDIENCE") {
-            let auds = aud.as_str().split(',').collect::<Vec<&str>>();
-            validation.set_audience(&auds);
-        }
-        let decoded = decode::<T>(token, key, &validation)?;
-        Ok(VerifiedClaims(decoded.header, decoded.claims))
+        jwt_verifier()?.verify(token)
     }
 }
@@ -0,0 +1,565 @@
+//! Administration of the login users.
+//!
+//! The functions can be used directly, or through the admin api mounted with
+//! `App::login_admin_api`, restricted to bearer tokens with the given role. The api of
+//! `App::login_admin_api_with_config` manages the users of the `LoginConfig::user_store`:
+//!  - GET /api/admin/users?q=&page=&per_page= searches by username or email
+//!  - POST /api/admin/users `{username, email, password, roles}` creates a confirmed user
+//!  - GET, DELETE /api/admin/users/:username
+//!  - PUT /api/admin/users/:username/roles `{roles}`
+//!  - PUT /api/admin/users/:username/email `{email}`
+//!  - PUT /api/admin/users/:username/disabled `{disabled}`
+//!  - PUT /api/admin/users/:username/password `{password}`
+//!  - POST /api/admin/users/:username/unlock
+//!  - GET /api/admin/audit?username=&event=&since=&until=&page=&per_page= searches the audit log
+//!  - GET /api/admin/invitations lists the pending invitations
+//!  - POST /api/admin/invitations `{email, roles, expires_in}` invites the email for expires_in
+//!    seconds (default 7 days), returning the invitation and its `link`, also mailed when a
+//!    mailer is injected
+//!  - DELETE /api/admin/invitations/:id
+//!
+//! Every change made through the api is recorded in the audit log, with the admin in its detail.
+
+use super::{
+    api_flow::Problem,
+    audit::{record, search_audit, AuditContext, AuditEvent, AuditPage, AuditQuery},
+    default_flow::{site, LoginConfig},
+    hash_password,
+    invitation::{
+        create_invitation, list_invitations, revoke_invitation, send_invitation, Invitation,
+    },
+    login_setup,
+    password::password_policy,
+    register_user_confirm, register_user_with_roles,
+    store::{IntoUserStore, StoredUser, UserStore},
+    DB,
+};
+use crate::mail::InvitationMail;
+use crate::{
+    app::App,
+    auth::{AuthorizedBearerWithRole, BearerClaims},
+    db::sql,
+    prelude::AppResult,
+};
+use axum::{
+    extract::{Path, Query},
+    http::{StatusCode, Uri},
+    routing::{get, post, put},
+    Extension, Json, Router,
+};
+use lettre::SmtpTransport;
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+
+/// Default validity of an invitation.
+const INVITATION_LIFETIME: u64 = 3600 * 24 * 7;
+
+/// A user of the login.
+#[derive(Debug, Clone, Serialize)]
+pub struct LoginUser {
+    pub userid: String,
+    pub username: String,
+    pub email: Option<String>,
+    pub roles: Vec<String>,
+    pub confirmed: bool,
+    pub disabled: bool,
+    /// Consecutive wrong passwords, see `LoginConfig::lockout`.
+    pub failed_logins: i64,
+}
+
+/// One page of a user search.
+#[derive(Debug, Clone, Serialize)]
+pub struct UserPage {
+    pub users: Vec<LoginUser>,
+    pub total: i64,
+    pub page: u32,
+    pub per_page: u32,
+}
+
+impl From<StoredUser> for LoginUser {
+    fn from(user: StoredUser) -> Self {
+        Self {
+            userid: user.userid,
+            username: user.username,
+            email: user.email,
+            roles: user.roles,
+            confirmed: user.confirmed,
+            disabled: user.disabled,
+            failed_logins: user.failed_logins,
+        }
+    }
+}
+
+/// Creates an already confirmed user with the given roles.
+pub async fn create_user(
+    users: &impl IntoUserStore,
+    username: &str,
+    email: &str,
+    password: &str,
+    roles: &[&str],
+) -> AppResult<()> {
+    let code = register_user_with_roles(users, username, email, password, roles).await?;
+    register_user_confirm(users, username, &code).await
+}
+
+pub async fn get_user(users: &impl IntoUserStore, username: &str) -> AppResult<Option<LoginUser>> {
+    Ok(users
+        .user_store()
+        .find(username)
+        .await?
+        .map(LoginUser::from))
+}
+
+/// Searches users by part of the username or email, ignoring case, ordered by username.
+/// Pages start at 1. `%` and `_` in the query match themselves.
+pub async fn search_users(
+    users: &impl IntoUserStore,
+    query: Option<&str>,
+    page: u32,
+    per_page: u32,
+) -> AppResult<UserPage> {
+    let page = page.max(1);
+    let offset = (page as i64 - 1).saturating_mul(per_page as i64);
+    let (found, total) = users
+        .user_store()
+        .search(query.unwrap_or_default(), offset, per_page as i64)
+        .await?;
+    Ok(UserPage {
+        users: found.into_iter().map(LoginUser::from).collect(),
+        total,
+        page,
+        per_page,
+    })
+}
+
+/// Deletes the user of the store together with its passkeys and refresh tokens, kept in the
+/// database.
+pub async fn delete_user(db: &DB, users: &impl IntoUserStore, username: &str) -> AppResult<()> {
+    let users = users.user_store();
+    let user = users.find(username).await?.ok_or(StatusCode::NOT_FOUND)?;
+    // The credentials go first: should deleting the user fail, it is left without passkeys and
+    // sessions rather than leaving them to a future user with the same id or name.
+    let mut tx = db.begin().await?;
+    sqlx::query(&sql("delete from login_webauthn where userid = ?"))
+        .bind(&user.userid)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(&sql("delete from login_refresh where username = ?"))
+        .bind(username)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    if !users.delete(username).await? {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    Ok(())
+}
+
+/// Replaces the roles of the user, effective from its next token.
+pub async fn set_user_roles(
+    users: &impl IntoUserStore,
+    username: &str,
+    roles: &[&str],
+) -> AppResult<()> {
+    let store = users.user_store();
+    existing(&*store, username).await?;
+    let roles = roles.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+    store.set_roles(username, &roles).await
+}
+
+/// Fails with CONFLICT when the email is used by another user.
+pub async fn set_user_email(
+    users: &impl IntoUserStore,
+    username: &str,
+    email: &str,
+) -> AppResult<()> {
+    let store = users.user_store();
+    existing(&*store, username).await?;
+    store.set_email(username, Some(email)).await
+}
+
+/// A disabled user can't login nor refresh its tokens.
+pub async fn set_user_disabled(
+    db: &DB,
+    users: &impl IntoUserStore,
+    username: &str,
+    disabled: bool,
+) -> AppResult<()> {
+    let users = users.user_store();
+    existing(&*users, username).await?;
+    users.set_disabled(username, disabled).await?;
+    if disabled {
+        revoke_refresh_tokens(db, username).await?;
+    }
+    Ok(())
+}
+
+/// Sets a new password, unlocking the user and revoking its refresh tokens.
+/// Fails with BAD_REQUEST when the password doesn't satisfy the PasswordPolicy.
+pub async fn reset_user_password(
+    db: &DB,
+    users: &impl IntoUserStore,
+    username: &str,
+    password: &str,
+) -> AppResult<()> {
+    password_policy()?.check(username, password)?;
+    let users = users.user_store();
+    existing(&*users, username).await?;
+    users
+        .set_password(username, &hash_password(password)?)
+        .await?;
+    users.record_login(username, true).await?;
+    revoke_refresh_tokens(db, username).await
+}
+
+/// Clears the failed logins of a user locked by the `LoginConfig::lockout`.
+pub async fn unlock_user(users: &impl IntoUserStore, username: &str) -> AppResult<()> {
+    let store = users.user_store();
+    existing(&*store, username).await?;
+    store.record_login(username, true).await
+}
+
+/// Fails with NOT_FOUND when the store hasn't the user.
+async fn existing(users: &dyn UserStore, username: &str) -> AppResult<()> {
+    match users.find(username).await? {
+        Some(_) => Ok(()),
+        None => Err(StatusCode::NOT_FOUND.into()),
+    }
+}
+
+async fn revoke_refresh_tokens(db: &DB, username: &str) -> AppResult<()> {
+    sqlx::query(&sql("delete from login_refresh where username = ?"))
+        .bind(username)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Adds the admin api for the users of the config, restricted to bearer tokens with the role.
+pub(crate) async fn add_admin_api(db: &DB, role: &str, config: LoginConfig, app: App) -> App {
+    config.setup_verifier().await;
+    login_setup(db).await.expect("Login initialization error");
+    let router = config.verify_with(admin_router(role));
+    app.router(router.layer(Extension(Arc::new(config))))
+}
+
+/// Routes of the admin api, restricted to bearer tokens with the role.
+fn admin_router(role: &str) -> Router {
+    Router::new()
+        .route("/api/admin/users", get(search).post(create))
+        .route("/api/admin/users/:username", get(read).delete(delete))
+        .route("/api/admin/users/:username/roles", put(roles))
+        .route("/api/admin/users/:username/email", put(email))
+        .route("/api/admin/users/:username/disabled", put(disabled))
+        .route("/api/admin/users/:username/password", put(password))
+        .route("/api/admin/users/:username/unlock", post(unlock))
+        .route("/api/admin/audit", get(audit_log))
+        .route("/api/admin/invitations", get(invitations).post(invite))
+        .route(
+            "/api/admin/invitations/:id",
+            axum::routing::delete(uninvite),
+        )
+        .authorized_bearer_role(role.to_string())
+}
+
+type ApiResult<T> = Result<T, Problem>;
+
+/// The admin performing the request.
+#[derive(Deserialize)]
+struct Admin {
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: Option<String>,
+    page: Option<u32>,
+    per_page: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct CreateRequest {
+    username: String,
+    email: String,
+    password: String,
+    roles: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct RolesRequest {
+    roles: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct EmailRequest {
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct DisabledRequest {
+    disabled: bool,
+}
+
+#[derive(Deserialize)]
+struct PasswordRequest {
+    password: String,
+}
+
+async fn search(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    Query(query): Query<SearchQuery>,
+) -> ApiResult<Json<UserPage>> {
+    let per_page = query.per_page.unwrap_or(20).clamp(1, 100);
+    let page = search_users(
+        &config.users(&db),
+        query.q.as_deref(),
+        query.page.unwrap_or(1),
+        per_page,
+    )
+    .await?;
+    Ok(Json(page))
+}
+
+async fn create(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    BearerClaims(admin): BearerClaims<Admin>,
+    context: AuditContext,
+    Json(request): Json<CreateRequest>,
+) -> ApiResult<StatusCode> {
+    let roles = request.roles.iter().map(String::as_str).collect::<Vec<_>>();
+    create_user(
+        &config.users(&db),
+        &request.username,
+        &request.email,
+        &request.password,
+        &roles,
+    )
+    .await
+    .map_err(|e| match e.status() {
+        StatusCode::CONFLICT => Problem::new(StatusCode::CONFLICT, "User already exists"),
+        _ => Problem::from(e),
+    })?;
+    let detail = format!("by {}", admin.username);
+    record(
+        &db,
+        AuditEvent::Register,
+        &request.username,
+        &context,
+        Some(&detail),
+    )
+    .await;
+    Ok(StatusCode::CREATED)
+}
+
+async fn read(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    Path(username): Path<String>,
+) -> ApiResult<Json<LoginUser>> {
+    let user = get_user(&config.users(&db), &username)
+        .await?
+        .ok_or(Problem::new(StatusCode::NOT_FOUND, "User not found"))?;
+    Ok(Json(user))
+}
+
+async fn delete(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    BearerClaims(admin): BearerClaims<Admin>,
+    context: AuditContext,
+    Path(username): Path<String>,
+) -> ApiResult<StatusCode> {
+    delete_user(&db, &config.users(&db), &username).await?;
+    let detail = format!("by {}", admin.username);
+    record(&db, AuditEvent::Delete, &username, &context, Some(&detail)).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn roles(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    BearerClaims(admin): BearerClaims<Admin>,
+    context: AuditContext,
+    Path(username): Path<String>,
+    Json(request): Json<RolesRequest>,
+) -> ApiResult<StatusCode> {
+    let roles = request.roles.iter().map(String::as_str).collect::<Vec<_>>();
+    set_user_roles(&config.users(&db), &username, &roles).await?;
+    let detail = format!("{} by {}", roles.join(","), admin.username);
+    record(
+        &db,
+        AuditEvent::RolesChange,
+        &username,
+        &context,
+        Some(&detail),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn email(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    BearerClaims(admin): BearerClaims<Admin>,
+    context: AuditContext,
+    Path(username): Path<String>,
+    Json(request): Json<EmailRequest>,
+) -> ApiResult<StatusCode> {
+    set_user_email(&config.users(&db), &username, &request.email).await?;
+    let detail = format!("{} by {}", request.email, admin.username);
+    record(
+        &db,
+        AuditEvent::EmailChange,
+        &username,
+        &context,
+        Some(&detail),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn disabled(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    BearerClaims(admin): BearerClaims<Admin>,
+    context: AuditContext,
+    Path(username): Path<String>,
+    Json(request): Json<DisabledRequest>,
+) -> ApiResult<StatusCode> {
+    set_user_disabled(&db, &config.users(&db), &username, request.disabled).await?;
+    let event = if request.disabled {
+        AuditEvent::Disable
+    } else {
+        AuditEvent::Enable
+    };
+    let detail = format!("by {}", admin.username);
+    record(&db, event, &username, &context, Some(&detail)).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn password(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    BearerClaims(admin): BearerClaims<Admin>,
+    context: AuditContext,
+    Path(username): Path<String>,
+    Json(request): Json<PasswordRequest>,
+) -> ApiResult<StatusCode> {
+    reset_user_password(&db, &config.users(&db), &username, &request.password).await?;
+    let detail = format!("by {}", admin.username);
+    record(
+        &db,
+        AuditEvent::PasswordChange,
+        &username,
+        &context,
+        Some(&detail),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn unlock(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    BearerClaims(admin): BearerClaims<Admin>,
+    context: AuditContext,
+    Path(username): Path<String>,
+) -> ApiResult<StatusCode> {
+    unlock_user(&config.users(&db), &username).await?;
+    let detail = format!("by {}", admin.username);
+    record(&db, AuditEvent::Unlock, &username, &context, Some(&detail)).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn audit_log(
+    Extension(db): Extension<DB>,
+    Query(query): Query<AuditQuery>,
+) -> ApiResult<Json<AuditPage>> {
+    Ok(Json(search_audit(&db, &query).await?))
+}
+
+async fn invitations(Extension(db): Extension<DB>) -> ApiResult<Json<Vec<Invitation>>> {
+    Ok(Json(list_invitations(&db).await?))
+}
+
+#[derive(Deserialize)]
+struct InviteRequest {
+    email: String,
+    roles: Vec<String>,
+    expires_in: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct InviteResponse {
+    #[serde(flatten)]
+    invitation: Invitation,
+    link: String,
+}
+
+async fn invite(
+    Extension(db): Extension<DB>,
+    BearerClaims(admin): BearerClaims<Admin>,
+    mailer: Option<Extension<SmtpTransport>>,
+    config: Option<Extension<Arc<LoginConfig>>>,
+    (url, context): (Uri, AuditContext),
+    Json(request): Json<InviteRequest>,
+) -> ApiResult<(StatusCode, Json<InviteResponse>)> {
+    let roles = request.roles.iter().map(String::as_str).collect::<Vec<_>>();
+    let lifetime = Duration::from_secs(request.expires_in.unwrap_or(INVITATION_LIFETIME));
+    let invitation =
+        create_invitation(&db, &request.email, &roles, lifetime, Some(&admin.username)).await?;
+    let config = config.map(|Extension(config)| config).unwrap_or_default();
+    let link = format!(
+        "{}{}",
+        site(&url),
+        config.invitation_url(&invitation.token()?)
+    );
+    if let Some(Extension(mailer)) = mailer {
+        let mail = InvitationMail {
+            site: site(&url),
+            link: link.clone(),
+            email: invitation.email.clone(),
+            invited_by: invitation.invited_by.clone(),
+        };
+        send_invitation(mailer, config.templates.as_ref(), &mail)?;
+    }
+    let detail = format!("by {}", admin.username);
+    record(
+        &db,
+        AuditEvent::Invite,
+        &request.email,
+        &context,
+        Some(&detail),
+    )
+    .await;
+    Ok((
+        StatusCode::CREATED,
+        Json(InviteResponse { invitation, link }),
+    ))
+}
+
+async fn uninvite(
+    Extension(db): Extension<DB>,
+    BearerClaims(admin): BearerClaims<Admin>,
+    context: AuditContext,
+    Path(id): Path<String>,
+) -> ApiResult<StatusCode> {
+    let invitation = list_invitations(&db)
+        .await?
+        .into_iter()
+        .find(|i| i.id == id)
+        .ok_or(Problem::new(StatusCode::NOT_FOUND, "Invitation not found"))?;
+    revoke_invitation(&db, &id).await?;
+    let detail = format!("{id} by {}", admin.username);
+    record(
+        &db,
+        AuditEvent::Uninvite,
+        &invitation.email,
+        &context,
+        Some(&detail),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,584 @@
+//! JSON variant of the login flow, for single page and mobile apps.
+//!
+//! All routes are mounted under `/api/auth`:
+//!  - POST /register `{username, email, password, invitation}`, unless the registration is
+//!    closed, with the default roles or the roles of the invitation. The invitation token is
+//!    required when the registration is by invitation only.
+//!  - POST /confirm `{username, code}` (only with mail confirmation)
+//!  - POST /confirm/resend `{username}` mails a new code (only with mail confirmation)
+//!  - POST /email `{email}` with the access token as bearer mails a verification code
+//!    to the new address (only with mail confirmation), impersonation tokens are refused
+//!  - POST /email/confirm `{username, code}` changes the email and notifies the old address
+//!  - POST /login `{username, password}` returns `{access_token, refresh_token, expires_in}`
+//!  - POST /refresh `{refresh_token}` returns a new token pair, the old refresh token is revoked
+//!    and can only be used once
+//!  - POST /logout `{refresh_token}` revokes the refresh token
+//!  - GET /me or /userinfo with the access token as bearer returns the claims of the token and
+//!    the `user` of the login table
+//!
+//! The token lifetime, claims hook and lockout of the LoginConfig apply, and refresh tokens are
+//! only stored as their SHA-256 hash. Errors are returned as problem details (RFC 7807).
+
+use super::{
+    admin::{get_user, LoginUser},
+    audit::{record, AuditContext, AuditEvent},
+    confirm_email_change,
+    default_flow::{LoginConfig, INVITATION_ONLY},
+    invitation::register_with_invitation,
+    login_setup, now, register_user_confirm, register_user_with_roles, request_email_change,
+    resend_confirmation,
+    templates::{ConfirmationMail, EmailChangeMail, EmailChangedMail},
+    user_claims, Act, Claims, DB,
+};
+use crate::{app::App, auth::BearerClaims, db::sql, errors::AppError, mail::send_multipart};
+use axum::{
+    async_trait,
+    extract::{FromRequest, Request},
+    http::{header::CONTENT_TYPE, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use lettre::SmtpTransport;
+use ring::digest::{digest, SHA256};
+use sentry::types::random_uuid;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use tracing::warn;
+
+const PREFIX: &str = "/api/auth";
+const REFRESH_TOKEN_LIFETIME: u64 = 3600 * 24 * 30;
+
+pub async fn add_api_flow(db: &DB, config: LoginConfig, app: App) -> App {
+    add_flow(db, config, app, false).await
+}
+
+pub async fn add_api_mail_flow(db: &DB, config: LoginConfig, app: App) -> App {
+    add_flow(db, config, app, true)
+        .await
+        .inject(crate::mail::mailer())
+}
+
+async fn add_flow(db: &DB, config: LoginConfig, app: App, mail: bool) -> App {
+    config.setup_verifier().await;
+    login_setup(db).await.expect("Login initialization error");
+    let mut router = Router::new()
+        .route(&path("/login"), post(login))
+        .route(&path("/refresh"), post(refresh))
+        .route(&path("/logout"), post(logout))
+        .route(&path("/me"), get(userinfo))
+        .route(&path("/userinfo"), get(userinfo));
+    if config.registration_open {
+        let register = if mail {
+            post(register_send_mail)
+        } else {
+            post(register)
+        };
+        router = router.route(&path("/register"), register);
+    }
+    if mail {
+        router = router
+            .route(&path("/confirm"), post(confirm))
+            .route(&path("/confirm/resend"), post(resend))
+            .route(&path("/email"), post(email_change))
+            .route(&path("/email/confirm"), post(email_confirm));
+    }
+    let router = config.verify_with(router);
+    app.router(router.layer(Extension(Arc::new(config))))
+}
+
+fn path(path: &str) -> String {
+    format!("{}{}", PREFIX, path)
+}
+
+/// Problem details (RFC 7807) returned by the JSON APIs.
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, detail: &str) -> Self {
+        Self {
+            kind: "about:blank".to_string(),
+            title: status.canonical_reason().unwrap_or_default().to_string(),
+            status: status.as_u16(),
+            detail: detail.to_string(),
+        }
+    }
+
+    pub(crate) fn internal<E: std::fmt::Debug>(e: E) -> Self {
+        warn!("Login api error: {:?}", e);
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "Internal error")
+    }
+}
+
+impl From<AppError> for Problem {
+    fn from(e: AppError) -> Self {
+        let status = e.status();
+        if status.is_server_error() {
+            return Self::internal(e);
+        }
+        Self::new(status, &e.to_string())
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let body = serde_json::to_string(&self).unwrap_or_default();
+        (status, [(CONTENT_TYPE, "application/problem+json")], body).into_response()
+    }
+}
+
+type ApiResult<T> = Result<T, Problem>;
+
+/// Json extractor rejecting with problem details.
+struct ApiJson<T>(T);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for ApiJson<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned,
+{
+    type Rejection = Problem;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state)
+            .await
+            .map_err(|e| Problem::new(e.status(), &e.body_text()))?;
+        Ok(Self(value))
+    }
+}
+
+#[derive(Deserialize)]
+struct RegisterRequest {
+    username: String,
+    #[serde(default)]
+    email: String,
+    password: String,
+    invitation: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ConfirmRequest {
+    username: String,
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(Serialize)]
+struct RegisterResponse {
+    username: String,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    expires_in: u64,
+}
+
+#[derive(Deserialize)]
+struct Me {
+    username: String,
+    act: Option<Act>,
+}
+
+#[derive(Serialize)]
+struct UserInfo {
+    #[serde(flatten)]
+    claims: Map<String, Value>,
+    user: Option<LoginUser>,
+}
+
+async fn register(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    context: AuditContext,
+    ApiJson(request): ApiJson<RegisterRequest>,
+) -> ApiResult<(StatusCode, Json<RegisterResponse>)> {
+    if let Some(code) = register_new_user(&db, &config, &context, &request).await? {
+        register_user_confirm(&config.users(&db), &request.username, &code)
+            .await
+            .map_err(Problem::internal)?;
+    }
+    Ok((
+        StatusCode::CREATED,
+        Json(RegisterResponse {
+            username: request.username,
+        }),
+    ))
+}
+
+async fn register_send_mail(
+    Extension(mailer): Extension<SmtpTransport>,
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    url: Uri,
+    context: AuditContext,
+    ApiJson(request): ApiJson<RegisterRequest>,
+) -> ApiResult<(StatusCode, Json<RegisterResponse>)> {
+    // users registering with an invitation are confirmed already
+    if let Some(code) = register_new_user(&db, &config, &context, &request).await? {
+        send_confirmation(
+            mailer,
+            &config,
+            &url,
+            &request.username,
+            &request.email,
+            code,
+        )?;
+    }
+    Ok((
+        StatusCode::CREATED,
+        Json(RegisterResponse {
+            username: request.username,
+        }),
+    ))
+}
+
+#[derive(Deserialize)]
+struct EmailRequest {
+    email: String,
+}
+
+async fn email_change(
+    Extension(mailer): Extension<SmtpTransport>,
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    claims: Result<BearerClaims<Me>, Response>,
+    url: Uri,
+    ApiJson(request): ApiJson<EmailRequest>,
+) -> ApiResult<StatusCode> {
+    let BearerClaims(me) =
+        claims.map_err(|_| Problem::new(StatusCode::UNAUTHORIZED, "Missing or invalid token"))?;
+    if me.act.is_some() {
+        return Err(Problem::new(
+            StatusCode::FORBIDDEN,
+            "Impersonations can't change the email",
+        ));
+    }
+    let code = request_email_change(&config.users(&db), &me.username, &request.email).await?;
+    let mail = EmailChangeMail {
+        link: site(&url),
+        site: site(&url),
+        username: me.username,
+        email: request.email.clone(),
+        code,
+    };
+    let plain = config.templates.email_change_mail_text(&mail)?;
+    let html = config.templates.email_change_mail_html(&mail)?;
+    send_multipart(mailer, &request.email, plain, html)?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn email_confirm(
+    Extension(mailer): Extension<SmtpTransport>,
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    url: Uri,
+    context: AuditContext,
+    ApiJson(request): ApiJson<ConfirmRequest>,
+) -> ApiResult<StatusCode> {
+    let (old, new) =
+        confirm_email_change(&config.users(&db), &request.username, &request.code).await?;
+    record(
+        &db,
+        AuditEvent::EmailChange,
+        &request.username,
+        &context,
+        Some(&new),
+    )
+    .await;
+    if let Some(old) = old {
+        let mail = EmailChangedMail {
+            site: site(&url),
+            username: request.username,
+            email: new,
+        };
+        let plain = config.templates.email_changed_mail_text(&mail)?;
+        let html = config.templates.email_changed_mail_html(&mail)?;
+        send_multipart(mailer, &old, plain, html)?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn site(url: &Uri) -> String {
+    let scheme = url
+        .scheme()
+        .map(|s| format!("{}://", s))
+        .unwrap_or_default();
+    format!(
+        "{}{}",
+        scheme,
+        url.authority().map(|s| s.to_string()).unwrap_or_default()
+    )
+}
+
+fn send_confirmation(
+    mailer: SmtpTransport,
+    config: &LoginConfig,
+    url: &Uri,
+    username: &str,
+    email: &str,
+    code: String,
+) -> ApiResult<()> {
+    let site = site(url);
+    let mail = ConfirmationMail {
+        link: site.clone(),
+        site,
+        username: username.to_string(),
+        code,
+    };
+    let plain = config.templates.confirmation_mail_text(&mail)?;
+    let html = config.templates.confirmation_mail_html(&mail)?;
+    send_multipart(mailer, email, plain, html)?;
+    Ok(())
+}
+
+/// Registers the user with the default roles, or with the invitation's roles, returning the
+/// confirmation code when the email still needs to be confirmed.
+async fn register_new_user(
+    db: &DB,
+    config: &LoginConfig,
+    context: &AuditContext,
+    request: &RegisterRequest,
+) -> ApiResult<Option<String>> {
+    let users = config.users(db);
+    let invitation = request.invitation.as_deref().filter(|i| !i.is_empty());
+    let registered = match invitation {
+        Some(token) => {
+            register_with_invitation(db, &users, token, &request.username, &request.password)
+                .await
+                .map(|invitation| {
+                    (
+                        None,
+                        invitation.invited_by.map(|by| format!("invited by {by}")),
+                    )
+                })
+        }
+        None if config.invitation_only => {
+            Err(AppError::new(StatusCode::FORBIDDEN, INVITATION_ONLY))
+        }
+        None => {
+            let roles = config
+                .default_roles
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            register_user_with_roles(
+                &users,
+                &request.username,
+                &request.email,
+                &request.password,
+                &roles,
+            )
+            .await
+            .map(|code| (Some(code), None))
+        }
+    };
+    let (code, detail) = registered.map_err(|e| {
+        warn!("Registration failed: {:?}", e);
+        Problem::from(e)
+    })?;
+    record(
+        db,
+        AuditEvent::Register,
+        &request.username,
+        context,
+        detail.as_deref(),
+    )
+    .await;
+    Ok(code)
+}
+
+async fn confirm(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    context: AuditContext,
+    ApiJson(request): ApiJson<ConfirmRequest>,
+) -> ApiResult<StatusCode> {
+    register_user_confirm(&config.users(&db), &request.username, &request.code).await?;
+    record(&db, AuditEvent::Confirm, &request.username, &context, None).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct ResendRequest {
+    username: String,
+}
+
+async fn resend(
+    Extension(mailer): Extension<SmtpTransport>,
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    url: Uri,
+    ApiJson(request): ApiJson<ResendRequest>,
+) -> ApiResult<StatusCode> {
+    let (email, code) = resend_confirmation(&config.users(&db), &request.username).await?;
+    send_confirmation(mailer, &config, &url, &request.username, &email, code)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn login(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    context: AuditContext,
+    ApiJson(request): ApiJson<LoginRequest>,
+) -> ApiResult<Json<TokenResponse>> {
+    let claims = config
+        .authenticate(&db, &request.username, &request.password)
+        .await;
+    let claims = match claims {
+        Ok(claims) => claims,
+        Err(e) => {
+            warn!("Login failed: {:?}", e);
+            let detail = e.to_string();
+            record(
+                &db,
+                AuditEvent::LoginFailure,
+                &request.username,
+                &context,
+                Some(&detail),
+            )
+            .await;
+            return Err(Problem::new(
+                StatusCode::UNAUTHORIZED,
+                "Invalid username or password",
+            ));
+        }
+    };
+    record(
+        &db,
+        AuditEvent::LoginSuccess,
+        &request.username,
+        &context,
+        None,
+    )
+    .await;
+    token_response(&db, &config, claims).await.map(Json)
+}
+
+async fn refresh(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    ApiJson(request): ApiJson<RefreshRequest>,
+) -> ApiResult<Json<TokenResponse>> {
+    let invalid = || Problem::new(StatusCode::UNAUTHORIZED, "Invalid refresh token");
+    let row: Option<(String, i64)> = sqlx::query_as(&sql(
+        "select username, expires from login_refresh where token = ?",
+    ))
+    .bind(refresh_token_hash(&request.refresh_token))
+    .fetch_optional(&db)
+    .await
+    .map_err(Problem::internal)?;
+    let Some((username, expires)) = row else {
+        return Err(invalid());
+    };
+    // Only the request revoking the token rotates it, concurrent uses of the same token fail.
+    if !revoke_refresh_token(&db, &request.refresh_token).await? || expires < now() as i64 {
+        return Err(invalid());
+    }
+    let claims = user_claims(&*config.users(&db), &username)
+        .await
+        .map_err(|e| {
+            warn!("Refresh failed: {:?}", e);
+            invalid()
+        })?;
+    token_response(&db, &config, claims).await.map(Json)
+}
+
+/// Signs the access token with the lifetime and claims hook of the config, together with a new
+/// refresh token.
+async fn token_response(db: &DB, config: &LoginConfig, claims: Claims) -> ApiResult<TokenResponse> {
+    let claims = config.claims(db, claims).await.map_err(Problem::from)?;
+    let access_token = config
+        .verifier()
+        .and_then(|verifier| verifier.sign(&claims))
+        .map_err(|e| Problem::internal(e.to_string()))?;
+    let refresh_token = create_refresh_token(db, &claims.username).await?;
+    Ok(TokenResponse {
+        access_token,
+        refresh_token,
+        expires_in: claims.exp.saturating_sub(claims.iat),
+    })
+}
+
+async fn logout(
+    Extension(db): Extension<DB>,
+    context: AuditContext,
+    ApiJson(request): ApiJson<RefreshRequest>,
+) -> ApiResult<StatusCode> {
+    let row: Option<(String,)> =
+        sqlx::query_as(&sql("select username from login_refresh where token = ?"))
+            .bind(refresh_token_hash(&request.refresh_token))
+            .fetch_optional(&db)
+            .await
+            .map_err(Problem::internal)?;
+    let revoked = revoke_refresh_token(&db, &request.refresh_token).await?;
+    if let (true, Some((username,))) = (revoked, row) {
+        record(&db, AuditEvent::Logout, &username, &context, None).await;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn userinfo(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<LoginConfig>>,
+    claims: Result<BearerClaims<Map<String, Value>>, Response>,
+) -> ApiResult<Json<UserInfo>> {
+    let BearerClaims(claims) =
+        claims.map_err(|_| Problem::new(StatusCode::UNAUTHORIZED, "Missing or invalid token"))?;
+    let user = match claims.get("username").and_then(Value::as_str) {
+        Some(username) => get_user(&config.users(&db), username).await?,
+        None => None,
+    };
+    Ok(Json(UserInfo { claims, user }))
+}
+
+async fn create_refresh_token(db: &DB, username: &str) -> ApiResult<String> {
+    let token = format!("{}{}", random_uuid().simple(), random_uuid().simple());
+    sqlx::query(&sql(
+        "insert into login_refresh (token, username, expires) values (?, ?, ?)",
+    ))
+    .bind(refresh_token_hash(&token))
+    .bind(username)
+    .bind((now() + REFRESH_TOKEN_LIFETIME) as i64)
+    .execute(db)
+    .await
+    .map_err(Problem::internal)?;
+    Ok(token)
+}
+
+/// Deletes the refresh token, returning whether it existed.
+async fn revoke_refresh_token(db: &DB, token: &str) -> ApiResult<bool> {
+    let result = sqlx::query(&sql("delete from login_refresh where token = ?"))
+        .bind(refresh_token_hash(token))
+        .execute(db)
+        .await
+        .map_err(Problem::internal)?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Key of the refresh token in the login_refresh table.
+pub(super) fn refresh_token_hash(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(digest(&SHA256, token.as_bytes()))
+}
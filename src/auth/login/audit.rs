@@ -0,0 +1,294 @@
+//! Audit log of the authentication events.
+//!
+//! The login flows and the admin api record logins, logouts, registrations, confirmations,
+//! impersonations, email, password and role changes, and the users deleted, disabled, enabled
+//! or unlocked by the admins in the `login_audit` table, together with the IP and user
+//! agent of the request. Every record is also emitted as a tracing event with the target
+//! `velvet_web::audit`, and can be searched with `search_audit` or through the admin api:
+//!  - GET /api/admin/audit?username=&event=&since=&until=&page=&per_page=
+
+use super::{now, DB};
+use crate::{db::sql, prelude::AppResult};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header::USER_AGENT, request::Parts},
+};
+use sentry::types::random_uuid;
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::Infallible,
+    env, fmt,
+    net::{IpAddr, SocketAddr},
+};
+use tracing::{info, warn};
+
+/// Longest user agent kept in a record.
+const MAX_USER_AGENT: usize = 512;
+/// Longest username and detail kept in a record.
+const MAX_USERNAME: usize = 255;
+const MAX_DETAIL: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    LoginSuccess,
+    LoginFailure,
+    Logout,
+    Register,
+    Confirm,
+    EmailChange,
+    PasswordChange,
+    RolesChange,
+    ImpersonationStart,
+    ImpersonationEnd,
+    Invite,
+    Uninvite,
+    Delete,
+    Disable,
+    Enable,
+    Unlock,
+}
+
+impl AuditEvent {
+    /// Name of the event as stored in the table.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::LoginSuccess => "login_success",
+            Self::LoginFailure => "login_failure",
+            Self::Logout => "logout",
+            Self::Register => "register",
+            Self::Confirm => "confirm",
+            Self::EmailChange => "email_change",
+            Self::PasswordChange => "password_change",
+            Self::RolesChange => "roles_change",
+            Self::ImpersonationStart => "impersonation_start",
+            Self::ImpersonationEnd => "impersonation_end",
+            Self::Invite => "invite",
+            Self::Uninvite => "uninvite",
+            Self::Delete => "delete",
+            Self::Disable => "disable",
+            Self::Enable => "enable",
+            Self::Unlock => "unlock",
+        }
+    }
+}
+
+impl fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Origin of the request being audited, available as an extractor.
+/// The IP is the peer address of the connection. Behind proxies listed in
+/// TRUSTED_PROXIES=<ip>[,<ip>] (or `*` for any), it is the last address of the X-Forwarded-For
+/// header not added by one of them.
+#[derive(Debug, Clone, Default)]
+pub struct AuditContext {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuditContext
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let proxies = env::var("TRUSTED_PROXIES").unwrap_or_default();
+        let mut ip = peer;
+        if is_trusted_proxy(&proxies, peer) {
+            let forwarded = parts
+                .headers
+                .get("x-forwarded-for")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+                .collect::<Vec<_>>();
+            // Proxies append the address they received the request from, so the client is the
+            // last one not added by a trusted proxy.
+            ip = forwarded
+                .iter()
+                .rev()
+                .find(|ip| !is_trusted_proxy(&proxies, Some(**ip)))
+                .or(forwarded.first())
+                .copied()
+                .or(peer);
+        }
+        let ip = ip.map(|ip| ip.to_string());
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(|ua| ua.chars().take(MAX_USER_AGENT).collect());
+        Ok(Self { ip, user_agent })
+    }
+}
+
+/// Whether the address is one of the comma separated proxies, always true for `*`.
+fn is_trusted_proxy(proxies: &str, ip: Option<IpAddr>) -> bool {
+    proxies
+        .split(',')
+        .map(str::trim)
+        .any(|proxy| proxy == "*" || ip.is_some_and(|ip| proxy.parse() == Ok(ip)))
+}
+
+/// A record of the audit log.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    pub id: String,
+    /// Seconds since the epoch.
+    pub created: i64,
+    pub event: String,
+    pub username: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub detail: Option<String>,
+}
+
+type AuditRow = (
+    String,
+    i64,
+    String,
+    String,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+impl From<AuditRow> for AuditRecord {
+    fn from(row: AuditRow) -> Self {
+        Self {
+            id: row.0,
+            created: row.1,
+            event: row.2,
+            username: row.3,
+            ip: row.4,
+            user_agent: row.5,
+            detail: row.6,
+        }
+    }
+}
+
+/// Filters of an audit search, all optional. Pages start at 1.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditQuery {
+    pub username: Option<String>,
+    /// Name of the event, like "login_failure".
+    pub event: Option<String>,
+    /// Records created at or after, in seconds since the epoch.
+    pub since: Option<i64>,
+    /// Records created before, in seconds since the epoch.
+    pub until: Option<i64>,
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+}
+
+/// One page of an audit search, newest first.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditPage {
+    pub records: Vec<AuditRecord>,
+    pub total: i64,
+    pub page: u32,
+    pub per_page: u32,
+}
+
+/// Records the event of the user, and emits it as a tracing event.
+pub async fn audit(
+    db: &DB,
+    event: AuditEvent,
+    username: &str,
+    context: &AuditContext,
+    detail: Option<&str>,
+) -> AppResult<()> {
+    info!(
+        target: "velvet_web::audit",
+        event = event.as_str(),
+        username,
+        ip = context.ip.as_deref(),
+        user_agent = context.user_agent.as_deref(),
+        detail,
+        "Audit"
+    );
+    let truncate = |value: &str, max: usize| value.chars().take(max).collect::<String>();
+    sqlx::query(&sql(
+        "insert into login_audit (id, created, event, username, ip, user_agent, detail) \
+        values(?, ?, ?, ?, ?, ?, ?)",
+    ))
+    .bind(random_uuid().to_string())
+    .bind(now() as i64)
+    .bind(event.as_str())
+    .bind(truncate(username, MAX_USERNAME))
+    .bind(&context.ip)
+    .bind(&context.user_agent)
+    .bind(detail.map(|detail| truncate(detail, MAX_DETAIL)))
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Same as audit, logging a failure to record instead of failing the audited request.
+pub(super) async fn record(
+    db: &DB,
+    event: AuditEvent,
+    username: &str,
+    context: &AuditContext,
+    detail: Option<&str>,
+) {
+    if let Err(e) = audit(db, event, username, context, detail).await {
+        warn!("Audit of {} failed: {:?}", event, e);
+    }
+}
+
+const AUDIT_FILTER: &str = "(? is null or username = ?) and (? is null or event = ?) \
+    and (? is null or created >= ?) and (? is null or created < ?)";
+
+/// Searches the audit log, newest first. Pages default to 50 records, at most 500.
+pub async fn search_audit(db: &DB, query: &AuditQuery) -> AppResult<AuditPage> {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(50).clamp(1, 500);
+    let count = sql(&format!(
+        "select count(*) from login_audit where {AUDIT_FILTER}"
+    ));
+    let (total,): (i64,) = sqlx::query_as(&count)
+        .bind(&query.username)
+        .bind(&query.username)
+        .bind(&query.event)
+        .bind(&query.event)
+        .bind(query.since)
+        .bind(query.since)
+        .bind(query.until)
+        .bind(query.until)
+        .fetch_one(db)
+        .await?;
+    let select = sql(&format!(
+        "select id, created, event, username, ip, user_agent, detail from login_audit \
+        where {AUDIT_FILTER} order by created desc, id limit ? offset ?"
+    ));
+    let rows: Vec<AuditRow> = sqlx::query_as(&select)
+        .bind(&query.username)
+        .bind(&query.username)
+        .bind(&query.event)
+        .bind(&query.event)
+        .bind(query.since)
+        .bind(query.since)
+        .bind(query.until)
+        .bind(query.until)
+        .bind(per_page as i64)
+        .bind((page as i64 - 1).saturating_mul(per_page as i64))
+        .fetch_all(db)
+        .await?;
+    Ok(AuditPage {
+        records: rows.into_iter().map(AuditRecord::from).collect(),
+        total,
+        page,
+        per_page,
+    })
+}
@@ -0,0 +1,77 @@
+//! Backends verifying the password of a login.
+//!
+//! The login flows, login_token, login_cookie and login_session check credentials with the
+//! backend set by `set_credential_backend`, by default `PasswordBackend` and the argon2 hashes of
+//! the UserStore. With the feature `ldap`, `LdapBackend` binds to a directory instead.
+
+use super::{password::password_policy, store::UserStore};
+use crate::prelude::AppResult;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use axum::async_trait;
+use std::sync::{Arc, RwLock};
+
+static BACKEND: RwLock<Option<Arc<dyn CredentialBackend>>> = RwLock::new(None);
+
+/// Verifies the credentials of a login.
+#[async_trait]
+pub trait CredentialBackend: Send + Sync {
+    /// Checks the password of the user, returning the roles of its claims.
+    /// `users` is the UserStore of the login, where backends can provision their users.
+    async fn authenticate(
+        &self,
+        users: &dyn UserStore,
+        username: &str,
+        password: &str,
+    ) -> AppResult<Vec<String>>;
+}
+
+/// The default backend, checking the argon2 hash of a confirmed and enabled user of the
+/// UserStore. Wrong passwords are counted for `LoginConfig::lockout`, and the hash is upgraded
+/// when the PasswordPolicy parameters changed.
+#[derive(Debug, Clone, Default)]
+pub struct PasswordBackend;
+
+#[async_trait]
+impl CredentialBackend for PasswordBackend {
+    async fn authenticate(
+        &self,
+        users: &dyn UserStore,
+        username: &str,
+        password: &str,
+    ) -> AppResult<Vec<String>> {
+        let user = users
+            .find(username)
+            .await?
+            .filter(|u| u.confirmed && !u.disabled)
+            .ok_or("User not found")?;
+        let hash = PasswordHash::new(user.password.as_str())?;
+        if let Err(e) = Argon2::default().verify_password(password.as_bytes(), &hash) {
+            users.record_login(username, false).await?;
+            return Err(e.into());
+        }
+        if user.failed_logins > 0 {
+            users.record_login(username, true).await?;
+        }
+        let policy = password_policy()?;
+        if policy.needs_rehash(&hash) {
+            users
+                .set_password(username, &policy.hash(password)?)
+                .await?;
+        }
+        Ok(user.roles)
+    }
+}
+
+/// Replaces the backend checking the credentials of the login.
+pub fn set_credential_backend(backend: impl CredentialBackend + 'static) {
+    *BACKEND.write().unwrap() = Some(Arc::new(backend));
+}
+
+/// The current backend, PasswordBackend when not set.
+pub(crate) fn credential_backend() -> Arc<dyn CredentialBackend> {
+    BACKEND
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| Arc::new(PasswordBackend))
+}
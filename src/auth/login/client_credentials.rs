@@ -0,0 +1,315 @@
+//! Tokens for services authenticating to each other (OAuth2 client credentials, RFC 6749 4.4).
+//!
+//! Clients are kept in the `login_client` table with an argon2 hash of their secret and the scopes
+//! they may request. Mounted with `App::login_client_credentials` at POST /api/auth/token, taking
+//! the form `grant_type=client_credentials&scope=<scope> <scope>` with the client id and secret as
+//! HTTP Basic credentials (or the `client_id` and `client_secret` fields), and returning
+//! `{access_token, token_type, expires_in, scope}`.
+//!
+//! Errors are returned as `{error, error_description}` as OAuth2 clients expect.
+//!
+//! The tokens are marked with their own `typ` header, so that they never pass as user tokens:
+//! only `ClientBearerClaims` and `ClientScope` accept them.
+
+use super::{first_of, hash_password, now, DB};
+use crate::{
+    auth::{
+        jwt::{jwt_verifier, jwt_verifier_for},
+        BearerToken,
+    },
+    db::{conflict, sql},
+    errors::AppError,
+    prelude::AppResult,
+};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use axum::{
+    async_trait,
+    extract::{rejection::FormRejection, FromRequestParts},
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Extension, Form, Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sentry::types::random_uuid;
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+
+/// Lifetime of the client tokens.
+const CLIENT_TOKEN_LIFETIME: u64 = 3600;
+
+/// Claims of a client token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientClaims {
+    pub exp: u64,
+    pub iat: u64,
+    /// The client id.
+    pub sub: String,
+    pub jti: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    pub client_id: String,
+    /// Granted scopes, separated by spaces.
+    pub scope: String,
+}
+
+impl ClientClaims {
+    /// Whether the scope was granted to the token.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scope.split_whitespace().any(|s| s == scope)
+    }
+}
+
+/// Claims of a client token, verified by the default verifier.
+pub fn client_claims_for(token: &str) -> anyhow::Result<ClientClaims> {
+    Ok(jwt_verifier()?.verify_client(token)?.1)
+}
+
+/// Extracts the claims of a client token from the bearer, rejecting the user tokens.
+pub struct ClientBearerClaims(pub ClientClaims);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientBearerClaims
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let token = BearerToken::from_request_parts(parts, state).await?;
+        let claims = jwt_verifier_for(&parts.extensions)
+            .and_then(|verifier| verifier.verify_client(token.0.as_str()))
+            .map_err(|_| StatusCode::UNAUTHORIZED.into_response())?;
+        Ok(Self(claims.1))
+    }
+}
+
+/// A scope that ClientScope requires.
+///
+/// ```
+/// # use velvet_web::prelude::*;
+/// struct OrdersRead;
+/// impl Scope for OrdersRead {
+///     const SCOPE: &'static str = "orders:read";
+/// }
+/// async fn orders(ClientScope(claims, ..): ClientScope<OrdersRead>) -> String {
+///     claims.client_id
+/// }
+/// ```
+pub trait Scope {
+    const SCOPE: &'static str;
+}
+
+/// Extracts the claims of a client token granted the scope S.
+/// Rejects with UNAUTHORIZED without a client token, and FORBIDDEN without the scope.
+pub struct ClientScope<S: Scope>(pub ClientClaims, pub PhantomData<S>);
+
+#[async_trait]
+impl<S, T> FromRequestParts<S> for ClientScope<T>
+where
+    S: Send + Sync,
+    T: Scope,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ClientBearerClaims(claims) =
+            ClientBearerClaims::from_request_parts(parts, state).await?;
+        if !claims.has_scope(T::SCOPE) {
+            return Err(StatusCode::FORBIDDEN.into_response());
+        }
+        Ok(Self(claims, PhantomData))
+    }
+}
+
+/// Adds a client allowed to request tokens with some of the scopes.
+pub async fn create_client(
+    db: &DB,
+    client_id: &str,
+    secret: &str,
+    scopes: &[&str],
+) -> AppResult<()> {
+    sqlx::query(&sql(
+        "insert into login_client (client_id, secret, scopes, disabled) values(?, ?, ?, 0)",
+    ))
+    .bind(client_id)
+    .bind(hash_password(secret)?)
+    .bind(scopes.join(" "))
+    .execute(db)
+    .await
+    .map_err(conflict("Client already exists"))?;
+    Ok(())
+}
+
+/// Removes the client, its tokens stay valid until they expire.
+pub async fn delete_client(db: &DB, client_id: &str) -> AppResult<()> {
+    sqlx::query(&sql("delete from login_client where client_id = ?"))
+        .bind(client_id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Scopes allowed to an enabled client, None when it doesn't exist or is disabled.
+pub(crate) async fn client_scopes(db: &DB, client_id: &str) -> AppResult<Option<Vec<String>>> {
+    let row: Option<(String,)> = sqlx::query_as(&sql(
+        "select scopes from login_client where client_id = ? and disabled = 0",
+    ))
+    .bind(client_id)
+    .fetch_optional(db)
+    .await?;
+    Ok(row.map(|(scopes,)| scopes.split_whitespace().map(String::from).collect()))
+}
+
+/// Issues a token to the client, with the requested scopes or all of its scopes when none.
+pub async fn client_token(
+    db: &DB,
+    client_id: &str,
+    secret: &str,
+    scopes: &[&str],
+) -> AppResult<String> {
+    sign(&client_claims(db, client_id, secret, scopes).await?)
+}
+
+fn sign(claims: &ClientClaims) -> AppResult<String> {
+    jwt_verifier()
+        .and_then(|verifier| verifier.sign_client(claims))
+        .map_err(|e| AppError::from(e.to_string().as_str()))
+}
+
+async fn client_claims(
+    db: &DB,
+    client_id: &str,
+    secret: &str,
+    scopes: &[&str],
+) -> AppResult<ClientClaims> {
+    let row: Option<(String, String)> = sqlx::query_as(&sql(
+        "select secret, scopes from login_client where client_id = ? and disabled = 0",
+    ))
+    .bind(client_id)
+    .fetch_optional(db)
+    .await?;
+    let invalid = || AppError::new(StatusCode::UNAUTHORIZED, "Invalid client credentials");
+    let (hash, allowed) = row.ok_or_else(invalid)?;
+    Argon2::default()
+        .verify_password(secret.as_bytes(), &PasswordHash::new(&hash)?)
+        .map_err(|_| invalid())?;
+    let allowed = allowed.split_whitespace().collect::<Vec<_>>();
+    if let Some(scope) = scopes.iter().find(|s| !allowed.contains(s)) {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            &format!("Scope {scope} is not allowed"),
+        ));
+    }
+    let scopes = if scopes.is_empty() { &allowed } else { scopes };
+    let now = now();
+    Ok(ClientClaims {
+        exp: now + CLIENT_TOKEN_LIFETIME,
+        iat: now,
+        sub: client_id.to_string(),
+        jti: random_uuid().to_string(),
+        iss: first_of("JWT_ISSUER"),
+        aud: first_of("JWT_AUDIENCE"),
+        client_id: client_id.to_string(),
+        scope: scopes.join(" "),
+    })
+}
+
+pub(crate) fn client_credentials_router() -> Router {
+    Router::new().route("/api/auth/token", post(token))
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    grant_type: Option<String>,
+    scope: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: u64,
+    scope: String,
+}
+
+/// Error response of RFC 6749 5.2.
+#[derive(Serialize)]
+struct OAuthError {
+    error: &'static str,
+    error_description: String,
+}
+
+fn oauth_error(status: StatusCode, error: &'static str, description: &str) -> Response {
+    let body = OAuthError {
+        error,
+        error_description: description.to_string(),
+    };
+    (status, Json(body)).into_response()
+}
+
+async fn token(
+    Extension(db): Extension<DB>,
+    headers: HeaderMap,
+    request: Result<Form<TokenRequest>, FormRejection>,
+) -> Result<Json<TokenResponse>, Response> {
+    let invalid_request =
+        |description: &str| oauth_error(StatusCode::BAD_REQUEST, "invalid_request", description);
+    let Form(request) = request.map_err(|e| invalid_request(&e.body_text()))?;
+    let Some(grant_type) = request.grant_type else {
+        return Err(invalid_request("Missing grant_type"));
+    };
+    if grant_type != "client_credentials" {
+        return Err(oauth_error(
+            StatusCode::BAD_REQUEST,
+            "unsupported_grant_type",
+            "Only client_credentials is supported",
+        ));
+    }
+    let basic = headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Basic "))
+        .and_then(|b| STANDARD.decode(b.trim()).ok())
+        .and_then(|b| String::from_utf8(b).ok())
+        .and_then(|b| {
+            let (id, secret) = b.split_once(':')?;
+            Some((form_decode(id)?, form_decode(secret)?))
+        });
+    let Some((client_id, secret)) = basic.or(request.client_id.zip(request.client_secret)) else {
+        return Err(oauth_error(
+            StatusCode::UNAUTHORIZED,
+            "invalid_client",
+            "Missing client credentials",
+        ));
+    };
+    let scope = request.scope.unwrap_or_default();
+    let scopes = scope.split_whitespace().collect::<Vec<_>>();
+    let claims = client_claims(&db, &client_id, &secret, &scopes)
+        .await
+        .map_err(|e| match e.status() {
+            StatusCode::UNAUTHORIZED => oauth_error(e.status(), "invalid_client", &e.to_string()),
+            StatusCode::BAD_REQUEST => oauth_error(e.status(), "invalid_scope", &e.to_string()),
+            _ => e.into_response(),
+        })?;
+    let access_token = sign(&claims).map_err(IntoResponse::into_response)?;
+    Ok(Json(TokenResponse {
+        access_token,
+        token_type: "Bearer",
+        expires_in: CLIENT_TOKEN_LIFETIME,
+        scope: claims.scope,
+    }))
+}
+
+/// Decodes a form-urlencoded value, as the Basic credentials are (RFC 6749 2.3.1).
+fn form_decode(value: &str) -> Option<String> {
+    let pairs = serde_urlencoded::from_str::<Vec<(String, String)>>(&format!("v={value}")).ok()?;
+    match <[(String, String); 1]>::try_from(pairs) {
+        Ok([(_, decoded)]) => Some(decoded),
+        Err(_) => None,
+    }
+}
@@ -1,50 +1,507 @@
-use super::{login_cookie, login_setup, logout_cookie, register_user, register_user_confirm, DB};
+use super::{
+    audit::{record, AuditContext, AuditEvent},
+    confirm_email_change, find_username,
+    invitation::{find_invitation, register_with_invitation},
+    login_claims, login_setup, now, register_user_confirm, register_user_with_roles,
+    request_email_change, resend_confirmation,
+    store::{IntoUserStore, UserStore},
+    templates::{
+        ConfirmPage, ConfirmationMail, DefaultTemplates, EmailChangeMail, EmailChangedMail,
+        EmailConfirmPage, EmailPage, LoginPage, LoginTemplates, RegisterPage,
+    },
+    Act, Claims, LoginWith, DB,
+};
 use crate::{
     app::App,
-    mail::send_confirmation_email,
-    prelude::{AppResult, JWT},
+    auth::{
+        csrf::CsrfToken,
+        jwt::{jwt_verifier, jwt_verifier_for, setup_secret},
+        session::Sessions,
+        CookieToken, TokenCookie,
+    },
+    errors::AppError,
+    mail::send_multipart,
+    prelude::{AppResult, JwtVerifier},
 };
-use askama::Template;
 use axum::{
-    extract::Query,
-    http::Uri,
-    response::{IntoResponse, Redirect},
-    routing::get,
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::{request::Parts, Extensions, StatusCode, Uri},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
     Extension, Form, Router,
 };
 use axum_extra::extract::CookieJar;
 use lettre::SmtpTransport;
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{sync::Arc, time::Duration};
+use tracing::warn;
 
-#[derive(Debug, Default)]
-pub struct LoginConfig {}
+use impersonation_flow::impersonation_routes;
+pub use impersonation_flow::Impersonation;
 
-pub async fn add_default_flow(db: &DB, _config: LoginConfig, app: App) -> App {
-    JWT::Secret.setup().await.expect("JWT initialization error");
-    login_setup(db).await.expect("Login initialization error");
-    let router = Router::new()
-        .route("/register", get(register_form).post(register))
-        .route("/login", get(login_form).post(login))
-        .route("/logout", get(logout));
-    app.router(router)
+/// Configuration of the login flows.
+///
+/// ```rust
+/// use velvet_web::prelude::*;
+/// use std::time::Duration;
+///
+/// let config = LoginConfig::new()
+///     .prefix("/auth")
+///     .registration_open(false)
+///     .token_lifetime(Duration::from_secs(3600));
+/// ```
+#[derive(Clone)]
+pub struct LoginConfig {
+    prefix: String,
+    login_path: String,
+    register_path: String,
+    confirm_path: String,
+    logout_path: String,
+    email_path: String,
+    login_with: LoginWith,
+    redirect_after_login: String,
+    redirect_after_logout: Option<String>,
+    redirect_after_register: Option<String>,
+    pub(super) token_lifetime: Duration,
+    pub(super) default_roles: Vec<String>,
+    pub(super) registration_open: bool,
+    cookie: TokenCookie,
+    pub(super) templates: Arc<dyn LoginTemplates>,
+    impersonation_role: Option<String>,
+    protected_roles: Vec<String>,
+    pub(super) invitation_only: bool,
+    claims_hook: Option<Arc<dyn ClaimsHook>>,
+    lockout: Option<(i64, Duration)>,
+    verifier: Option<JwtVerifier>,
+    users: Option<Arc<dyn UserStore>>,
+}
+
+/// Enriches or replaces the claims of the tokens issued by the login flow, for example adding
+/// the tenant of the user to `extra`, so that routes don't need to look it up on every request.
+///
+/// ```rust
+/// use velvet_web::prelude::*;
+/// use axum::async_trait;
+///
+/// struct Tenant;
+///
+/// #[async_trait]
+/// impl ClaimsHook for Tenant {
+///     async fn claims(&self, db: &Pool<Backend>, mut claims: LoginClaims) -> AppResult<LoginClaims> {
+///         claims.extra.insert("tenant".into(), "acme".into());
+///         Ok(claims)
+///     }
+/// }
+///
+/// let config = LoginConfig::new().claims_hook(Tenant);
+/// ```
+#[async_trait]
+pub trait ClaimsHook: Send + Sync {
+    async fn claims(&self, db: &DB, claims: Claims) -> AppResult<Claims>;
 }
 
-pub async fn add_mail_flow(db: &DB, _config: LoginConfig, app: App) -> App {
-    JWT::Secret.setup().await.expect("JWT initialization error");
+impl Default for LoginConfig {
+    fn default() -> Self {
+        Self {
+            prefix: "".into(),
+            login_path: "/login".into(),
+            register_path: "/register".into(),
+            confirm_path: "/confirm".into(),
+            logout_path: "/logout".into(),
+            email_path: "/email".into(),
+            login_with: LoginWith::Username,
+            redirect_after_login: "/".into(),
+            redirect_after_logout: None,
+            redirect_after_register: None,
+            token_lifetime: Duration::from_secs(3600 * 24),
+            default_roles: vec!["user".into()],
+            registration_open: true,
+            cookie: TokenCookie::default(),
+            templates: Arc::new(DefaultTemplates),
+            impersonation_role: None,
+            protected_roles: vec!["admin".into()],
+            invitation_only: false,
+            claims_hook: None,
+            lockout: None,
+            verifier: None,
+            users: None,
+        }
+    }
+}
+
+impl LoginConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mounts all the login routes under this prefix, for example "/auth".
+    pub fn prefix(self, prefix: &str) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            ..self
+        }
+    }
+
+    /// Path of the login page, relative to the prefix. Default "/login".
+    pub fn login_path(self, path: &str) -> Self {
+        Self {
+            login_path: path.to_string(),
+            ..self
+        }
+    }
+
+    /// Path of the registration page, relative to the prefix. Default "/register".
+    pub fn register_path(self, path: &str) -> Self {
+        Self {
+            register_path: path.to_string(),
+            ..self
+        }
+    }
+
+    /// Path of the mail confirmation page, relative to the prefix. Default "/confirm".
+    pub fn confirm_path(self, path: &str) -> Self {
+        Self {
+            confirm_path: path.to_string(),
+            ..self
+        }
+    }
+
+    /// Path of the logout route, relative to the prefix. Default "/logout".
+    pub fn logout_path(self, path: &str) -> Self {
+        Self {
+            logout_path: path.to_string(),
+            ..self
+        }
+    }
+
+    /// Path of the email change page of the mail flow, relative to the prefix. Default "/email".
+    pub fn email_path(self, path: &str) -> Self {
+        Self {
+            email_path: path.to_string(),
+            ..self
+        }
+    }
+
+    /// Whether users login with their username, email or either. Default username.
+    pub fn login_with(self, login_with: LoginWith) -> Self {
+        Self { login_with, ..self }
+    }
+
+    /// Where to go after a successful login. Default "/".
+    pub fn redirect_after_login(self, redirect: &str) -> Self {
+        Self {
+            redirect_after_login: redirect.to_string(),
+            ..self
+        }
+    }
+
+    /// Where to go after logout. Defaults to the login page.
+    pub fn redirect_after_logout(self, redirect: &str) -> Self {
+        Self {
+            redirect_after_logout: Some(redirect.to_string()),
+            ..self
+        }
+    }
+
+    /// Where to go after registering, when no mail confirmation is needed.
+    /// Defaults to the login page.
+    pub fn redirect_after_register(self, redirect: &str) -> Self {
+        Self {
+            redirect_after_register: Some(redirect.to_string()),
+            ..self
+        }
+    }
+
+    /// Validity of the issued tokens. Default 24 hours.
+    pub fn token_lifetime(self, token_lifetime: Duration) -> Self {
+        Self {
+            token_lifetime,
+            ..self
+        }
+    }
+
+    /// Roles assigned to self registered users. Default "user".
+    pub fn default_roles(self, roles: &[&str]) -> Self {
+        Self {
+            default_roles: roles.iter().map(|s| s.to_string()).collect(),
+            ..self
+        }
+    }
+
+    /// Whether users can register themselves. Default true.
+    pub fn registration_open(self, registration_open: bool) -> Self {
+        Self {
+            registration_open,
+            ..self
+        }
+    }
+
+    /// Whether registering requires an invitation, see `create_invitation`.
+    /// The login page then doesn't link to the registration page. Default false.
+    pub fn invitation_only(self, invitation_only: bool) -> Self {
+        Self {
+            invitation_only,
+            ..self
+        }
+    }
+
+    /// Name and attributes of the token cookie.
+    pub fn cookie(self, cookie: TokenCookie) -> Self {
+        Self { cookie, ..self }
+    }
+
+    /// Renderers of the pages and mails, replacing the built-in templates.
+    pub fn templates(self, templates: impl LoginTemplates + 'static) -> Self {
+        Self {
+            templates: Arc::new(templates),
+            ..self
+        }
+    }
+
+    /// Called with the claims of every token issued by the login flow, before it is signed.
+    pub fn claims_hook(self, hook: impl ClaimsHook + 'static) -> Self {
+        Self {
+            claims_hook: Some(Arc::new(hook)),
+            ..self
+        }
+    }
+
+    /// Signs and verifies the tokens of the login with the verifier, instead of the default one
+    /// set up from JWT_SECRET. Other routes accepting these tokens need it as well, injected or
+    /// registered with `add_jwt_verifier`.
+    pub fn jwt_verifier(self, verifier: JwtVerifier) -> Self {
+        Self {
+            verifier: Some(verifier),
+            ..self
+        }
+    }
+
+    /// Keeps the users of the login in the store, instead of the `login` table of the database.
+    /// The flows and the admin api of the config look up every user there.
+    pub fn user_store(self, store: impl UserStore + 'static) -> Self {
+        Self {
+            users: Some(Arc::new(store)),
+            ..self
+        }
+    }
+
+    /// Lets users having the role impersonate other users, see `Impersonation`.
+    /// Disabled by default.
+    pub fn impersonation(self, role: &str) -> Self {
+        Self {
+            impersonation_role: Some(role.to_string()),
+            ..self
+        }
+    }
+
+    /// Locks users after this many consecutive wrong passwords, until the duration passed since
+    /// the last one, or an admin unlocks them with `unlock_user`. Password and passkey logins
+    /// are refused meanwhile. Disabled by default.
+    pub fn lockout(self, max_failed_logins: u32, duration: Duration) -> Self {
+        Self {
+            lockout: Some((max_failed_logins as i64, duration)),
+            ..self
+        }
+    }
+
+    /// Roles of the users that can't be impersonated, besides the impersonation role.
+    /// Default "admin".
+    pub fn protected_roles(self, roles: &[&str]) -> Self {
+        Self {
+            protected_roles: roles.iter().map(|s| s.to_string()).collect(),
+            ..self
+        }
+    }
+
+    fn path(&self, path: &str) -> String {
+        format!("{}{}", self.prefix, path)
+    }
+
+    fn login_url(&self) -> String {
+        self.path(&self.login_path)
+    }
+
+    fn register_url(&self) -> Option<String> {
+        self.registration_open
+            .then(|| self.path(&self.register_path))
+    }
+
+    /// Link to the registration page with the invitation token.
+    pub(super) fn invitation_url(&self, token: &str) -> String {
+        format!("{}?invitation={}", self.path(&self.register_path), token)
+    }
+
+    fn confirm_url(&self) -> String {
+        self.path(&self.confirm_path)
+    }
+
+    fn resend_url(&self) -> String {
+        format!("{}/resend", self.confirm_url())
+    }
+
+    fn email_url(&self) -> String {
+        self.path(&self.email_path)
+    }
+
+    fn email_confirm_url(&self) -> String {
+        format!("{}/confirm", self.email_url())
+    }
+
+    fn impersonate_url(&self) -> String {
+        self.path("/impersonate")
+    }
+
+    fn impersonate_end_url(&self) -> String {
+        self.path("/impersonate/end")
+    }
+
+    fn logout_redirect(&self) -> String {
+        self.redirect_after_logout
+            .clone()
+            .unwrap_or(self.login_url())
+    }
+
+    fn register_redirect(&self) -> String {
+        self.redirect_after_register
+            .clone()
+            .unwrap_or(self.login_url())
+    }
+
+    /// Sets up the secret of the default verifier from the environment, unless the config has its
+    /// own verifier or the default one has a secret already.
+    pub(super) async fn setup_verifier(&self) {
+        if self.verifier.is_none() {
+            setup_secret().await.expect("JWT initialization error");
+        }
+    }
+
+    /// Makes the extractors of the login routes verify with the verifier of the config.
+    pub(super) fn verify_with(&self, router: Router) -> Router {
+        match &self.verifier {
+            Some(verifier) => router.layer(Extension(verifier.clone())),
+            None => router,
+        }
+    }
+
+    /// The verifier of the config, otherwise the default one.
+    pub(super) fn verifier(&self) -> anyhow::Result<JwtVerifier> {
+        match &self.verifier {
+            Some(verifier) => Ok(verifier.clone()),
+            None => jwt_verifier(),
+        }
+    }
+
+    /// The verifier of the config, otherwise the one of the request.
+    fn verifier_for(&self, extensions: &Extensions) -> anyhow::Result<JwtVerifier> {
+        match &self.verifier {
+            Some(verifier) => Ok(verifier.clone()),
+            None => jwt_verifier_for(extensions),
+        }
+    }
+
+    /// The store of the config, otherwise the `login` table of the database.
+    pub(super) fn users(&self, db: &DB) -> Arc<dyn UserStore> {
+        match &self.users {
+            Some(users) => users.clone(),
+            None => db.user_store(),
+        }
+    }
+
+    /// Claims with the token lifetime, passed to the claims hook.
+    pub(super) async fn claims(&self, db: &DB, claims: Claims) -> AppResult<Claims> {
+        self.enrich(db, claims.lifetime(self.token_lifetime)).await
+    }
+
+    async fn enrich(&self, db: &DB, claims: Claims) -> AppResult<Claims> {
+        match &self.claims_hook {
+            Some(hook) => hook.claims(db, claims).await,
+            None => Ok(claims),
+        }
+    }
+
+    /// Claims of the user when the password is right and the user isn't locked.
+    pub(super) async fn authenticate(
+        &self,
+        db: &DB,
+        username: &str,
+        password: &str,
+    ) -> AppResult<Claims> {
+        self.check_lockout(db, username).await?;
+        login_claims(&*self.users(db), username, password).await
+    }
+
+    /// Fails with UNAUTHORIZED while the user is locked by the lockout.
+    async fn check_lockout(&self, db: &DB, username: &str) -> AppResult<()> {
+        let Some((max_failed_logins, duration)) = self.lockout else {
+            return Ok(());
+        };
+        let locked = self.users(db).find(username).await?.is_some_and(|user| {
+            user.failed_logins >= max_failed_logins
+                && user
+                    .last_failed_login
+                    .is_some_and(|last| last + duration.as_secs() as i64 > now() as i64)
+        });
+        if locked {
+            return Err(AppError::new(StatusCode::UNAUTHORIZED, "User is locked"));
+        }
+        Ok(())
+    }
+}
+
+type Config = Extension<Arc<LoginConfig>>;
+
+pub async fn add_default_flow(db: &DB, config: LoginConfig, app: App) -> App {
+    add_flow(db, config, app, false).await
+}
+
+pub async fn add_mail_flow(db: &DB, config: LoginConfig, app: App) -> App {
+    add_flow(db, config, app, true)
+        .await
+        .inject(crate::mail::mailer())
+}
+
+async fn add_flow(db: &DB, config: LoginConfig, app: App, mail: bool) -> App {
+    config.setup_verifier().await;
     login_setup(db).await.expect("Login initialization error");
-    let router = Router::new()
-        .route("/register", get(register_form).post(register_send_mail))
-        .route("/confirm", get(confirm_form).post(confirm))
-        .route("/login", get(login_form).post(login))
-        .route("/logout", get(logout));
-    app.router(router).inject(crate::mail::mailer())
+    let mut router = Router::new()
+        .route(&config.login_url(), get(login_form).post(login))
+        .route(&config.path(&config.logout_path), get(logout));
+    if let Some(register_url) = config.register_url() {
+        if mail {
+            router = router
+                .route(&register_url, get(register_form).post(register_send_mail))
+                .route(&config.confirm_url(), get(confirm_form).post(confirm))
+                .route(&config.resend_url(), post(resend));
+        } else {
+            router = router.route(&register_url, get(register_form).post(register));
+        }
+    }
+    if mail {
+        router = router
+            .route(&config.email_url(), get(email_form).post(email_change))
+            .route(
+                &config.email_confirm_url(),
+                get(email_confirm_form).post(email_confirm),
+            );
+    }
+    if config.impersonation_role.is_some() {
+        router = impersonation_routes(router, &config);
+    }
+    #[cfg(feature = "webauthn")]
+    let router = webauthn_routes(router, &config);
+    let router = config.verify_with(router);
+    let cookie = config.cookie.clone();
+    app.router(router).inject(cookie).inject(Arc::new(config))
 }
 
 #[derive(Deserialize)]
 struct RegisterForm {
     username: String,
+    #[serde(default)]
     email: String,
     password: String,
+    invitation: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -59,26 +516,86 @@ struct ConfirmForm {
     code: String,
 }
 
-#[derive(Template)]
-#[template(path = "login.html")]
-struct LoginTemplate;
+async fn login_form(
+    Extension(config): Config,
+    CsrfToken(csrf): CsrfToken,
+) -> AppResult<Html<String>> {
+    login_page(&config, csrf, "", None)
+}
 
-#[derive(Template)]
-#[template(path = "register.html")]
-struct RegisterTemplate;
+fn login_page(
+    config: &LoginConfig,
+    csrf: String,
+    username: &str,
+    error: Option<&str>,
+) -> AppResult<Html<String>> {
+    let page = LoginPage {
+        action: config.login_url(),
+        identifier: match config.login_with {
+            LoginWith::Username => "Username",
+            LoginWith::Email => "Email",
+            LoginWith::UsernameOrEmail => "Username or email",
+        }
+        .to_string(),
+        register: config.register_url().filter(|_| !config.invitation_only),
+        webauthn: cfg!(feature = "webauthn").then(|| config.path("/webauthn/login")),
+        csrf,
+        username: username.to_string(),
+        error: error.map(String::from),
+    };
+    Ok(Html(config.templates.login(&page)?))
+}
 
-#[derive(Template)]
-#[template(path = "confirm.html")]
-struct ConfirmTemplate {
-    username: String,
+#[derive(Deserialize)]
+struct RegisterQuery {
+    invitation: Option<String>,
 }
 
-async fn login_form() -> impl IntoResponse {
-    LoginTemplate
+async fn register_form(
+    Extension(db): Extension<DB>,
+    Extension(config): Config,
+    CsrfToken(csrf): CsrfToken,
+    Query(q): Query<RegisterQuery>,
+) -> AppResult<Response> {
+    let Some(token) = q.invitation else {
+        if config.invitation_only {
+            let error = Some(INVITATION_ONLY);
+            let page = register_page(&config, csrf, "", "", None, error)?;
+            return Ok((StatusCode::FORBIDDEN, page).into_response());
+        }
+        return Ok(register_page(&config, csrf, "", "", None, None)?.into_response());
+    };
+    match find_invitation(&db, &token).await {
+        Ok(invitation) => {
+            let page = register_page(&config, csrf, "", &invitation.email, Some(&token), None)?;
+            Ok(page.into_response())
+        }
+        Err(e) => {
+            let page = register_page(&config, csrf, "", "", None, Some(&error_message(&e)))?;
+            Ok((e.status(), page).into_response())
+        }
+    }
 }
 
-async fn register_form() -> impl IntoResponse {
-    RegisterTemplate
+pub(super) const INVITATION_ONLY: &str = "Registration is by invitation only";
+
+fn register_page(
+    config: &LoginConfig,
+    csrf: String,
+    username: &str,
+    email: &str,
+    invitation: Option<&str>,
+    error: Option<&str>,
+) -> AppResult<Html<String>> {
+    let page = RegisterPage {
+        action: config.register_url().unwrap_or_default(),
+        csrf,
+        username: username.to_string(),
+        email: email.to_string(),
+        invitation: invitation.map(String::from),
+        error: error.map(String::from),
+    };
+    Ok(Html(config.templates.register(&page)?))
 }
 
 #[derive(Deserialize)]
@@ -86,79 +603,901 @@ struct ConfirmQuery {
     username: String,
 }
 
-async fn confirm_form(Query(q): Query<ConfirmQuery>) -> impl IntoResponse {
-    ConfirmTemplate {
-        username: q.username,
+async fn confirm_form(
+    Extension(config): Config,
+    CsrfToken(csrf): CsrfToken,
+    Query(q): Query<ConfirmQuery>,
+) -> AppResult<Html<String>> {
+    confirm_page(&config, csrf, &q.username, None)
+}
+
+fn confirm_page(
+    config: &LoginConfig,
+    csrf: String,
+    username: &str,
+    error: Option<&str>,
+) -> AppResult<Html<String>> {
+    let page = ConfirmPage {
+        action: config.confirm_url(),
+        resend: config.resend_url(),
+        csrf,
+        username: username.to_string(),
+        error: error.map(String::from),
+    };
+    Ok(Html(config.templates.confirm(&page)?))
+}
+
+/// Registers the user with the default roles, or with the invitation's roles, returning the
+/// confirmation code when the email still needs to be confirmed, or the form with an error.
+async fn register_with_roles(
+    db: &DB,
+    config: &LoginConfig,
+    csrf: String,
+    context: &AuditContext,
+    form: &RegisterForm,
+) -> AppResult<Result<Option<String>, Response>> {
+    let users = config.users(db);
+    let invitation = form.invitation.as_deref().filter(|i| !i.is_empty());
+    let registered = match invitation {
+        Some(token) => register_with_invitation(db, &users, token, &form.username, &form.password)
+            .await
+            .map(|invitation| {
+                (
+                    None,
+                    invitation.invited_by.map(|by| format!("invited by {by}")),
+                )
+            }),
+        None if config.invitation_only => {
+            Err(AppError::new(StatusCode::FORBIDDEN, INVITATION_ONLY))
+        }
+        None => {
+            let roles = config
+                .default_roles
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            register_user_with_roles(&users, &form.username, &form.email, &form.password, &roles)
+                .await
+                .map(|code| (Some(code), None))
+        }
+    };
+    match registered {
+        Ok((code, detail)) => {
+            let detail = detail.as_deref();
+            record(db, AuditEvent::Register, &form.username, context, detail).await;
+            Ok(Ok(code))
+        }
+        Err(e) => {
+            warn!("Registration failed: {:?}", e);
+            let (status, error) = match e.status() {
+                status @ (StatusCode::BAD_REQUEST | StatusCode::FORBIDDEN | StatusCode::GONE) => {
+                    (status, e.to_string())
+                }
+                _ => (
+                    StatusCode::BAD_REQUEST,
+                    "Registration failed, try another username".to_string(),
+                ),
+            };
+            let page = register_page(
+                config,
+                csrf,
+                &form.username,
+                &form.email,
+                invitation,
+                Some(&error),
+            )?;
+            Ok(Err((status, page).into_response()))
+        }
     }
 }
 
 async fn register(
     Extension(db): Extension<DB>,
+    Extension(config): Config,
+    CsrfToken(csrf): CsrfToken,
+    context: AuditContext,
     Form(register_form): Form<RegisterForm>,
-) -> AppResult<Redirect> {
-    let confirmation_code = register_user(
-        &db,
-        &register_form.username,
-        &register_form.email,
-        &register_form.password,
-    )
-    .await?;
-    register_user_confirm(&db, &register_form.username, &confirmation_code).await?;
-    Ok(Redirect::to("/login"))
+) -> AppResult<Response> {
+    let confirmation_code =
+        match register_with_roles(&db, &config, csrf, &context, &register_form).await? {
+            Ok(code) => code,
+            Err(page) => return Ok(page),
+        };
+    if let Some(code) = confirmation_code {
+        register_user_confirm(&config.users(&db), &register_form.username, &code).await?;
+    }
+    Ok(Redirect::to(&config.register_redirect()).into_response())
 }
 
 async fn register_send_mail(
     Extension(mailer): Extension<SmtpTransport>,
     Extension(db): Extension<DB>,
+    Extension(config): Config,
+    CsrfToken(csrf): CsrfToken,
     url: Uri,
+    context: AuditContext,
     Form(register_form): Form<RegisterForm>,
-) -> AppResult<Redirect> {
-    let confirmation_code = register_user(
-        &db,
+) -> AppResult<Response> {
+    let confirmation_code =
+        match register_with_roles(&db, &config, csrf, &context, &register_form).await? {
+            Ok(Some(code)) => code,
+            // the invitation was mailed to the address already
+            Ok(None) => return Ok(Redirect::to(&config.register_redirect()).into_response()),
+            Err(page) => return Ok(page),
+        };
+    let confirm = send_confirmation(
+        mailer,
+        &config,
+        &url,
         &register_form.username,
         &register_form.email,
-        &register_form.password,
-    )
-    .await?;
+        confirmation_code,
+    )?;
+    Ok(Redirect::to(&confirm).into_response())
+}
+
+/// Mails the confirmation code, returning the path of the confirmation page.
+fn send_confirmation(
+    mailer: SmtpTransport,
+    config: &LoginConfig,
+    url: &Uri,
+    username: &str,
+    email: &str,
+    code: String,
+) -> AppResult<String> {
+    let base = site(url);
+    let confirm = format!("{}?username={}", config.confirm_url(), username);
+    let mail = ConfirmationMail {
+        link: format!("{}{}", base, confirm),
+        site: base,
+        username: username.to_string(),
+        code,
+    };
+    send_multipart(
+        mailer,
+        email,
+        config.templates.confirmation_mail_text(&mail)?,
+        config.templates.confirmation_mail_html(&mail)?,
+    )?;
+    Ok(confirm)
+}
+
+/// Message shown in the pages, hiding the details of server errors.
+fn error_message(e: &AppError) -> String {
+    if e.status().is_client_error() {
+        e.to_string()
+    } else {
+        "Something went wrong, try again later".to_string()
+    }
+}
+
+/// Base url of the site, as far as known from the request.
+pub(super) fn site(url: &Uri) -> String {
     let scheme = url
         .scheme()
         .map(|s| format!("{}://", s))
         .unwrap_or_default();
-    let base = format!(
+    format!(
         "{}{}",
         scheme,
         url.authority().map(|s| s.to_string()).unwrap_or_default()
-    );
-    let link = format!("{}/confirm?username={}", base, register_form.username);
-    send_confirmation_email(
-        mailer,
-        &base,
-        &link,
-        &register_form.username,
-        &register_form.email,
-        &confirmation_code,
-    )?;
-    Ok(Redirect::to(
-        format!("/confirm?username={}", &register_form.username).as_str(),
-    ))
+    )
 }
 
 async fn login(
     Extension(db): Extension<DB>,
+    Extension(config): Config,
     jar: CookieJar,
+    sessions: Option<Extension<Sessions>>,
+    CsrfToken(csrf): CsrfToken,
+    context: AuditContext,
     Form(form): Form<LoginForm>,
+) -> AppResult<Response> {
+    let claims = match find_username(&config.users(&db), config.login_with, &form.username).await {
+        Ok(username) => config.authenticate(&db, &username, &form.password).await,
+        // Users of another CredentialBackend may not be in the login table before their first login.
+        Err(e) if e.status() == StatusCode::NOT_FOUND && config.login_with != LoginWith::Email => {
+            config
+                .authenticate(&db, &form.username, &form.password)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    let claims = match claims {
+        Ok(claims) => {
+            record(
+                &db,
+                AuditEvent::LoginSuccess,
+                &claims.username,
+                &context,
+                None,
+            )
+            .await;
+            config.claims(&db, claims).await?
+        }
+        Err(e) => {
+            warn!("Login failed: {:?}", e);
+            let detail = e.to_string();
+            record(
+                &db,
+                AuditEvent::LoginFailure,
+                &form.username,
+                &context,
+                Some(&detail),
+            )
+            .await;
+            let page = login_page(
+                &config,
+                csrf,
+                &form.username,
+                Some("Invalid username or password"),
+            )?;
+            return Ok((StatusCode::UNAUTHORIZED, page).into_response());
+        }
+    };
+    Ok(login_with_claims(jar, sessions, &config, claims)
+        .await?
+        .into_response())
+}
+
+/// Sets the claims in the session when enabled, otherwise in the token cookie.
+async fn login_with_claims(
+    jar: CookieJar,
+    sessions: Option<Extension<Sessions>>,
+    config: &LoginConfig,
+    claims: Claims,
 ) -> AppResult<(CookieJar, Redirect)> {
-    login_cookie(jar, "/", &db, &form.username, &form.password).await
+    let jar = match sessions {
+        Some(Extension(sessions)) => {
+            let mut session = sessions.load(&jar).await?;
+            session.rotate();
+            session.set_claims(claims)?;
+            session.save(jar).await?
+        }
+        None => {
+            let token = config
+                .verifier()
+                .and_then(|verifier| verifier.sign(&claims))
+                .map_err(|e| {
+                    warn!("Login failed: {}", e);
+                    Redirect::to(&config.login_url())
+                })?;
+            CookieToken::set_with(jar, token, &config.cookie)
+        }
+    };
+    Ok((jar, Redirect::to(&config.redirect_after_login)))
 }
 
-async fn logout(jar: CookieJar) -> AppResult<(CookieJar, Redirect)> {
-    logout_cookie(jar, "/login")
+async fn logout(
+    Extension(db): Extension<DB>,
+    Extension(config): Config,
+    jar: CookieJar,
+    sessions: Option<Extension<Sessions>>,
+    logged_in: Option<LoggedIn>,
+    context: AuditContext,
+) -> AppResult<(CookieJar, Redirect)> {
+    if let Some(LoggedIn(Some(username))) = logged_in {
+        record(&db, AuditEvent::Logout, &username, &context, None).await;
+    }
+    let jar = match sessions {
+        Some(Extension(sessions)) => sessions.load(&jar).await?.destroy(jar).await?,
+        None => jar,
+    };
+    let jar = CookieToken::remove_with(jar, &config.cookie);
+    Ok((jar, Redirect::to(&config.logout_redirect())))
 }
 
 async fn confirm(
     Extension(db): Extension<DB>,
+    Extension(config): Config,
+    CsrfToken(csrf): CsrfToken,
+    context: AuditContext,
     Form(form): Form<ConfirmForm>,
-) -> AppResult<impl IntoResponse> {
-    register_user_confirm(&db, &form.username, &form.code).await?;
-    Ok(Redirect::to("/login"))
+) -> AppResult<Response> {
+    if let Err(e) = register_user_confirm(&config.users(&db), &form.username, &form.code).await {
+        warn!("Confirmation failed: {:?}", e);
+        let page = confirm_page(&config, csrf, &form.username, Some(&error_message(&e)))?;
+        return Ok((e.status(), page).into_response());
+    }
+    record(&db, AuditEvent::Confirm, &form.username, &context, None).await;
+    Ok(Redirect::to(&config.login_url()).into_response())
+}
+
+#[derive(Deserialize)]
+struct ResendForm {
+    username: String,
+}
+
+async fn resend(
+    Extension(mailer): Extension<SmtpTransport>,
+    Extension(db): Extension<DB>,
+    Extension(config): Config,
+    CsrfToken(csrf): CsrfToken,
+    url: Uri,
+    Form(form): Form<ResendForm>,
+) -> AppResult<Response> {
+    let (email, code) = match resend_confirmation(&config.users(&db), &form.username).await {
+        Ok(resent) => resent,
+        Err(e) => {
+            warn!("Resending confirmation failed: {:?}", e);
+            let page = confirm_page(&config, csrf, &form.username, Some(&error_message(&e)))?;
+            return Ok((e.status(), page).into_response());
+        }
+    };
+    let confirm = send_confirmation(mailer, &config, &url, &form.username, &email, code)?;
+    Ok(Redirect::to(&confirm).into_response())
+}
+
+#[derive(Deserialize)]
+struct UsernameClaims {
+    username: String,
+    act: Option<Act>,
+}
+
+/// Claims of the logged in user, from the session when enabled, otherwise from the token cookie.
+async fn logged_in_claims<T: DeserializeOwned>(parts: &Parts) -> Option<T> {
+    let jar = CookieJar::from_headers(&parts.headers);
+    match parts.extensions.get::<Sessions>() {
+        Some(sessions) => sessions.load(&jar).await.ok()?.claims::<T>(),
+        None => {
+            let config = parts.extensions.get::<Arc<LoginConfig>>()?;
+            let verifier = config.verifier_for(&parts.extensions).ok()?;
+            verifier
+                .claims::<T>(jar.get(&config.cookie.name)?.value())
+                .ok()
+        }
+    }
+}
+
+/// Username of the logged in user, from the session when enabled, otherwise from the token cookie.
+/// Impersonations are refused with FORBIDDEN, so that the routes changing the credentials or the
+/// email of the account can't be used by the impersonating user.
+struct LoggedIn(Option<String>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for LoggedIn
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        match logged_in_claims::<UsernameClaims>(parts).await {
+            Some(UsernameClaims { act: Some(_), .. }) => Err(StatusCode::FORBIDDEN),
+            claims => Ok(Self(claims.map(|c| c.username))),
+        }
+    }
+}
+
+fn email_page(
+    config: &LoginConfig,
+    csrf: String,
+    message: Option<String>,
+    error: Option<String>,
+) -> AppResult<Html<String>> {
+    let page = EmailPage {
+        action: config.email_url(),
+        csrf,
+        message,
+        error,
+    };
+    Ok(Html(config.templates.email(&page)?))
+}
+
+async fn email_form(
+    Extension(config): Config,
+    LoggedIn(username): LoggedIn,
+    CsrfToken(csrf): CsrfToken,
+) -> AppResult<Response> {
+    if username.is_none() {
+        return Ok(Redirect::to(&config.login_url()).into_response());
+    }
+    Ok(email_page(&config, csrf, None, None)?.into_response())
+}
+
+#[derive(Deserialize)]
+struct EmailForm {
+    email: String,
+}
+
+async fn email_change(
+    Extension(mailer): Extension<SmtpTransport>,
+    Extension(db): Extension<DB>,
+    Extension(config): Config,
+    LoggedIn(username): LoggedIn,
+    CsrfToken(csrf): CsrfToken,
+    url: Uri,
+    Form(form): Form<EmailForm>,
+) -> AppResult<Response> {
+    let Some(username) = username else {
+        return Ok(Redirect::to(&config.login_url()).into_response());
+    };
+    let code = match request_email_change(&config.users(&db), &username, &form.email).await {
+        Ok(code) => code,
+        Err(e) => {
+            warn!("Email change failed: {:?}", e);
+            let page = email_page(&config, csrf, None, Some(error_message(&e)))?;
+            return Ok((e.status(), page).into_response());
+        }
+    };
+    let base = site(&url);
+    let mail = EmailChangeMail {
+        link: format!(
+            "{}{}?username={}&code={}",
+            base,
+            config.email_confirm_url(),
+            username,
+            &code
+        ),
+        site: base,
+        username,
+        email: form.email.clone(),
+        code,
+    };
+    send_multipart(
+        mailer,
+        &form.email,
+        config.templates.email_change_mail_text(&mail)?,
+        config.templates.email_change_mail_html(&mail)?,
+    )?;
+    let message = format!("A verification mail was sent to {}", form.email);
+    Ok(email_page(&config, csrf, Some(message), None)?.into_response())
+}
+
+#[derive(Deserialize)]
+struct EmailConfirmForm {
+    username: String,
+    code: String,
+}
+
+fn email_confirm_page(
+    config: &LoginConfig,
+    csrf: String,
+    form: EmailConfirmForm,
+    email: String,
+    error: Option<String>,
+) -> AppResult<Html<String>> {
+    let page = EmailConfirmPage {
+        action: config.email_confirm_url(),
+        csrf,
+        username: form.username,
+        code: form.code,
+        email,
+        error,
+    };
+    Ok(Html(config.templates.email_confirm(&page)?))
+}
+
+/// Asks the user to confirm the new email, so that opening the link alone changes nothing.
+async fn email_confirm_form(
+    Extension(db): Extension<DB>,
+    Extension(config): Config,
+    LoggedIn(username): LoggedIn,
+    CsrfToken(csrf): CsrfToken,
+    Query(q): Query<EmailConfirmForm>,
+) -> AppResult<Response> {
+    if username.as_deref() != Some(q.username.as_str()) {
+        return Ok(Redirect::to(&config.login_url()).into_response());
+    }
+    let user = config.users(&db).find(&q.username).await?;
+    let email = user.and_then(|u| u.pending_email).unwrap_or_default();
+    Ok(email_confirm_page(&config, csrf, q, email, None)?.into_response())
+}
+
+async fn email_confirm(
+    Extension(mailer): Extension<SmtpTransport>,
+    Extension(db): Extension<DB>,
+    Extension(config): Config,
+    LoggedIn(username): LoggedIn,
+    CsrfToken(csrf): CsrfToken,
+    (url, context): (Uri, AuditContext),
+    Form(q): Form<EmailConfirmForm>,
+) -> AppResult<Response> {
+    if username.as_deref() != Some(q.username.as_str()) {
+        return Ok(Redirect::to(&config.login_url()).into_response());
+    }
+    let (old, new) = match confirm_email_change(&config.users(&db), &q.username, &q.code).await {
+        Ok(emails) => emails,
+        Err(e) => {
+            warn!("Email change failed: {:?}", e);
+            let page =
+                email_confirm_page(&config, csrf, q, String::new(), Some(error_message(&e)))?;
+            return Ok((e.status(), page).into_response());
+        }
+    };
+    record(
+        &db,
+        AuditEvent::EmailChange,
+        &q.username,
+        &context,
+        Some(&new),
+    )
+    .await;
+    if let Some(old) = old {
+        let mail = EmailChangedMail {
+            site: site(&url),
+            username: q.username,
+            email: new,
+        };
+        send_multipart(
+            mailer,
+            &old,
+            config.templates.email_changed_mail_text(&mail)?,
+            config.templates.email_changed_mail_html(&mail)?,
+        )?;
+    }
+    Ok(Redirect::to(&config.redirect_after_login).into_response())
+}
+
+mod impersonation_flow {
+    use super::super::{
+        audit::{record, AuditContext, AuditEvent},
+        templates::{ImpersonationBanner, LoginTemplates},
+        user_claims, Act, Claims, DB,
+    };
+    use super::{logged_in_claims, login_with_claims, Config, LoginConfig};
+    use crate::{
+        auth::{csrf::CsrfToken, session::Sessions},
+        errors::AppError,
+        prelude::AppResult,
+    };
+    use axum::{
+        async_trait,
+        extract::FromRequestParts,
+        http::{request::Parts, StatusCode},
+        response::{IntoResponse, Redirect, Response},
+        routing::post,
+        Extension, Form, Router,
+    };
+    use axum_extra::extract::CookieJar;
+    use serde::Deserialize;
+    use std::{convert::Infallible, sync::Arc, time::Duration};
+
+    /// Longest validity of an impersonation token.
+    const IMPERSONATION_LIFETIME: Duration = Duration::from_secs(3600);
+
+    pub(super) fn impersonation_routes(router: Router, config: &LoginConfig) -> Router {
+        router
+            .route(&config.impersonate_url(), post(impersonate))
+            .route(&config.impersonate_end_url(), post(impersonate_end))
+    }
+
+    #[derive(Deserialize)]
+    struct TokenClaims {
+        username: String,
+        #[serde(default)]
+        roles: Vec<String>,
+        act: Option<Act>,
+    }
+
+    /// The impersonation in progress, when a user with the role of `LoginConfig::impersonation`
+    /// views the app as another user. Extract it as `Option<Impersonation>` to show its banner.
+    /// Like the login flow, it is only available on the routes added before the flow.
+    pub struct Impersonation {
+        /// The impersonated user.
+        pub username: String,
+        /// The user impersonating.
+        pub actor: String,
+        end: String,
+        csrf: String,
+        templates: Arc<dyn LoginTemplates>,
+    }
+
+    impl Impersonation {
+        /// Banner with a button ending the impersonation, see `LoginTemplates::impersonation_banner`.
+        pub fn banner(&self) -> AppResult<String> {
+            self.templates.impersonation_banner(&ImpersonationBanner {
+                username: self.username.clone(),
+                actor: self.actor.clone(),
+                end: self.end.clone(),
+                csrf: self.csrf.clone(),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl<S> FromRequestParts<S> for Impersonation
+    where
+        S: Send + Sync,
+    {
+        type Rejection = StatusCode;
+
+        async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+            let config = parts
+                .extensions
+                .get::<Arc<LoginConfig>>()
+                .cloned()
+                .ok_or(StatusCode::UNAUTHORIZED)?;
+            let claims = logged_in_claims::<TokenClaims>(parts)
+                .await
+                .ok_or(StatusCode::UNAUTHORIZED)?;
+            let act = claims.act.ok_or(StatusCode::UNAUTHORIZED)?;
+            let Ok(CsrfToken(csrf)) = CsrfToken::from_request_parts(parts, state).await;
+            Ok(Self {
+                username: claims.username,
+                actor: act.sub,
+                end: config.impersonate_end_url(),
+                csrf,
+                templates: config.templates.clone(),
+            })
+        }
+    }
+
+    /// Claims of the logged in user, if any.
+    struct Current(Option<TokenClaims>);
+
+    #[async_trait]
+    impl<S> FromRequestParts<S> for Current
+    where
+        S: Send + Sync,
+    {
+        type Rejection = Infallible;
+
+        async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+            Ok(Self(logged_in_claims(parts).await))
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct ImpersonateForm {
+        username: String,
+    }
+
+    async fn impersonate(
+        Extension(db): Extension<DB>,
+        Extension(config): Config,
+        jar: CookieJar,
+        sessions: Option<Extension<Sessions>>,
+        Current(actor): Current,
+        context: AuditContext,
+        Form(form): Form<ImpersonateForm>,
+    ) -> AppResult<Response> {
+        let Some(actor) = actor else {
+            return Ok(Redirect::to(&config.login_url()).into_response());
+        };
+        let allowed = config
+            .impersonation_role
+            .as_ref()
+            .is_some_and(|role| actor.roles.contains(role));
+        if !allowed || actor.act.is_some() {
+            return Err(AppError::new(
+                StatusCode::FORBIDDEN,
+                "Not allowed to impersonate",
+            ));
+        }
+        if form.username == actor.username {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                "Cannot impersonate yourself",
+            ));
+        }
+        let claims = user_claims(&*config.users(&db), &form.username)
+            .await
+            .map_err(|_| AppError::new(StatusCode::NOT_FOUND, "User not found"))?;
+        let protected = claims.roles.iter().any(|role| {
+            config.impersonation_role.as_ref() == Some(role)
+                || config.protected_roles.contains(role)
+        });
+        if protected {
+            return Err(AppError::new(
+                StatusCode::FORBIDDEN,
+                "This user can't be impersonated",
+            ));
+        }
+        let claims = Claims {
+            act: Some(Act {
+                sub: actor.username.clone(),
+            }),
+            ..claims.lifetime(config.token_lifetime.min(IMPERSONATION_LIFETIME))
+        };
+        let claims = config.enrich(&db, claims).await?;
+        let detail = format!("by {}", actor.username);
+        record(
+            &db,
+            AuditEvent::ImpersonationStart,
+            &form.username,
+            &context,
+            Some(&detail),
+        )
+        .await;
+        Ok(login_with_claims(jar, sessions, &config, claims)
+            .await?
+            .into_response())
+    }
+
+    async fn impersonate_end(
+        Extension(db): Extension<DB>,
+        Extension(config): Config,
+        jar: CookieJar,
+        sessions: Option<Extension<Sessions>>,
+        context: AuditContext,
+        impersonation: Option<Impersonation>,
+    ) -> AppResult<Response> {
+        let Some(impersonation) = impersonation else {
+            return Ok(Redirect::to(&config.redirect_after_login).into_response());
+        };
+        let claims = user_claims(&*config.users(&db), &impersonation.actor).await?;
+        let detail = format!("by {}", impersonation.actor);
+        record(
+            &db,
+            AuditEvent::ImpersonationEnd,
+            &impersonation.username,
+            &context,
+            Some(&detail),
+        )
+        .await;
+        let claims = config.claims(&db, claims).await?;
+        Ok(login_with_claims(jar, sessions, &config, claims)
+            .await?
+            .into_response())
+    }
+}
+
+#[cfg(feature = "webauthn")]
+use webauthn_flow::webauthn_routes;
+
+#[cfg(feature = "webauthn")]
+mod webauthn_flow {
+    use super::super::templates::{WebauthnLoginPage, WebauthnRegisterPage};
+    use super::super::webauthn::{
+        login_start, register_finish, register_start, webauthn_login_claims, WebauthnAssertion,
+        WebauthnCreationOptions, WebauthnRegistration, WebauthnRequestOptions,
+    };
+    use super::{
+        login_with_claims, record, AuditContext, AuditEvent, Config, LoggedIn, LoginConfig, DB,
+    };
+    use crate::auth::session::Sessions;
+    use crate::{auth::csrf::CsrfToken, prelude::AppResult};
+    use axum::{
+        http::StatusCode,
+        response::{Html, Redirect},
+        routing::{get, post},
+        Extension, Json, Router,
+    };
+    use axum_extra::extract::{
+        cookie::{Cookie, SameSite},
+        CookieJar,
+    };
+    use serde::Deserialize;
+
+    const STATE_COOKIE: &str = "webauthn";
+
+    pub(super) fn webauthn_routes(router: Router, config: &LoginConfig) -> Router {
+        router
+            .route(
+                &config.path("/webauthn/register"),
+                get(webauthn_register_form).post(webauthn_register),
+            )
+            .route(
+                &config.path("/webauthn/register/options"),
+                post(webauthn_register_options),
+            )
+            .route(
+                &config.path("/webauthn/login"),
+                get(webauthn_login_form).post(webauthn_login),
+            )
+            .route(
+                &config.path("/webauthn/login/options"),
+                post(webauthn_login_options),
+            )
+    }
+
+    #[derive(Deserialize)]
+    struct LoginOptionsForm {
+        username: String,
+    }
+
+    async fn webauthn_register_form(
+        Extension(config): Config,
+        CsrfToken(csrf): CsrfToken,
+    ) -> AppResult<Html<String>> {
+        let page = WebauthnRegisterPage {
+            prefix: config.prefix.clone(),
+            csrf,
+        };
+        Ok(Html(config.templates.webauthn_register(&page)?))
+    }
+
+    async fn webauthn_login_form(
+        Extension(config): Config,
+        CsrfToken(csrf): CsrfToken,
+    ) -> AppResult<Html<String>> {
+        let page = WebauthnLoginPage {
+            prefix: config.prefix.clone(),
+            password_login: config.login_url(),
+            csrf,
+        };
+        Ok(Html(config.templates.webauthn_login(&page)?))
+    }
+
+    async fn webauthn_register_options(
+        Extension(db): Extension<DB>,
+        Extension(config): Config,
+        jar: CookieJar,
+        LoggedIn(username): LoggedIn,
+    ) -> AppResult<(CookieJar, Json<WebauthnCreationOptions>)> {
+        let username = username.ok_or(StatusCode::UNAUTHORIZED)?;
+        let verifier = config.verifier()?;
+        let (state, options) =
+            register_start(&db, &*config.users(&db), &verifier, &username).await?;
+        Ok((set_state(jar, &config, state), Json(options)))
+    }
+
+    async fn webauthn_register(
+        Extension(db): Extension<DB>,
+        Extension(config): Config,
+        jar: CookieJar,
+        LoggedIn(username): LoggedIn,
+        Json(registration): Json<WebauthnRegistration>,
+    ) -> AppResult<(CookieJar, StatusCode)> {
+        username.ok_or(StatusCode::UNAUTHORIZED)?;
+        let state = get_state(&jar)?;
+        register_finish(&db, &config.verifier()?, &state, &registration).await?;
+        Ok((remove_state(jar, &config), StatusCode::CREATED))
+    }
+
+    async fn webauthn_login_options(
+        Extension(db): Extension<DB>,
+        Extension(config): Config,
+        jar: CookieJar,
+        Json(form): Json<LoginOptionsForm>,
+    ) -> AppResult<(CookieJar, Json<WebauthnRequestOptions>)> {
+        let (state, options) = login_start(
+            &db,
+            &*config.users(&db),
+            &config.verifier()?,
+            &form.username,
+        )
+        .await?;
+        Ok((set_state(jar, &config, state), Json(options)))
+    }
+
+    async fn webauthn_login(
+        Extension(db): Extension<DB>,
+        Extension(config): Config,
+        jar: CookieJar,
+        sessions: Option<Extension<Sessions>>,
+        context: AuditContext,
+        Json(assertion): Json<WebauthnAssertion>,
+    ) -> AppResult<(CookieJar, Redirect)> {
+        let state = get_state(&jar)?;
+        let verifier = config.verifier()?;
+        let claims =
+            webauthn_login_claims(&db, &*config.users(&db), &verifier, &state, &assertion).await?;
+        let username = &claims.username;
+        config.check_lockout(&db, username).await?;
+        record(
+            &db,
+            AuditEvent::LoginSuccess,
+            username,
+            &context,
+            Some("passkey"),
+        )
+        .await;
+        let jar = remove_state(jar, &config);
+        let claims = config.claims(&db, claims).await?;
+        login_with_claims(jar, sessions, &config, claims).await
+    }
+
+    fn set_state(jar: CookieJar, config: &LoginConfig, state: String) -> CookieJar {
+        let c = Cookie::build((STATE_COOKIE, state))
+            .path(config.path("/webauthn"))
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .build();
+        jar.add(c)
+    }
+
+    fn get_state(jar: &CookieJar) -> AppResult<String> {
+        Ok(jar
+            .get(STATE_COOKIE)
+            .ok_or(StatusCode::UNAUTHORIZED)?
+            .value()
+            .to_string())
+    }
+
+    fn remove_state(jar: CookieJar, config: &LoginConfig) -> CookieJar {
+        jar.remove(Cookie::build(STATE_COOKIE).path(config.path("/webauthn")))
+    }
 }
@@ -0,0 +1,237 @@
+//! Token introspection (RFC 7662) for the services receiving the tokens of the login.
+//!
+//! Mounted with `App::login_introspection` at POST /api/auth/introspect, taking the form
+//! `token=<token>`. Callers authenticate with the HTTP Basic credentials of a client, or an
+//! `X-API-Key` header, configured in `IntrospectionConfig`.
+//!
+//! Access tokens and refresh tokens of an enabled user are `{"active": true}`, with the claims of
+//! the token and the `user` of the login, as are the tokens of an enabled client.
+//! Anything else is `{"active": false}`.
+
+use super::{
+    admin::{get_user, LoginUser},
+    api_flow::{refresh_token_hash, Problem},
+    client_credentials::client_scopes,
+    now,
+    store::{IntoUserStore, UserStore},
+    DB,
+};
+use crate::{
+    auth::{
+        csrf::constant_time_eq,
+        jwt::{jwt_verifier, setup_secret, JwtVerifier},
+    },
+    db::sql,
+};
+use axum::{
+    http::{header::WWW_AUTHENTICATE, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Extension, Form, Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{env, fmt, sync::Arc};
+
+/// Callers allowed to introspect the tokens.
+#[derive(Clone, Default)]
+pub struct IntrospectionConfig {
+    clients: Vec<(String, String)>,
+    api_keys: Vec<String>,
+    users: Option<Arc<dyn UserStore>>,
+    verifier: Option<JwtVerifier>,
+}
+
+impl fmt::Debug for IntrospectionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IntrospectionConfig")
+            .field("clients", &self.clients)
+            .field("api_keys", &self.api_keys)
+            .field("verifier", &self.verifier)
+            .finish_non_exhaustive()
+    }
+}
+
+impl IntrospectionConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Callers of the environment variables:
+    ///  - INTROSPECTION_CLIENTS=<id>:<secret>[,<id>:<secret>]
+    ///  - INTROSPECTION_API_KEYS=<key>[,<key>]
+    pub fn from_env() -> Self {
+        dotenvy::dotenv().ok();
+        let list = |name: &str| env::var(name).unwrap_or_default();
+        let mut config = Self::new();
+        for client in list("INTROSPECTION_CLIENTS").split(',') {
+            if let Some((id, secret)) = client.trim().split_once(':') {
+                config = config.client(id, secret);
+            }
+        }
+        for key in list("INTROSPECTION_API_KEYS").split(',') {
+            if !key.trim().is_empty() {
+                config = config.api_key(key.trim());
+            }
+        }
+        config
+    }
+
+    /// Allows the client authenticating with HTTP Basic credentials.
+    pub fn client(mut self, id: &str, secret: &str) -> Self {
+        self.clients.push((id.to_string(), secret.to_string()));
+        self
+    }
+
+    /// Allows the callers sending the key in the `X-API-Key` header.
+    pub fn api_key(mut self, key: &str) -> Self {
+        self.api_keys.push(key.to_string());
+        self
+    }
+
+    /// Looks up the users of the tokens in the store, as set with `LoginConfig::user_store`,
+    /// instead of the `login` table of the database.
+    pub fn user_store(mut self, store: impl UserStore + 'static) -> Self {
+        self.users = Some(Arc::new(store));
+        self
+    }
+
+    /// Verifies the access tokens with the verifier, as set with `LoginConfig::jwt_verifier`,
+    /// instead of the default one set up from JWT_SECRET.
+    pub fn jwt_verifier(mut self, verifier: JwtVerifier) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+
+    /// Sets up the secret of the default verifier from the environment, unless the config has its
+    /// own verifier.
+    pub(crate) async fn setup_verifier(&self) {
+        if self.verifier.is_none() {
+            setup_secret().await.expect("JWT initialization error");
+        }
+    }
+
+    fn allows(&self, headers: &HeaderMap) -> bool {
+        let matches = |a: &str, b: &str| constant_time_eq(a.as_bytes(), b.as_bytes());
+        if let Some(key) = headers.get("x-api-key").and_then(|k| k.to_str().ok()) {
+            return self.api_keys.iter().any(|k| matches(k, key));
+        }
+        let basic = headers
+            .get(axum::http::header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Basic "))
+            .and_then(|b| STANDARD.decode(b.trim()).ok())
+            .and_then(|b| String::from_utf8(b).ok());
+        let Some((id, secret)) = basic.as_deref().and_then(|b| b.split_once(':')) else {
+            return false;
+        };
+        self.clients
+            .iter()
+            .any(|(i, s)| matches(i, id) & matches(s, secret))
+    }
+}
+
+pub(crate) fn introspection_router(config: IntrospectionConfig) -> Router {
+    Router::new()
+        .route("/api/auth/introspect", post(introspect))
+        .layer(Extension(Arc::new(config)))
+}
+
+#[derive(Deserialize)]
+struct IntrospectRequest {
+    token: String,
+}
+
+#[derive(Serialize, Default)]
+struct Introspection {
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_type: Option<&'static str>,
+    #[serde(flatten)]
+    claims: Map<String, Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<LoginUser>,
+}
+
+async fn introspect(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Arc<IntrospectionConfig>>,
+    headers: HeaderMap,
+    Form(request): Form<IntrospectRequest>,
+) -> Result<Json<Introspection>, Response> {
+    if !config.allows(&headers) {
+        let problem = Problem::new(StatusCode::UNAUTHORIZED, "Invalid client credentials");
+        return Err(([(WWW_AUTHENTICATE, "Basic")], problem).into_response());
+    }
+    let claims = match access_claims(&config, &request.token) {
+        Some(claims) => Some((claims, "Bearer")),
+        None => refresh_claims(&db, &request.token)
+            .await
+            .map_err(IntoResponse::into_response)?
+            .map(|claims| (claims, "refresh_token")),
+    };
+    let Some((claims, token_type)) = claims else {
+        return Ok(Json(Introspection::default()));
+    };
+    let active = |user| {
+        Json(Introspection {
+            active: true,
+            token_type: Some(token_type),
+            claims: claims.clone(),
+            user,
+        })
+    };
+    if let Some(username) = claims.get("username").and_then(Value::as_str) {
+        let users = match &config.users {
+            Some(users) => users.clone(),
+            None => db.user_store(),
+        };
+        let user = get_user(&users, username)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        return match user {
+            Some(user) if user.confirmed && !user.disabled => Ok(active(Some(user))),
+            _ => Ok(Json(Introspection::default())),
+        };
+    }
+    // Tokens of the client credentials, while the client is enabled.
+    if let Some(client_id) = claims.get("client_id").and_then(Value::as_str) {
+        let scopes = client_scopes(&db, client_id)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        if scopes.is_some() {
+            return Ok(active(None));
+        }
+    }
+    Ok(Json(Introspection::default()))
+}
+
+fn access_claims(config: &IntrospectionConfig, token: &str) -> Option<Map<String, Value>> {
+    let verifier = match &config.verifier {
+        Some(verifier) => verifier.clone(),
+        None => jwt_verifier().ok()?,
+    };
+    match verifier.claims(token) {
+        Ok(claims) => Some(claims),
+        Err(_) => verifier.verify_client(token).ok().map(|claims| claims.1),
+    }
+}
+
+async fn refresh_claims(db: &DB, token: &str) -> Result<Option<Map<String, Value>>, Problem> {
+    let row: Option<(String, i64)> = sqlx::query_as(&sql(
+        "select username, expires from login_refresh where token = ?",
+    ))
+    .bind(refresh_token_hash(token))
+    .fetch_optional(db)
+    .await
+    .map_err(Problem::internal)?;
+    Ok(row
+        .filter(|(_, expires)| *expires >= now() as i64)
+        .map(|(username, expires)| {
+            let mut claims = Map::new();
+            claims.insert("username".into(), username.into());
+            claims.insert("exp".into(), expires.into());
+            claims
+        }))
+}
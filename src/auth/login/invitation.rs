@@ -0,0 +1,219 @@
+//! Invitations to register, for apps without open sign-up.
+//!
+//! An admin invites an email with the roles the new user will get. The invitation is mailed as
+//! a link to the registration page carrying a token signed with INVITATION_SECRET (or JWT_SECRET
+//! when not set). Registering with it creates an already confirmed user, and the invitation can't
+//! be used again. With `LoginConfig::invitation_only` the registration page requires one.
+
+use super::{
+    now, register_user_confirm, register_user_with_roles, store::IntoUserStore,
+    templates::LoginTemplates, DB,
+};
+use crate::{
+    db::sql,
+    errors::AppError,
+    mail::{send_multipart, InvitationMail},
+    prelude::{AppResult, MailTransport},
+};
+use axum::http::StatusCode;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::hmac;
+use sentry::types::random_uuid;
+use serde::Serialize;
+use std::{env, time::Duration};
+
+/// A pending or accepted invitation.
+#[derive(Debug, Clone, Serialize)]
+pub struct Invitation {
+    pub id: String,
+    pub email: String,
+    /// Roles of the user registering with the invitation.
+    pub roles: Vec<String>,
+    /// Seconds since the epoch.
+    pub expires: i64,
+    pub invited_by: Option<String>,
+    /// Username registered with the invitation.
+    pub accepted_by: Option<String>,
+}
+
+type InvitationRow = (String, String, String, i64, Option<String>, Option<String>);
+
+const INVITATION_COLUMNS: &str = "id, email, roles, expires, invited_by, accepted_by";
+
+impl From<InvitationRow> for Invitation {
+    fn from(row: InvitationRow) -> Self {
+        Self {
+            id: row.0,
+            email: row.1,
+            roles: row
+                .2
+                .split(',')
+                .filter(|r| !r.is_empty())
+                .map(String::from)
+                .collect(),
+            expires: row.3,
+            invited_by: row.4,
+            accepted_by: row.5,
+        }
+    }
+}
+
+impl Invitation {
+    /// Signed token of the invitation, to send in the registration link.
+    pub fn token(&self) -> AppResult<String> {
+        let tag = hmac::sign(&key()?, self.id.as_bytes());
+        Ok(format!(
+            "{}.{}",
+            self.id,
+            URL_SAFE_NO_PAD.encode(tag.as_ref())
+        ))
+    }
+}
+
+fn key() -> AppResult<hmac::Key> {
+    let secret = env::var("INVITATION_SECRET").or(env::var("JWT_SECRET"))?;
+    Ok(hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()))
+}
+
+fn invalid() -> AppError {
+    AppError::new(StatusCode::BAD_REQUEST, "Invalid invitation")
+}
+
+/// Invites the email, valid for the given lifetime.
+pub async fn create_invitation(
+    db: &DB,
+    email: &str,
+    roles: &[&str],
+    lifetime: Duration,
+    invited_by: Option<&str>,
+) -> AppResult<Invitation> {
+    let invitation = Invitation {
+        id: random_uuid().to_string(),
+        email: email.to_string(),
+        roles: roles.iter().map(|r| r.to_string()).collect(),
+        expires: (now() + lifetime.as_secs()) as i64,
+        invited_by: invited_by.map(String::from),
+        accepted_by: None,
+    };
+    sqlx::query(&sql(
+        "insert into login_invitation (id, email, roles, expires, invited_by) values(?, ?, ?, ?, ?)",
+    ))
+    .bind(&invitation.id)
+    .bind(&invitation.email)
+    .bind(roles.join(","))
+    .bind(invitation.expires)
+    .bind(&invitation.invited_by)
+    .execute(db)
+    .await?;
+    Ok(invitation)
+}
+
+/// The pending invitation of a token.
+/// Fails with BAD_REQUEST when the token is invalid or already used, and GONE when it expired.
+pub async fn find_invitation(db: &DB, token: &str) -> AppResult<Invitation> {
+    let (id, tag) = token.split_once('.').ok_or_else(invalid)?;
+    let tag = URL_SAFE_NO_PAD.decode(tag).map_err(|_| invalid())?;
+    hmac::verify(&key()?, id.as_bytes(), &tag).map_err(|_| invalid())?;
+    let row: Option<InvitationRow> = sqlx::query_as(&sql(&format!(
+        "select {INVITATION_COLUMNS} from login_invitation where id = ? and accepted_by is null"
+    )))
+    .bind(id)
+    .fetch_optional(db)
+    .await?;
+    let invitation = Invitation::from(row.ok_or_else(invalid)?);
+    if invitation.expires < now() as i64 {
+        return Err(AppError::new(StatusCode::GONE, "Invitation expired"));
+    }
+    Ok(invitation)
+}
+
+/// Invitations not accepted yet, including the expired ones, by expiry.
+pub async fn list_invitations(db: &DB) -> AppResult<Vec<Invitation>> {
+    let rows: Vec<InvitationRow> = sqlx::query_as(&sql(&format!(
+        "select {INVITATION_COLUMNS} from login_invitation where accepted_by is null order by expires"
+    )))
+    .fetch_all(db)
+    .await?;
+    Ok(rows.into_iter().map(Invitation::from).collect())
+}
+
+/// Deletes a pending invitation, its link can't be used anymore.
+pub async fn revoke_invitation(db: &DB, id: &str) -> AppResult<()> {
+    let result = sqlx::query(&sql(
+        "delete from login_invitation where id = ? and accepted_by is null",
+    ))
+    .bind(id)
+    .execute(db)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    Ok(())
+}
+
+/// Registers a confirmed user with the email and roles of the invitation.
+/// Fails like find_invitation, or like register_user when the user can't be created.
+pub async fn register_user_with_invitation(
+    db: &DB,
+    token: &str,
+    username: &str,
+    password: &str,
+) -> AppResult<Invitation> {
+    register_with_invitation(db, db, token, username, password).await
+}
+
+/// Same as register_user_with_invitation, creating the user in the store.
+pub(super) async fn register_with_invitation(
+    db: &DB,
+    users: &impl IntoUserStore,
+    token: &str,
+    username: &str,
+    password: &str,
+) -> AppResult<Invitation> {
+    let invitation = find_invitation(db, token).await?;
+    let accepted = sqlx::query(&sql(
+        "update login_invitation set accepted_by = ? where id = ? and accepted_by is null",
+    ))
+    .bind(username)
+    .bind(&invitation.id)
+    .execute(db)
+    .await?;
+    if accepted.rows_affected() == 0 {
+        return Err(invalid());
+    }
+    let roles = invitation
+        .roles
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let registered = async {
+        let code =
+            register_user_with_roles(users, username, &invitation.email, password, &roles).await?;
+        register_user_confirm(users, username, &code).await
+    }
+    .await;
+    if let Err(e) = registered {
+        sqlx::query(&sql(
+            "update login_invitation set accepted_by = null where id = ?",
+        ))
+        .bind(&invitation.id)
+        .execute(db)
+        .await?;
+        return Err(e);
+    }
+    Ok(Invitation {
+        accepted_by: Some(username.to_string()),
+        ..invitation
+    })
+}
+
+/// Mails the invitation, with the link to the registration page.
+pub fn send_invitation(
+    mailer: MailTransport,
+    templates: &dyn LoginTemplates,
+    mail: &InvitationMail,
+) -> AppResult<()> {
+    let plain = templates.invitation_mail_text(mail)?;
+    let html = templates.invitation_mail_html(mail)?;
+    send_multipart(mailer, &mail.email, plain, html)
+}
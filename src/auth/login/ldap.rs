@@ -0,0 +1,241 @@
+//! Login against an LDAP directory or Active Directory, with the feature `ldap`.
+//!
+//! `LdapBackend` checks the password with a simple bind as the user, then maps the groups of the
+//! user to the roles of its claims. On a successful login the user is added to (or updated in)
+//! the UserStore without a password, so that refresh tokens, the admin api and the audit log
+//! keep working, and disabling the user there still blocks the login.
+
+use super::{
+    backend::CredentialBackend,
+    store::{StoredUser, UserStore},
+};
+use crate::{errors::AppError, prelude::AppResult};
+use axum::{async_trait, http::StatusCode};
+use ldap3::{dn_escape, ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use sentry::types::random_uuid;
+use std::{collections::HashMap, env, time::Duration};
+use tracing::warn;
+
+/// Result code of a bind with a wrong password.
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// Credential backend binding to an LDAP server, set with `set_credential_backend`.
+///
+/// The groups of the user are searched under the group base when set, otherwise read from the
+/// `memberOf` attribute of its entry. A group becomes the role mapped with `group_role`, matching
+/// its DN or its first RDN value (the `cn`), or the `cn` itself when no mapping is configured.
+///
+/// ```rust
+/// use velvet_web::prelude::*;
+///
+/// set_credential_backend(
+///     LdapBackend::new("ldaps://ldap.example.com", "uid={username},ou=people,dc=example,dc=com")
+///         .group_search("ou=groups,dc=example,dc=com", "(member={dn})")
+///         .group_role("admins", "admin"),
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct LdapBackend {
+    url: String,
+    user_dn: String,
+    group_base: Option<String>,
+    group_filter: String,
+    group_roles: HashMap<String, String>,
+    default_roles: Vec<String>,
+    timeout: Duration,
+}
+
+impl LdapBackend {
+    /// Binds to the server at the url (`ldap://`, or `ldaps://` for TLS) as the DN of the
+    /// template, where `{username}` is replaced by the escaped username.
+    pub fn new(url: &str, user_dn: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            user_dn: user_dn.to_string(),
+            group_base: None,
+            group_filter: "(member={dn})".to_string(),
+            group_roles: HashMap::new(),
+            default_roles: vec!["user".to_string()],
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// The backend configured by the environment variables:
+    ///  - LDAP_URL=ldap://localhost:389
+    ///  - LDAP_USER_DN=uid={username},ou=people,dc=example,dc=com
+    ///  - LDAP_GROUP_BASE=ou=groups,dc=example,dc=com
+    ///  - LDAP_GROUP_FILTER=(member={dn})
+    ///  - LDAP_GROUP_ROLES=admins=admin,developers=editor
+    pub fn from_env() -> AppResult<Self> {
+        dotenvy::dotenv().ok();
+        let mut backend = Self::new(&env::var("LDAP_URL")?, &env::var("LDAP_USER_DN")?);
+        if let Ok(base) = env::var("LDAP_GROUP_BASE") {
+            let filter = env::var("LDAP_GROUP_FILTER").unwrap_or(backend.group_filter.clone());
+            backend = backend.group_search(&base, &filter);
+        }
+        if let Ok(roles) = env::var("LDAP_GROUP_ROLES") {
+            for mapping in roles.split(',').filter(|m| !m.is_empty()) {
+                let (group, role) = mapping
+                    .split_once('=')
+                    .ok_or(AppError::from("Invalid LDAP_GROUP_ROLES"))?;
+                backend = backend.group_role(group.trim(), role.trim());
+            }
+        }
+        Ok(backend)
+    }
+
+    /// Searches the groups of the user in the subtree of base, with the filter where `{dn}` is
+    /// replaced by the DN of the user. Default is the `memberOf` attribute of the user.
+    pub fn group_search(self, base: &str, filter: &str) -> Self {
+        Self {
+            group_base: Some(base.to_string()),
+            group_filter: filter.to_string(),
+            ..self
+        }
+    }
+
+    /// Gives the role to the members of the group, by DN or `cn`.
+    pub fn group_role(mut self, group: &str, role: &str) -> Self {
+        self.group_roles
+            .insert(group.to_lowercase(), role.to_string());
+        self
+    }
+
+    /// Roles of every user of the directory. Default "user".
+    pub fn default_roles(self, roles: &[&str]) -> Self {
+        Self {
+            default_roles: roles.iter().map(|r| r.to_string()).collect(),
+            ..self
+        }
+    }
+
+    /// Timeout of the connection. Default 5 seconds.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+
+    async fn bind(&self, username: &str, password: &str) -> AppResult<(Ldap, String)> {
+        // An empty password would be an unauthenticated bind, which always succeeds.
+        if username.is_empty() || password.is_empty() {
+            return Err(invalid());
+        }
+        let settings = LdapConnSettings::new().set_conn_timeout(self.timeout);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.url)
+            .await
+            .map_err(anyhow::Error::from)?;
+        tokio::spawn(async move {
+            if let Err(e) = conn.drive().await {
+                warn!("LDAP connection error: {}", e);
+            }
+        });
+        let dn = self.user_dn.replace("{username}", &dn_escape(username));
+        let result = ldap
+            .simple_bind(&dn, password)
+            .await
+            .map_err(anyhow::Error::from)?;
+        if result.rc == INVALID_CREDENTIALS {
+            return Err(invalid());
+        }
+        result.success().map_err(anyhow::Error::from)?;
+        Ok((ldap, dn))
+    }
+
+    async fn groups(&self, ldap: &mut Ldap, dn: &str) -> AppResult<Vec<String>> {
+        let entries = match &self.group_base {
+            Some(base) => {
+                let filter = self.group_filter.replace("{dn}", &ldap_escape(dn));
+                ldap.search(base, Scope::Subtree, &filter, vec!["cn"])
+                    .await
+                    .and_then(|r| r.success())
+                    .map_err(anyhow::Error::from)?
+                    .0
+                    .into_iter()
+                    .map(|e| SearchEntry::construct(e).dn)
+                    .collect()
+            }
+            None => ldap
+                .search(dn, Scope::Base, "(objectClass=*)", vec!["memberOf"])
+                .await
+                .and_then(|r| r.success())
+                .map_err(anyhow::Error::from)?
+                .0
+                .into_iter()
+                .flat_map(|e| {
+                    SearchEntry::construct(e)
+                        .attrs
+                        .into_iter()
+                        .filter(|(name, _)| name.eq_ignore_ascii_case("memberOf"))
+                        .flat_map(|(_, values)| values)
+                })
+                .collect(),
+        };
+        Ok(entries)
+    }
+
+    fn roles(&self, groups: &[String]) -> Vec<String> {
+        let mut roles = self.default_roles.clone();
+        for group in groups {
+            let cn = group
+                .split(',')
+                .next()
+                .and_then(|rdn| rdn.split_once('='))
+                .map(|(_, value)| value.trim().to_string())
+                .unwrap_or(group.clone());
+            let role = if self.group_roles.is_empty() {
+                Some(cn)
+            } else {
+                self.group_roles
+                    .get(&group.to_lowercase())
+                    .or(self.group_roles.get(&cn.to_lowercase()))
+                    .cloned()
+            };
+            if let Some(role) = role.filter(|r| !roles.contains(r)) {
+                roles.push(role);
+            }
+        }
+        roles
+    }
+}
+
+fn invalid() -> AppError {
+    AppError::new(StatusCode::UNAUTHORIZED, "Invalid username or password")
+}
+
+#[async_trait]
+impl CredentialBackend for LdapBackend {
+    async fn authenticate(
+        &self,
+        users: &dyn UserStore,
+        username: &str,
+        password: &str,
+    ) -> AppResult<Vec<String>> {
+        let (mut ldap, dn) = self.bind(username, password).await?;
+        let groups = self.groups(&mut ldap, &dn).await;
+        if let Err(e) = ldap.unbind().await {
+            warn!("LDAP unbind failed: {}", e);
+        }
+        let roles = self.roles(&groups?);
+        provision(users, username, &roles).await?;
+        Ok(roles)
+    }
+}
+
+/// Adds the directory user to the UserStore, or updates its roles.
+async fn provision(store: &dyn UserStore, username: &str, roles: &[String]) -> AppResult<()> {
+    match store.find(username).await? {
+        Some(user) if user.disabled => {
+            Err(AppError::new(StatusCode::UNAUTHORIZED, "User is disabled"))
+        }
+        Some(_) => store.set_roles(username, roles).await,
+        None => {
+            let user = StoredUser {
+                userid: random_uuid().to_string(),
+                username: username.to_string(),
+                roles: roles.to_vec(),
+                confirmed: true,
+                ..Default::default()
+            };
+            store.insert(&user).await
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Versioned schema of the login tables.
+//!
+//! The migrations are embedded in the library and tracked in their own `login_migrations`
+//! table, so that they don't interfere with the application's own `sqlx::migrate!()`.
+//! New migrations are only ever appended, with increasing versions, each listing its statements
+//! one by one. Postgres and MySQL hold an advisory lock while migrating, so that instances
+//! starting together apply them once.
+
+use crate::{
+    db::{sql, Backend, DB},
+    errors::AppResult,
+};
+use sqlx::{pool::PoolConnection, Connection};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+struct Migration {
+    version: i64,
+    description: &'static str,
+    statements: &'static [&'static str],
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "login",
+        statements: &["create table if not exists login (
+            userid varchar(255) not null,
+            username varchar(255) not null,
+            roles varchar(255) default 'user',
+            email varchar(255),
+            password varchar(255) not null,
+            confirmation_code varchar(255) not null,
+            confirmed smallint not null default 0,
+            primary key (userid),
+            unique (username)
+        )"],
+    },
+    Migration {
+        version: 2,
+        description: "login_webauthn",
+        statements: &["create table if not exists login_webauthn (
+            credential_id varchar(255) not null,
+            userid varchar(255) not null,
+            public_key varchar(255) not null,
+            sign_count bigint not null default 0,
+            primary key (credential_id)
+        )"],
+    },
+    Migration {
+        version: 3,
+        description: "login_refresh",
+        // The token column holds the hash of the refresh token, see refresh_token_hash.
+        statements: &["create table if not exists login_refresh (
+            token varchar(255) not null,
+            username varchar(255) not null,
+            expires bigint not null,
+            primary key (token)
+        )"],
+    },
+    Migration {
+        version: 4,
+        description: "login_admin",
+        statements: &[
+            "alter table login add column disabled smallint not null default 0",
+            "alter table login add column failed_logins bigint not null default 0",
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "login_confirmation",
+        statements: &[
+            "alter table login add column confirmation_expires bigint",
+            "alter table login add column confirmation_sent bigint",
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "login_email",
+        // Emails already used by another user are moved to pending_email, for an admin to sort
+        // out, before they become unique. The derived table lets MySQL read the updated table.
+        statements: &[
+            "alter table login add column pending_email varchar(255)",
+            "alter table login add column email_code varchar(255)",
+            "alter table login add column email_code_expires bigint",
+            "update login set email = null where email = ''",
+            "update login set pending_email = email, email = null \
+            where email is not null and userid not in \
+            (select userid from (select min(userid) userid from login \
+            where email is not null group by email) kept)",
+            "create unique index login_email on login (email)",
+        ],
+    },
+    Migration {
+        version: 7,
+        description: "login_audit",
+        statements: &[
+            "create table if not exists login_audit (
+                id varchar(255) not null,
+                created bigint not null,
+                event varchar(64) not null,
+                username varchar(255) not null,
+                ip varchar(255),
+                user_agent varchar(512),
+                detail varchar(512),
+                primary key (id)
+            )",
+            "create index login_audit_username on login_audit (username, created)",
+            "create index login_audit_created on login_audit (created)",
+        ],
+    },
+    Migration {
+        version: 8,
+        description: "login_invitation",
+        statements: &["create table if not exists login_invitation (
+            id varchar(255) not null,
+            email varchar(255) not null,
+            roles varchar(255) not null,
+            expires bigint not null,
+            invited_by varchar(255),
+            accepted_by varchar(255),
+            primary key (id)
+        )"],
+    },
+    Migration {
+        version: 9,
+        description: "login_client",
+        statements: &["create table if not exists login_client (
+            client_id varchar(255) not null,
+            secret varchar(255) not null,
+            scopes varchar(255) not null,
+            disabled smallint not null default 0,
+            primary key (client_id)
+        )"],
+    },
+    Migration {
+        version: 10,
+        description: "login_lockout",
+        statements: &["alter table login add column last_failed_login bigint"],
+    },
+    Migration {
+        version: 11,
+        description: "login_webauthn_ceremony",
+        statements: &["create table if not exists login_webauthn_ceremony (
+            jti varchar(255) not null,
+            expires bigint not null,
+            primary key (jti)
+        )"],
+    },
+    Migration {
+        version: 12,
+        description: "login_session",
+        statements: &["create table if not exists login_session (
+            id varchar(255) not null,
+            data text not null,
+            created bigint not null,
+            accessed bigint not null,
+            primary key (id)
+        )"],
+    },
+];
+
+/// Key of the advisory lock held while migrating.
+const LOCK_KEY: i64 = 0x76656c766574;
+
+/// Applies the login migrations not yet applied to the database.
+pub(crate) async fn login_migrate(db: &DB) -> AppResult<()> {
+    let mut conn = db.acquire().await?;
+    lock(&mut conn).await?;
+    let migrated = migrate(&mut conn).await;
+    let unlocked = unlock(&mut conn).await;
+    if unlocked.is_err() {
+        // The lock is released together with the connection.
+        conn.close_on_drop();
+    }
+    migrated.and(unlocked)
+}
+
+/// Applies the migrations not yet applied, as read once the lock is held.
+async fn migrate(conn: &mut PoolConnection<Backend>) -> AppResult<()> {
+    let create = r#"
+create table if not exists login_migrations (
+    version bigint not null,
+    description varchar(255) not null,
+    applied bigint not null,
+    primary key (version)
+)
+"#;
+    sqlx::query(create).execute(&mut **conn).await?;
+    let applied: Vec<(i64,)> = sqlx::query_as("select version from login_migrations")
+        .fetch_all(&mut **conn)
+        .await?;
+    for migration in MIGRATIONS {
+        if applied.iter().any(|(v,)| *v == migration.version) {
+            continue;
+        }
+        let mut tx = conn.begin().await?;
+        for statement in migration.statements {
+            sqlx::query(statement).execute(&mut *tx).await?;
+        }
+        sqlx::query(&sql(
+            "insert into login_migrations (version, description, applied) values(?, ?, ?)",
+        ))
+        .bind(migration.version)
+        .bind(migration.description)
+        .bind(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        info!(
+            version = migration.version,
+            description = migration.description,
+            "Applied login migration"
+        );
+    }
+    Ok(())
+}
+
+/// Waits for the advisory lock of the migrations, nothing to do on sqlite.
+async fn lock(conn: &mut PoolConnection<Backend>) -> AppResult<()> {
+    let lock = if cfg!(feature = "postgres") {
+        "select pg_advisory_lock(?)"
+    } else if cfg!(feature = "mysql") {
+        "select get_lock(concat('velvet_login_', ?), -1)"
+    } else {
+        return Ok(());
+    };
+    sqlx::query(&sql(lock))
+        .bind(LOCK_KEY)
+        .execute(&mut **conn)
+        .await?;
+    Ok(())
+}
+
+async fn unlock(conn: &mut PoolConnection<Backend>) -> AppResult<()> {
+    let unlock = if cfg!(feature = "postgres") {
+        "select pg_advisory_unlock(?)"
+    } else if cfg!(feature = "mysql") {
+        "select release_lock(concat('velvet_login_', ?))"
+    } else {
+        return Ok(());
+    };
+    sqlx::query(&sql(unlock))
+        .bind(LOCK_KEY)
+        .execute(&mut **conn)
+        .await?;
+    Ok(())
+}
@@ -1,117 +1,349 @@
 #![cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
 
+pub mod admin;
+pub mod api_flow;
+pub mod audit;
+pub mod backend;
+pub mod client_credentials;
 pub mod default_flow;
+pub mod introspection;
+pub mod invitation;
+#[cfg(feature = "ldap")]
+pub mod ldap;
+mod migrations;
+pub mod password;
+pub mod store;
+pub mod templates;
+#[cfg(feature = "webauthn")]
+pub mod webauthn;
 
-use super::{jwt::token_from_claims, CookieToken};
-use crate::prelude::AppResult;
-use argon2::{
-    password_hash::{rand_core::OsRng, SaltString},
-    Argon2,
-};
-use argon2::{PasswordHash, PasswordHasher, PasswordVerifier};
+use super::{jwt::token_from_claims, session::Session, CookieToken};
+use crate::{db::DB, errors::AppError, prelude::AppResult};
 use axum::{http::status::StatusCode, response::Redirect};
 use axum_extra::extract::CookieJar;
+use backend::credential_backend;
+use password::password_policy;
 use sentry::types::random_uuid;
-use serde::Serialize;
-use sqlx::Pool;
-use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::{
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use store::{IntoUserStore, StoredUser, UserStore};
 use tracing::warn;
 
-#[cfg(feature = "sqlite")]
-type DB = Pool<sqlx::Sqlite>;
-
-#[cfg(feature = "mysql")]
-type DB = Pool<sqlx::Mysql>;
-
-#[cfg(feature = "postgres")]
-type DB = Pool<sqlx::Postgres>;
-
+/// Creates or updates the login tables, applying the embedded login migrations.
+/// This is already called by the login flows on startup.
 pub async fn login_setup(db: &DB) -> AppResult<()> {
-    let create = r#"
-create table if not exists login (
-    userid varchar(255) not null,
-    username varchar(255) not null,
-    roles varchar(255) default 'user',
-    email varchar(255),
-    password varchar(255) not null,
-    confirmation_code varchar(255) not null,
-    confirmed smallint not null default 0,
-    primary key (userid),
-    unique (username)
-)
-"#;
-    sqlx::query(create).execute(db).await?;
-    Ok(())
+    migrations::login_migrate(db).await
 }
 
+/// Validity of a confirmation code.
+const CONFIRMATION_LIFETIME: u64 = 3600 * 24;
+/// Minimum delay between two confirmation codes sent to the same user.
+const CONFIRMATION_RESEND_INTERVAL: u64 = 60;
+
 /// Returns the confirmation code that will be used for register_user_confirm
 pub async fn register_user(
-    db: &DB,
+    users: &impl IntoUserStore,
     username: &str,
     email: &str,
     password: &str,
 ) -> AppResult<String> {
-    let user = User::create(username, email, password)?;
+    register_user_with_roles(users, username, email, password, &["user"]).await
+}
+
+/// Same as register_user, assigning the given roles instead of the default "user".
+/// An empty email is stored as none, as emails are unique.
+/// Fails with BAD_REQUEST when the password doesn't satisfy the PasswordPolicy.
+pub async fn register_user_with_roles(
+    users: &impl IntoUserStore,
+    username: &str,
+    email: &str,
+    password: &str,
+    roles: &[&str],
+) -> AppResult<String> {
+    password_policy()?.check(username, password)?;
     let code = random_uuid().to_string();
-    sqlx::query(
-        "insert into login 
-        (userid, username, email, password, confirmation_code, confirmed) 
-        values(?, ?, ?, ?, ?, 9)",
-    )
-    .bind(user.userid)
-    .bind(user.username)
-    .bind(user.email)
-    .bind(user.password)
-    .bind(code.clone())
-    .execute(db)
-    .await?;
+    let user = StoredUser {
+        userid: random_uuid().to_string(),
+        username: username.to_string(),
+        email: Some(email.to_string()).filter(|e| !e.is_empty()),
+        password: hash_password(password)?,
+        roles: roles.iter().map(|r| r.to_string()).collect(),
+        confirmation_code: code.clone(),
+        confirmation_expires: Some((now() + CONFIRMATION_LIFETIME) as i64),
+        confirmation_sent: Some(now() as i64),
+        ..Default::default()
+    };
+    users.user_store().insert(&user).await?;
     Ok(code)
 }
 
+/// Confirms the registration. The code can only be used once, until it expires after 24 hours.
+/// Fails with BAD_REQUEST when the username or code don't match, and GONE when the code expired.
 pub async fn register_user_confirm(
-    db: &DB,
+    users: &impl IntoUserStore,
     username: &str,
     confirmation_code: &str,
 ) -> AppResult<()> {
-    sqlx::query("update login set confirmed = 1 where username = ? and confirmation_code = ?")
-        .bind(username)
-        .bind(confirmation_code)
-        .execute(db)
-        .await?;
+    let invalid = || AppError::new(StatusCode::BAD_REQUEST, "Invalid confirmation code");
+    let store = users.user_store();
+    let user = store
+        .find(username)
+        .await?
+        .filter(|u| !u.confirmed && u.confirmation_code == confirmation_code)
+        .ok_or_else(invalid)?;
+    if user
+        .confirmation_expires
+        .is_some_and(|expires| expires < now() as i64)
+    {
+        return Err(AppError::new(StatusCode::GONE, "Confirmation code expired"));
+    }
+    if !store.confirm(username, confirmation_code).await? {
+        return Err(invalid());
+    }
     Ok(())
 }
 
-#[derive(Serialize)]
-struct Claims {
-    exp: u64,
-    username: String,
-    roles: Vec<String>,
+/// Replaces the confirmation code of a user not yet confirmed, returning its email and the new code.
+/// Fails with TOO_MANY_REQUESTS when the previous code was sent less than a minute ago.
+pub async fn resend_confirmation(
+    users: &impl IntoUserStore,
+    username: &str,
+) -> AppResult<(String, String)> {
+    let store = users.user_store();
+    let user = store.find(username).await?.filter(|u| !u.confirmed);
+    let Some(StoredUser {
+        email: Some(email),
+        confirmation_sent: sent,
+        ..
+    }) = user
+    else {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "No registration to confirm",
+        ));
+    };
+    if sent.is_some_and(|sent| sent + CONFIRMATION_RESEND_INTERVAL as i64 > now() as i64) {
+        return Err(AppError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Wait a minute before requesting another code",
+        ));
+    }
+    let code = random_uuid().to_string();
+    store
+        .set_confirmation(
+            username,
+            &code,
+            (now() + CONFIRMATION_LIFETIME) as i64,
+            now() as i64,
+        )
+        .await?;
+    Ok((email, code))
 }
 
-async fn login_claims(db: &DB, username: &str, password: &str) -> AppResult<Claims> {
-    let row: (String, String, String) =
-        sqlx::query_as("select username, password, roles from login where username = ? and confirmed = 1")
-            .bind(username)
-            .fetch_one(db)
-            .await?;
-    let hash = PasswordHash::new(row.1.as_str())?;
-    Argon2::default().verify_password(password.as_bytes(), &hash)?;
-    Ok(Claims {
-        username: username.to_string(),
-        roles: row.2.split(",").map(String::from).collect(),
-        exp: SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            + 3600 * 24,
-    })
+/// How users identify themselves at login.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoginWith {
+    #[default]
+    Username,
+    Email,
+    /// The username, or else the email.
+    UsernameOrEmail,
+}
+
+/// Finds the username of a user identified by username and/or email.
+pub async fn find_username(
+    users: &impl IntoUserStore,
+    login_with: LoginWith,
+    identifier: &str,
+) -> AppResult<String> {
+    let store = users.user_store();
+    if login_with != LoginWith::Email {
+        if let Some(user) = store.find(identifier).await? {
+            return Ok(user.username);
+        }
+    }
+    if login_with != LoginWith::Username {
+        if let Some(user) = store.find_by_email(identifier).await? {
+            return Ok(user.username);
+        }
+    }
+    Err(StatusCode::NOT_FOUND.into())
 }
 
-pub async fn login_token(db: &DB, username: &str, password: &str) -> AppResult<String> {
-    let claims = login_claims(db, username, password).await.map_err(|e| {
-        warn!("Login failed: {:?}", e);
-        StatusCode::UNAUTHORIZED
-    })?;
+/// Starts changing the email of the user, returning the code to send to the new address.
+/// The change only happens with confirm_email_change, the code expires after 24 hours.
+/// Fails with CONFLICT when the address is used by another user.
+pub async fn request_email_change(
+    users: &impl IntoUserStore,
+    username: &str,
+    email: &str,
+) -> AppResult<String> {
+    let store = users.user_store();
+    if store.find_by_email(email).await?.is_some() {
+        return Err(AppError::new(StatusCode::CONFLICT, "Email already in use"));
+    }
+    let code = random_uuid().to_string();
+    store
+        .set_pending_email(
+            username,
+            email,
+            &code,
+            (now() + CONFIRMATION_LIFETIME) as i64,
+        )
+        .await?;
+    Ok(code)
+}
+
+/// Swaps in the pending email of the user, returning the old and the new address.
+/// Fails with BAD_REQUEST when the username or code don't match, GONE when the code expired, and
+/// CONFLICT when another user got the address meanwhile.
+pub async fn confirm_email_change(
+    users: &impl IntoUserStore,
+    username: &str,
+    code: &str,
+) -> AppResult<(Option<String>, String)> {
+    let invalid = || AppError::new(StatusCode::BAD_REQUEST, "Invalid verification code");
+    let store = users.user_store();
+    let user = store
+        .find(username)
+        .await?
+        .filter(|u| u.pending_email.is_some() && u.email_code.as_deref() == Some(code))
+        .ok_or_else(invalid)?;
+    if user.email_code_expires.unwrap_or(0) < now() as i64 {
+        return Err(AppError::new(StatusCode::GONE, "Verification code expired"));
+    }
+    if !store.apply_pending_email(username, code).await? {
+        return Err(invalid());
+    }
+    Ok((user.email, user.pending_email.unwrap_or_default()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Claims of the tokens issued by the login, which a ClaimsHook can enrich or replace.
+#[derive(Debug, Clone, Serialize)]
+pub struct Claims {
+    /// Expiration, in seconds since the epoch.
+    pub exp: u64,
+    /// Issue time, in seconds since the epoch.
+    pub iat: u64,
+    /// Userid of the user.
+    pub sub: String,
+    /// Unique id of the token.
+    pub jti: String,
+    /// JWT_ISSUER, when set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    /// First value of JWT_AUDIENCE, when set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    pub username: String,
+    pub roles: Vec<String>,
+    /// The real user acting on behalf of username, while impersonating.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) act: Option<Act>,
+    /// Claims added by the app, like a tenant id or a display name.
+    /// Entries named as the claims above are left out of the token.
+    #[serde(flatten, serialize_with = "serialize_extra")]
+    pub extra: Map<String, Value>,
+}
+
+/// Names of the claims of the login, which the extra claims can't replace.
+const RESERVED_CLAIMS: &[&str] = &[
+    "exp", "iat", "sub", "jti", "iss", "aud", "username", "roles", "act",
+];
+
+fn serialize_extra<S: Serializer>(
+    extra: &Map<String, Value>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(
+        extra
+            .iter()
+            .filter(|(name, _)| !RESERVED_CLAIMS.contains(&name.as_str())),
+    )
+}
+
+/// Actor claim (RFC 8693) of an impersonation token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Act {
+    sub: String,
+}
+
+async fn login_claims(users: &dyn UserStore, username: &str, password: &str) -> AppResult<Claims> {
+    let roles = credential_backend()
+        .authenticate(users, username, password)
+        .await?;
+    let userid = match users.find(username).await? {
+        Some(user) => user.userid,
+        None => username.to_string(),
+    };
+    Ok(Claims::new(&userid, username, roles))
+}
+
+/// Claims of a confirmed user, without checking the password.
+async fn user_claims(users: &dyn UserStore, username: &str) -> AppResult<Claims> {
+    let user = users
+        .find(username)
+        .await?
+        .filter(|u| u.confirmed && !u.disabled)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Claims::new(&user.userid, username, user.roles))
+}
+
+/// First value of a comma separated environment variable.
+fn first_of(name: &str) -> Option<String> {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.split(',').next().map(|v| v.trim().to_string()))
+}
+
+impl Claims {
+    fn new(userid: &str, username: &str, roles: Vec<String>) -> Self {
+        let now = now();
+        Self {
+            exp: now + 3600 * 24,
+            iat: now,
+            sub: userid.to_string(),
+            jti: random_uuid().to_string(),
+            iss: first_of("JWT_ISSUER"),
+            aud: first_of("JWT_AUDIENCE"),
+            username: username.to_string(),
+            roles,
+            act: None,
+            extra: Map::new(),
+        }
+    }
+
+    fn lifetime(self, lifetime: Duration) -> Self {
+        Self {
+            exp: self.iat + lifetime.as_secs(),
+            ..self
+        }
+    }
+}
+
+pub async fn login_token(
+    users: &impl IntoUserStore,
+    username: &str,
+    password: &str,
+) -> AppResult<String> {
+    let users = users.user_store();
+    let claims = login_claims(&*users, username, password)
+        .await
+        .map_err(|e| {
+            warn!("Login failed: {:?}", e);
+            StatusCode::UNAUTHORIZED
+        })?;
     token_from_claims(&claims).map_err(|e| {
         warn!("Login failed: {}", e);
         StatusCode::UNAUTHORIZED.into()
@@ -121,19 +353,11 @@ pub async fn login_token(db: &DB, username: &str, password: &str) -> AppResult<S
 pub async fn login_cookie(
     jar: CookieJar,
     redirect: &str,
-    db: &DB,
+    users: &impl IntoUserStore,
     username: &str,
     password: &str,
 ) -> AppResult<(CookieJar, Redirect)> {
-    let claims = login_claims(db, username, password).await.map_err(|e| {
-        warn!("Login failed: {:?}", e);
-        Redirect::to(redirect)
-    })?;
-    let jar = CookieToken::set_from_claims(jar, claims).map_err(|e| {
-        warn!("Login failed: {}", e);
-        Redirect::to(redirect)
-    })?;
-    Ok((jar, Redirect::to(redirect)))
+    login_redirect(jar, None, redirect, users, username, password).await
 }
 
 pub fn logout_cookie(jar: CookieJar, redirect: &str) -> AppResult<(CookieJar, Redirect)> {
@@ -141,24 +365,60 @@ pub fn logout_cookie(jar: CookieJar, redirect: &str) -> AppResult<(CookieJar, Re
     Ok((jar, Redirect::to(redirect)))
 }
 
-#[derive(Debug, Clone)]
-struct User {
-    userid: String,
-    username: String,
-    email: String,
-    password: String,
+/// Same as login_cookie, but keeps the claims in the server side session instead of the cookie.
+/// The session id is rotated on login.
+pub async fn login_session(
+    jar: CookieJar,
+    session: Session,
+    redirect: &str,
+    users: &impl IntoUserStore,
+    username: &str,
+    password: &str,
+) -> AppResult<(CookieJar, Redirect)> {
+    login_redirect(jar, Some(session), redirect, users, username, password).await
 }
 
-impl User {
-    fn create(username: &str, email: &str, password: &str) -> AppResult<Self> {
-        let salt = SaltString::generate(&mut OsRng);
-        let argon = Argon2::default();
-        let hash = argon.hash_password(password.as_bytes(), &salt)?.to_string();
-        Ok(Self {
-            userid: random_uuid().to_string(),
-            username: username.to_string(),
-            email: email.to_string(),
-            password: hash,
-        })
-    }
+/// Checks the credentials and keeps the claims in the session when there is one, or else in the
+/// cookie. Failures redirect as well.
+async fn login_redirect(
+    jar: CookieJar,
+    session: Option<Session>,
+    redirect: &str,
+    users: &impl IntoUserStore,
+    username: &str,
+    password: &str,
+) -> AppResult<(CookieJar, Redirect)> {
+    let users = users.user_store();
+    let claims = login_claims(&*users, username, password)
+        .await
+        .map_err(|e| {
+            warn!("Login failed: {:?}", e);
+            Redirect::to(redirect)
+        })?;
+    let jar = match session {
+        Some(mut session) => {
+            session.rotate();
+            session.set_claims(claims)?;
+            session.save(jar).await?
+        }
+        None => CookieToken::set_from_claims(jar, claims).map_err(|e| {
+            warn!("Login failed: {}", e);
+            Redirect::to(redirect)
+        })?,
+    };
+    Ok((jar, Redirect::to(redirect)))
+}
+
+/// Same as logout_cookie, also destroying the server side session.
+pub async fn logout_session(
+    jar: CookieJar,
+    session: Session,
+    redirect: &str,
+) -> AppResult<(CookieJar, Redirect)> {
+    let jar = session.destroy(jar).await?;
+    logout_cookie(jar, redirect)
+}
+
+fn hash_password(password: &str) -> AppResult<String> {
+    password_policy()?.hash(password)
 }
@@ -0,0 +1,207 @@
+//! Password policy and hashing of the login.
+//!
+//! New passwords are checked by register_user, by the registration of the login flows and by
+//! password resets. Unlike previous versions, which accepted any password on registration, the
+//! default policy requires at least 8 characters and rejects passwords too similar to the
+//! username. Apps upgrading can loosen it with `set_password_policy`, for example with
+//! `PasswordPolicy::new().min_length(4).reject_username(false)`, or with PASSWORD_MIN_LENGTH.
+//! Existing passwords keep working, and are rehashed on login when the Argon2 parameters change.
+
+use crate::{errors::AppError, prelude::AppResult};
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher, Version,
+};
+use axum::http::StatusCode;
+use std::{
+    collections::HashSet,
+    env,
+    sync::{Arc, RwLock},
+};
+
+static POLICY: RwLock<Option<Arc<PasswordPolicy>>> = RwLock::new(None);
+
+/// Rules for new passwords, and the Argon2 parameters used to hash them.
+/// Enforced by register_user and password resets, the policy is set with `set_password_policy`,
+/// otherwise it is read from the environment:
+///  - PASSWORD_MIN_LENGTH=8
+///  - PASSWORD_BREACHED_LIST=<file with one password per line>
+///  - ARGON2_MEMORY_KIB=19456
+///  - ARGON2_ITERATIONS=2
+///  - ARGON2_PARALLELISM=1
+///
+/// ```rust
+/// use velvet_web::prelude::*;
+///
+/// set_password_policy(
+///     PasswordPolicy::new()
+///         .min_length(12)
+///         .argon2(65536, 3, 1),
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    min_length: usize,
+    max_length: usize,
+    breached: HashSet<String>,
+    reject_username: bool,
+    params: Params,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            max_length: 128,
+            breached: HashSet::new(),
+            reject_username: true,
+            params: Params::default(),
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The policy configured by the environment variables.
+    pub fn from_env() -> AppResult<Self> {
+        dotenvy::dotenv().ok();
+        let mut policy = Self::default();
+        if let Ok(min_length) = env::var("PASSWORD_MIN_LENGTH") {
+            policy = policy.min_length(min_length.parse().map_err(anyhow::Error::from)?);
+        }
+        if let Ok(path) = env::var("PASSWORD_BREACHED_LIST") {
+            policy = policy.breached_list_file(&path)?;
+        }
+        let var = |name: &str, default: u32| -> AppResult<u32> {
+            match env::var(name) {
+                Ok(value) => Ok(value.parse().map_err(anyhow::Error::from)?),
+                Err(_) => Ok(default),
+            }
+        };
+        let defaults = Params::default();
+        Ok(policy.argon2(
+            var("ARGON2_MEMORY_KIB", defaults.m_cost())?,
+            var("ARGON2_ITERATIONS", defaults.t_cost())?,
+            var("ARGON2_PARALLELISM", defaults.p_cost())?,
+        ))
+    }
+
+    /// Minimum number of characters. Default 8.
+    pub fn min_length(self, min_length: usize) -> Self {
+        Self { min_length, ..self }
+    }
+
+    /// Maximum number of characters. Default 128.
+    pub fn max_length(self, max_length: usize) -> Self {
+        Self { max_length, ..self }
+    }
+
+    /// Rejects the passwords listed in the file, one per line, for example a list of breached passwords.
+    pub fn breached_list_file(self, path: &str) -> AppResult<Self> {
+        let list = std::fs::read_to_string(path)?;
+        Ok(self.breached_passwords(list.lines()))
+    }
+
+    /// Rejects the given passwords.
+    pub fn breached_passwords<'a>(mut self, passwords: impl IntoIterator<Item = &'a str>) -> Self {
+        self.breached.extend(
+            passwords
+                .into_iter()
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(String::from),
+        );
+        self
+    }
+
+    /// Whether passwords containing the username, or contained in it, are rejected. Default true.
+    pub fn reject_username(self, reject_username: bool) -> Self {
+        Self {
+            reject_username,
+            ..self
+        }
+    }
+
+    /// Argon2 memory in KiB, iterations and parallelism used for new hashes.
+    /// Existing hashes are rehashed on the next successful login.
+    /// Invalid parameters fall back to the Argon2 defaults.
+    pub fn argon2(self, memory_kib: u32, iterations: u32, parallelism: u32) -> Self {
+        let params = Params::new(memory_kib, iterations, parallelism, None).unwrap_or_else(|e| {
+            tracing::warn!("Invalid argon2 parameters, using the defaults: {}", e);
+            Params::default()
+        });
+        Self { params, ..self }
+    }
+
+    /// Checks a new password of the user, failing with BAD_REQUEST and the reason.
+    pub fn check(&self, username: &str, password: &str) -> AppResult<()> {
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Err(rejected(&format!(
+                "Password must have at least {} characters",
+                self.min_length
+            )));
+        }
+        if length > self.max_length {
+            return Err(rejected(&format!(
+                "Password must have at most {} characters",
+                self.max_length
+            )));
+        }
+        if self.breached.contains(password) {
+            return Err(rejected("Password is too common"));
+        }
+        let username = username.to_lowercase();
+        let lowercase = password.to_lowercase();
+        if self.reject_username
+            && !username.is_empty()
+            && (lowercase.contains(&username) || username.contains(&lowercase))
+        {
+            return Err(rejected("Password is too similar to the username"));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn hash(&self, password: &str) -> AppResult<String> {
+        let salt = SaltString::generate(&mut OsRng);
+        let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone());
+        Ok(argon.hash_password(password.as_bytes(), &salt)?.to_string())
+    }
+
+    /// Whether the hash was made with other parameters than the current ones.
+    pub(crate) fn needs_rehash(&self, hash: &PasswordHash) -> bool {
+        if hash.algorithm != Algorithm::Argon2id.ident() {
+            return true;
+        }
+        match Params::try_from(hash) {
+            Ok(params) => {
+                params.m_cost() != self.params.m_cost()
+                    || params.t_cost() != self.params.t_cost()
+                    || params.p_cost() != self.params.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+fn rejected(reason: &str) -> AppError {
+    AppError::new(StatusCode::BAD_REQUEST, reason)
+}
+
+/// Replaces the password policy used by the login.
+pub fn set_password_policy(policy: PasswordPolicy) {
+    *POLICY.write().unwrap() = Some(Arc::new(policy));
+}
+
+/// The current password policy, read from the environment when not set.
+pub(crate) fn password_policy() -> AppResult<Arc<PasswordPolicy>> {
+    if let Some(policy) = POLICY.read().unwrap().as_ref() {
+        return Ok(policy.clone());
+    }
+    let policy = Arc::new(PasswordPolicy::from_env()?);
+    *POLICY.write().unwrap() = Some(policy.clone());
+    Ok(policy)
+}
@@ -0,0 +1,578 @@
+//! Storage of the login users.
+//!
+//! The login functions take the users as anything implementing `IntoUserStore`: the database,
+//! for `SqlUserStore` and the `login` table, or a UserStore. The flows, the admin api and the
+//! introspection use the store of their config, set with `LoginConfig::user_store`.
+//! Apps can keep users in their own schema with another implementation, or in memory for tests
+//! with `MemoryUserStore`. Passkeys, refresh tokens, invitations and the audit log stay in the
+//! login tables of the database, referring to the users by username or userid.
+
+use super::{now, DB};
+use crate::{
+    db::{conflict, sql},
+    errors::AppError,
+    prelude::AppResult,
+};
+use axum::{async_trait, http::StatusCode};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// A user as kept by a UserStore. Times are in seconds since the epoch.
+#[derive(Debug, Clone, Default)]
+pub struct StoredUser {
+    pub userid: String,
+    pub username: String,
+    pub email: Option<String>,
+    /// Argon2 hash, empty for users of another CredentialBackend.
+    pub password: String,
+    pub roles: Vec<String>,
+    pub confirmed: bool,
+    pub confirmation_code: String,
+    pub confirmation_expires: Option<i64>,
+    pub confirmation_sent: Option<i64>,
+    pub disabled: bool,
+    pub failed_logins: i64,
+    pub last_failed_login: Option<i64>,
+    /// Email waiting for verification with the email code.
+    pub pending_email: Option<String>,
+    pub email_code: Option<String>,
+    pub email_code_expires: Option<i64>,
+}
+
+/// Storage backend for the login users.
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    /// Adds the user, failing with CONFLICT when the username or the email are already taken.
+    async fn insert(&self, user: &StoredUser) -> AppResult<()>;
+    async fn find(&self, username: &str) -> AppResult<Option<StoredUser>>;
+    async fn find_by_id(&self, userid: &str) -> AppResult<Option<StoredUser>>;
+    async fn find_by_email(&self, email: &str) -> AppResult<Option<StoredUser>>;
+    /// Users whose username or email contain the query, ignoring case, ordered by username, and
+    /// their total.
+    async fn search(
+        &self,
+        query: &str,
+        offset: i64,
+        limit: i64,
+    ) -> AppResult<(Vec<StoredUser>, i64)>;
+    /// Removes the user, returning false when it doesn't exist.
+    async fn delete(&self, username: &str) -> AppResult<bool>;
+    /// Replaces the confirmation code of a user not confirmed yet.
+    async fn set_confirmation(
+        &self,
+        username: &str,
+        code: &str,
+        expires: i64,
+        sent: i64,
+    ) -> AppResult<()>;
+    /// Confirms the user when the code matches, returning false otherwise.
+    async fn confirm(&self, username: &str, code: &str) -> AppResult<bool>;
+    async fn set_password(&self, username: &str, password: &str) -> AppResult<()>;
+    async fn set_roles(&self, username: &str, roles: &[String]) -> AppResult<()>;
+    /// Fails with CONFLICT when the email is used by another user.
+    async fn set_email(&self, username: &str, email: Option<&str>) -> AppResult<()>;
+    async fn set_disabled(&self, username: &str, disabled: bool) -> AppResult<()>;
+    /// Counts a wrong password and its time, or resets the count after a successful login.
+    async fn record_login(&self, username: &str, success: bool) -> AppResult<()>;
+    /// Sets the email waiting for verification, failing with NOT_FOUND when the user doesn't exist.
+    async fn set_pending_email(
+        &self,
+        username: &str,
+        email: &str,
+        code: &str,
+        expires: i64,
+    ) -> AppResult<()>;
+    /// Replaces the email with the pending one when the code matches, returning false otherwise.
+    /// Fails with CONFLICT when another user got the email meanwhile.
+    async fn apply_pending_email(&self, username: &str, code: &str) -> AppResult<bool>;
+}
+
+/// Where the login functions find the users: the database with its `login` table, or a UserStore.
+pub trait IntoUserStore: Send + Sync {
+    fn user_store(&self) -> Arc<dyn UserStore>;
+}
+
+impl IntoUserStore for DB {
+    fn user_store(&self) -> Arc<dyn UserStore> {
+        Arc::new(SqlUserStore::new(self))
+    }
+}
+
+impl IntoUserStore for Arc<dyn UserStore> {
+    fn user_store(&self) -> Arc<dyn UserStore> {
+        self.clone()
+    }
+}
+
+impl<T: UserStore + Clone + 'static> IntoUserStore for T {
+    fn user_store(&self) -> Arc<dyn UserStore> {
+        Arc::new(self.clone())
+    }
+}
+
+/// User store kept in the `login` table of the database.
+#[derive(Clone)]
+pub struct SqlUserStore(DB);
+
+impl SqlUserStore {
+    /// The store of the database, whose tables are created by login_setup.
+    pub fn new(db: &DB) -> Self {
+        Self(db.clone())
+    }
+
+    async fn find_where(&self, column: &str, value: &str) -> AppResult<Option<StoredUser>> {
+        let row: Option<UserRow> = sqlx::query_as(&sql(&format!(
+            "select {USER_COLUMNS} from login where {column} = ?"
+        )))
+        .bind(value)
+        .fetch_optional(&self.0)
+        .await?;
+        Ok(row.map(StoredUser::from))
+    }
+}
+
+type UserRow = (
+    String,
+    String,
+    Option<String>,
+    String,
+    String,
+    i16,
+    String,
+    Option<i64>,
+    Option<i64>,
+    i16,
+    i64,
+    Option<i64>,
+    Option<String>,
+    Option<String>,
+    Option<i64>,
+);
+
+const USER_COLUMNS: &str = "userid, username, email, password, coalesce(roles, ''), confirmed, \
+    confirmation_code, confirmation_expires, confirmation_sent, disabled, failed_logins, \
+    last_failed_login, pending_email, email_code, email_code_expires";
+
+impl From<UserRow> for StoredUser {
+    fn from(row: UserRow) -> Self {
+        Self {
+            userid: row.0,
+            username: row.1,
+            email: row.2,
+            password: row.3,
+            roles: row
+                .4
+                .split(',')
+                .filter(|r| !r.is_empty())
+                .map(String::from)
+                .collect(),
+            confirmed: row.5 == 1,
+            confirmation_code: row.6,
+            confirmation_expires: row.7,
+            confirmation_sent: row.8,
+            disabled: row.9 != 0,
+            failed_logins: row.10,
+            last_failed_login: row.11,
+            pending_email: row.12,
+            email_code: row.13,
+            email_code_expires: row.14,
+        }
+    }
+}
+
+#[async_trait]
+impl UserStore for SqlUserStore {
+    async fn insert(&self, user: &StoredUser) -> AppResult<()> {
+        sqlx::query(&sql(
+            "insert into login (userid, username, roles, email, password, confirmation_code, \
+            confirmed, confirmation_expires, confirmation_sent, disabled) \
+            values(?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        ))
+        .bind(&user.userid)
+        .bind(&user.username)
+        .bind(user.roles.join(","))
+        .bind(&user.email)
+        .bind(&user.password)
+        .bind(&user.confirmation_code)
+        .bind(if user.confirmed { 1i16 } else { 0 })
+        .bind(user.confirmation_expires)
+        .bind(user.confirmation_sent)
+        .bind(user.disabled as i16)
+        .execute(&self.0)
+        .await
+        .map_err(conflict("User already exists"))?;
+        Ok(())
+    }
+
+    async fn find(&self, username: &str) -> AppResult<Option<StoredUser>> {
+        self.find_where("username", username).await
+    }
+
+    async fn find_by_id(&self, userid: &str) -> AppResult<Option<StoredUser>> {
+        self.find_where("userid", userid).await
+    }
+
+    async fn find_by_email(&self, email: &str) -> AppResult<Option<StoredUser>> {
+        self.find_where("email", email).await
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        offset: i64,
+        limit: i64,
+    ) -> AppResult<(Vec<StoredUser>, i64)> {
+        let pattern = format!("%{}%", escape_like(query));
+        let (total,): (i64,) = sqlx::query_as(&sql(
+            "select count(*) from login where lower(username) like lower(?) escape '!' \
+            or lower(email) like lower(?) escape '!'",
+        ))
+        .bind(&pattern)
+        .bind(&pattern)
+        .fetch_one(&self.0)
+        .await?;
+        let rows: Vec<UserRow> = sqlx::query_as(&sql(&format!(
+            "select {USER_COLUMNS} from login where lower(username) like lower(?) escape '!' \
+            or lower(email) like lower(?) escape '!' order by username limit ? offset ?"
+        )))
+        .bind(&pattern)
+        .bind(&pattern)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.0)
+        .await?;
+        Ok((rows.into_iter().map(StoredUser::from).collect(), total))
+    }
+
+    async fn delete(&self, username: &str) -> AppResult<bool> {
+        let result = sqlx::query(&sql("delete from login where username = ?"))
+            .bind(username)
+            .execute(&self.0)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_confirmation(
+        &self,
+        username: &str,
+        code: &str,
+        expires: i64,
+        sent: i64,
+    ) -> AppResult<()> {
+        sqlx::query(&sql(
+            "update login set confirmation_code = ?, confirmation_expires = ?, confirmation_sent = ? \
+            where username = ? and confirmed <> 1",
+        ))
+        .bind(code)
+        .bind(expires)
+        .bind(sent)
+        .bind(username)
+        .execute(&self.0)
+        .await?;
+        Ok(())
+    }
+
+    async fn confirm(&self, username: &str, code: &str) -> AppResult<bool> {
+        let result = sqlx::query(&sql(
+            "update login set confirmed = 1, confirmation_code = '' \
+            where username = ? and confirmation_code = ? and confirmed <> 1",
+        ))
+        .bind(username)
+        .bind(code)
+        .execute(&self.0)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_password(&self, username: &str, password: &str) -> AppResult<()> {
+        sqlx::query(&sql("update login set password = ? where username = ?"))
+            .bind(password)
+            .bind(username)
+            .execute(&self.0)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_roles(&self, username: &str, roles: &[String]) -> AppResult<()> {
+        sqlx::query(&sql("update login set roles = ? where username = ?"))
+            .bind(roles.join(","))
+            .bind(username)
+            .execute(&self.0)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_email(&self, username: &str, email: Option<&str>) -> AppResult<()> {
+        sqlx::query(&sql("update login set email = ? where username = ?"))
+            .bind(email)
+            .bind(username)
+            .execute(&self.0)
+            .await
+            .map_err(conflict("Email already in use"))?;
+        Ok(())
+    }
+
+    async fn set_disabled(&self, username: &str, disabled: bool) -> AppResult<()> {
+        sqlx::query(&sql("update login set disabled = ? where username = ?"))
+            .bind(disabled as i16)
+            .bind(username)
+            .execute(&self.0)
+            .await?;
+        Ok(())
+    }
+
+    async fn record_login(&self, username: &str, success: bool) -> AppResult<()> {
+        if success {
+            sqlx::query(&sql(
+                "update login set failed_logins = 0 where username = ? and failed_logins > 0",
+            ))
+            .bind(username)
+            .execute(&self.0)
+            .await?;
+        } else {
+            sqlx::query(&sql(
+                "update login set failed_logins = failed_logins + 1, last_failed_login = ? \
+                where username = ?",
+            ))
+            .bind(now() as i64)
+            .bind(username)
+            .execute(&self.0)
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn set_pending_email(
+        &self,
+        username: &str,
+        email: &str,
+        code: &str,
+        expires: i64,
+    ) -> AppResult<()> {
+        let result = sqlx::query(&sql(
+            "update login set pending_email = ?, email_code = ?, email_code_expires = ? where username = ?",
+        ))
+        .bind(email)
+        .bind(code)
+        .bind(expires)
+        .bind(username)
+        .execute(&self.0)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(StatusCode::NOT_FOUND.into());
+        }
+        Ok(())
+    }
+
+    async fn apply_pending_email(&self, username: &str, code: &str) -> AppResult<bool> {
+        let result = sqlx::query(&sql(
+            "update login set email = pending_email, pending_email = null, email_code = null, \
+            email_code_expires = null where username = ? and email_code = ? and pending_email is not null",
+        ))
+        .bind(username)
+        .bind(code)
+        .execute(&self.0)
+        .await
+        .map_err(conflict("Email already in use"))?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+/// User store kept in process memory, for tests.
+/// Users are lost on restart and not shared across replicas.
+#[derive(Clone, Default)]
+pub struct MemoryUserStore(Arc<Mutex<HashMap<String, StoredUser>>>);
+
+impl MemoryUserStore {
+    /// Applies the change to the user, returning whether it exists.
+    fn update(&self, username: &str, change: impl FnOnce(&mut StoredUser)) -> bool {
+        match self.0.lock().unwrap().get_mut(username) {
+            Some(user) => {
+                change(user);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryUserStore {
+    async fn insert(&self, user: &StoredUser) -> AppResult<()> {
+        let mut users = self.0.lock().unwrap();
+        let email_taken = user.email.is_some() && users.values().any(|u| u.email == user.email);
+        if users.contains_key(&user.username) || email_taken {
+            return Err(AppError::new(StatusCode::CONFLICT, "User already exists"));
+        }
+        users.insert(user.username.clone(), user.clone());
+        Ok(())
+    }
+
+    async fn find(&self, username: &str) -> AppResult<Option<StoredUser>> {
+        Ok(self.0.lock().unwrap().get(username).cloned())
+    }
+
+    async fn find_by_id(&self, userid: &str) -> AppResult<Option<StoredUser>> {
+        let users = self.0.lock().unwrap();
+        Ok(users.values().find(|u| u.userid == userid).cloned())
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        offset: i64,
+        limit: i64,
+    ) -> AppResult<(Vec<StoredUser>, i64)> {
+        let users = self.0.lock().unwrap();
+        let query = query.to_lowercase();
+        let contains = |value: &str| value.to_lowercase().contains(&query);
+        let mut found = users
+            .values()
+            .filter(|u| contains(&u.username) || u.email.as_deref().is_some_and(contains))
+            .cloned()
+            .collect::<Vec<_>>();
+        found.sort_by(|a, b| a.username.cmp(&b.username));
+        let total = found.len() as i64;
+        let page = found
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .collect();
+        Ok((page, total))
+    }
+
+    async fn delete(&self, username: &str) -> AppResult<bool> {
+        Ok(self.0.lock().unwrap().remove(username).is_some())
+    }
+
+    async fn find_by_email(&self, email: &str) -> AppResult<Option<StoredUser>> {
+        let users = self.0.lock().unwrap();
+        Ok(users
+            .values()
+            .find(|u| u.email.as_deref() == Some(email))
+            .cloned())
+    }
+
+    async fn set_confirmation(
+        &self,
+        username: &str,
+        code: &str,
+        expires: i64,
+        sent: i64,
+    ) -> AppResult<()> {
+        self.update(username, |user| {
+            if !user.confirmed {
+                user.confirmation_code = code.to_string();
+                user.confirmation_expires = Some(expires);
+                user.confirmation_sent = Some(sent);
+            }
+        });
+        Ok(())
+    }
+
+    async fn confirm(&self, username: &str, code: &str) -> AppResult<bool> {
+        let mut confirmed = false;
+        self.update(username, |user| {
+            if !user.confirmed && user.confirmation_code == code {
+                user.confirmed = true;
+                user.confirmation_code = String::new();
+                confirmed = true;
+            }
+        });
+        Ok(confirmed)
+    }
+
+    async fn set_password(&self, username: &str, password: &str) -> AppResult<()> {
+        self.update(username, |user| user.password = password.to_string());
+        Ok(())
+    }
+
+    async fn set_roles(&self, username: &str, roles: &[String]) -> AppResult<()> {
+        self.update(username, |user| user.roles = roles.to_vec());
+        Ok(())
+    }
+
+    async fn set_email(&self, username: &str, email: Option<&str>) -> AppResult<()> {
+        let mut users = self.0.lock().unwrap();
+        let taken = email.is_some()
+            && users
+                .values()
+                .any(|u| u.username != username && u.email.as_deref() == email);
+        if taken {
+            return Err(AppError::new(StatusCode::CONFLICT, "Email already in use"));
+        }
+        if let Some(user) = users.get_mut(username) {
+            user.email = email.map(String::from);
+        }
+        Ok(())
+    }
+
+    async fn set_disabled(&self, username: &str, disabled: bool) -> AppResult<()> {
+        self.update(username, |user| user.disabled = disabled);
+        Ok(())
+    }
+
+    async fn record_login(&self, username: &str, success: bool) -> AppResult<()> {
+        self.update(username, |user| {
+            if success {
+                user.failed_logins = 0;
+            } else {
+                user.failed_logins += 1;
+                user.last_failed_login = Some(now() as i64);
+            }
+        });
+        Ok(())
+    }
+
+    async fn set_pending_email(
+        &self,
+        username: &str,
+        email: &str,
+        code: &str,
+        expires: i64,
+    ) -> AppResult<()> {
+        let found = self.update(username, |user| {
+            user.pending_email = Some(email.to_string());
+            user.email_code = Some(code.to_string());
+            user.email_code_expires = Some(expires);
+        });
+        if !found {
+            return Err(StatusCode::NOT_FOUND.into());
+        }
+        Ok(())
+    }
+
+    async fn apply_pending_email(&self, username: &str, code: &str) -> AppResult<bool> {
+        let mut users = self.0.lock().unwrap();
+        let Some(pending) = users
+            .get(username)
+            .filter(|u| u.email_code.as_deref() == Some(code))
+            .and_then(|u| u.pending_email.clone())
+        else {
+            return Ok(false);
+        };
+        if users
+            .values()
+            .any(|u| u.username != username && u.email.as_deref() == Some(pending.as_str()))
+        {
+            return Err(AppError::new(StatusCode::CONFLICT, "Email already in use"));
+        }
+        if let Some(user) = users.get_mut(username) {
+            user.email = Some(pending);
+            user.pending_email = None;
+            user.email_code = None;
+            user.email_code_expires = None;
+        }
+        Ok(true)
+    }
+}
+
+/// Escapes the wildcards of a like pattern, with '!' as escape character.
+fn escape_like(value: &str) -> String {
+    value
+        .replace('!', "!!")
+        .replace('%', "!%")
+        .replace('_', "!_")
+}
@@ -0,0 +1,218 @@
+use crate::prelude::AppResult;
+use askama::Template;
+
+pub use crate::mail::{ConfirmationMail, EmailChangeMail, EmailChangedMail, InvitationMail};
+
+/// Renders the pages and mails of the login flows.
+/// Every method defaults to the built-in template, so an app only overrides what it needs.
+///
+/// ```rust
+/// use velvet_web::prelude::*;
+///
+/// struct MyTemplates;
+///
+/// impl LoginTemplates for MyTemplates {
+///     fn login(&self, page: &LoginPage) -> AppResult<String> {
+///         Ok(format!(
+///             r#"<form method="post" action="{}">{}...</form>"#,
+///             page.action,
+///             page.error.clone().unwrap_or_default()
+///         ))
+///     }
+/// }
+///
+/// let config = LoginConfig::new().templates(MyTemplates);
+/// ```
+pub trait LoginTemplates: Send + Sync {
+    fn login(&self, page: &LoginPage) -> AppResult<String> {
+        render(page)
+    }
+
+    fn register(&self, page: &RegisterPage) -> AppResult<String> {
+        render(page)
+    }
+
+    fn confirm(&self, page: &ConfirmPage) -> AppResult<String> {
+        render(page)
+    }
+
+    fn email(&self, page: &EmailPage) -> AppResult<String> {
+        render(page)
+    }
+
+    fn email_confirm(&self, page: &EmailConfirmPage) -> AppResult<String> {
+        render(page)
+    }
+
+    #[cfg(feature = "webauthn")]
+    fn webauthn_login(&self, page: &WebauthnLoginPage) -> AppResult<String> {
+        render(page)
+    }
+
+    #[cfg(feature = "webauthn")]
+    fn webauthn_register(&self, page: &WebauthnRegisterPage) -> AppResult<String> {
+        render(page)
+    }
+
+    fn impersonation_banner(&self, banner: &ImpersonationBanner) -> AppResult<String> {
+        render(banner)
+    }
+
+    fn confirmation_mail_text(&self, mail: &ConfirmationMail) -> AppResult<String> {
+        mail.text()
+    }
+
+    fn confirmation_mail_html(&self, mail: &ConfirmationMail) -> AppResult<String> {
+        mail.html()
+    }
+
+    fn email_change_mail_text(&self, mail: &EmailChangeMail) -> AppResult<String> {
+        mail.text()
+    }
+
+    fn email_change_mail_html(&self, mail: &EmailChangeMail) -> AppResult<String> {
+        mail.html()
+    }
+
+    fn email_changed_mail_text(&self, mail: &EmailChangedMail) -> AppResult<String> {
+        mail.text()
+    }
+
+    fn email_changed_mail_html(&self, mail: &EmailChangedMail) -> AppResult<String> {
+        mail.html()
+    }
+
+    fn invitation_mail_text(&self, mail: &InvitationMail) -> AppResult<String> {
+        mail.text()
+    }
+
+    fn invitation_mail_html(&self, mail: &InvitationMail) -> AppResult<String> {
+        mail.html()
+    }
+}
+
+/// The templates shipped with the crate.
+pub struct DefaultTemplates;
+
+impl LoginTemplates for DefaultTemplates {}
+
+pub(crate) fn render<T: Template>(template: &T) -> AppResult<String> {
+    Ok(template.render().map_err(anyhow::Error::from)?)
+}
+
+/// Context of the login page.
+#[derive(Template)]
+#[template(path = "login.html")]
+pub struct LoginPage {
+    /// Where the form is posted.
+    pub action: String,
+    /// Label of the username field, depending on LoginConfig::login_with.
+    pub identifier: String,
+    /// Link to the registration page, if registration is open.
+    pub register: Option<String>,
+    /// Link to the passkey login page, if enabled.
+    pub webauthn: Option<String>,
+    /// Value of the hidden field csrf_token.
+    pub csrf: String,
+    /// Username of the failed attempt.
+    pub username: String,
+    pub error: Option<String>,
+}
+
+/// Context of the registration page.
+#[derive(Template)]
+#[template(path = "register.html")]
+pub struct RegisterPage {
+    /// Where the form is posted.
+    pub action: String,
+    /// Value of the hidden field csrf_token.
+    pub csrf: String,
+    /// Username of the failed attempt.
+    pub username: String,
+    /// Email of the failed attempt, or of the invitation.
+    pub email: String,
+    /// Token of the invitation the user registers with, sent as the hidden field invitation.
+    pub invitation: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Context of the mail confirmation page.
+#[derive(Template)]
+#[template(path = "confirm.html")]
+pub struct ConfirmPage {
+    /// Where the form is posted.
+    pub action: String,
+    /// Where the form is posted to send a new code.
+    pub resend: String,
+    /// Value of the hidden field csrf_token.
+    pub csrf: String,
+    pub username: String,
+    pub error: Option<String>,
+}
+
+/// Context of the email change page.
+#[derive(Template)]
+#[template(path = "email.html")]
+pub struct EmailPage {
+    /// Where the form is posted.
+    pub action: String,
+    /// Value of the hidden field csrf_token.
+    pub csrf: String,
+    /// Set after the verification mail was sent.
+    pub message: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Context of the page confirming the new email, opened from the link of the verification mail.
+#[derive(Template)]
+#[template(path = "email_confirm.html")]
+pub struct EmailConfirmPage {
+    /// Where the form is posted.
+    pub action: String,
+    /// Value of the hidden field csrf_token.
+    pub csrf: String,
+    /// Sent as the hidden fields username and code.
+    pub username: String,
+    pub code: String,
+    /// The new email.
+    pub email: String,
+    pub error: Option<String>,
+}
+
+/// Context of the banner shown while impersonating a user, rendered by `Impersonation::banner`.
+#[derive(Template)]
+#[template(path = "impersonation_banner.html")]
+pub struct ImpersonationBanner {
+    /// The impersonated user.
+    pub username: String,
+    /// The user impersonating.
+    pub actor: String,
+    /// Where the form ending the impersonation is posted.
+    pub end: String,
+    /// Value of the hidden field csrf_token.
+    pub csrf: String,
+}
+
+/// Context of the passkey login page.
+#[cfg(feature = "webauthn")]
+#[derive(Template)]
+#[template(path = "webauthn_login.html")]
+pub struct WebauthnLoginPage {
+    /// Prefix of the /webauthn routes.
+    pub prefix: String,
+    /// Link to the password login page.
+    pub password_login: String,
+    /// Value of the X-CSRF-Token header.
+    pub csrf: String,
+}
+
+/// Context of the passkey registration page.
+#[cfg(feature = "webauthn")]
+#[derive(Template)]
+#[template(path = "webauthn_register.html")]
+pub struct WebauthnRegisterPage {
+    /// Prefix of the /webauthn routes.
+    pub prefix: String,
+    /// Value of the X-CSRF-Token header.
+    pub csrf: String,
+}
@@ -0,0 +1,581 @@
+//! Passwordless login with WebAuthn credentials (passkeys).
+//!
+//! Only the `ES256` algorithm is supported and attestation statements are not verified,
+//! which is equivalent to requesting `attestation: "none"`.
+//!
+//! The ceremony state (challenge and user) is carried by a short lived signed token instead of
+//! being stored server side, and is handed to the browser as a cookie by the default flow.
+//! Finishing a ceremony records the id of its state until it expires, so that each state (and
+//! the assertion signed over its challenge) is only accepted once.
+//!
+//! Login options are returned for unknown users and users without passkeys as well, with decoy
+//! credentials, so that they don't reveal which usernames exist.
+//!
+//! Relying party setup via .env:
+//!  - WEBAUTHN_RP_ID=localhost
+//!  - WEBAUTHN_RP_NAME=velvet
+//!  - WEBAUTHN_ORIGIN=http://localhost:8080
+
+use super::{
+    now,
+    store::{IntoUserStore, UserStore},
+    Claims, DB,
+};
+use crate::{
+    auth::{
+        jwt::{jwt_verifier, token_from_claims, JwtVerifier},
+        CookieToken,
+    },
+    db::{conflict, sql},
+    errors::AppError,
+    prelude::AppResult,
+};
+use anyhow::{anyhow, bail, ensure};
+use axum::{http::StatusCode, response::Redirect};
+use axum_extra::extract::CookieJar;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::Value;
+use ring::{
+    digest::{digest, SHA256},
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+    signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1},
+};
+use sentry::types::random_uuid;
+use serde::{Deserialize, Serialize};
+use std::{env, sync::LazyLock};
+use tracing::warn;
+
+const COSE_ALG_ES256: i64 = -7;
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+const CEREMONY_TIMEOUT_SECS: u64 = 300;
+
+/// Key deriving the decoy credentials of a username, the same for the lifetime of the process.
+static DECOY_KEY: LazyLock<hmac::Key> = LazyLock::new(|| {
+    hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+        .expect("Could not generate WebAuthn decoy key")
+});
+
+/// Options to pass to `navigator.credentials.create({publicKey})`.
+/// Binary fields are base64url encoded and need to be decoded on the browser side.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnCreationOptions {
+    challenge: String,
+    rp: RelyingParty,
+    user: UserEntity,
+    pub_key_cred_params: Vec<CredentialParameters>,
+    timeout: u64,
+    attestation: &'static str,
+    exclude_credentials: Vec<CredentialDescriptor>,
+}
+
+/// Options to pass to `navigator.credentials.get({publicKey})`.
+/// Binary fields are base64url encoded and need to be decoded on the browser side.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnRequestOptions {
+    challenge: String,
+    rp_id: String,
+    timeout: u64,
+    allow_credentials: Vec<CredentialDescriptor>,
+    user_verification: &'static str,
+}
+
+#[derive(Serialize)]
+struct RelyingParty {
+    id: String,
+    name: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UserEntity {
+    id: String,
+    name: String,
+    display_name: String,
+}
+
+#[derive(Serialize)]
+struct CredentialParameters {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    alg: i64,
+}
+
+#[derive(Serialize)]
+struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    id: String,
+}
+
+/// Response of `navigator.credentials.create()`, with binary fields base64url encoded.
+#[derive(Deserialize)]
+pub struct WebauthnRegistration {
+    pub id: String,
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+/// Response of `navigator.credentials.get()`, with binary fields base64url encoded.
+#[derive(Deserialize)]
+pub struct WebauthnAssertion {
+    pub id: String,
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CeremonyState {
+    exp: u64,
+    /// Unique id of the state, recorded once the ceremony is finished.
+    jti: String,
+    ceremony: String,
+    userid: String,
+    challenge: String,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+struct RelyingPartyConfig {
+    id: String,
+    name: String,
+    origin: String,
+}
+
+fn relying_party() -> RelyingPartyConfig {
+    let id = env::var("WEBAUTHN_RP_ID").unwrap_or("localhost".into());
+    RelyingPartyConfig {
+        name: env::var("WEBAUTHN_RP_NAME").unwrap_or(id.clone()),
+        origin: env::var("WEBAUTHN_ORIGIN").unwrap_or("http://localhost:8080".into()),
+        id,
+    }
+}
+
+/// Starts the registration of a new credential for an existing (and confirmed) user.
+/// Returns the ceremony state to be passed back to webauthn_register_finish, and the options
+/// for the browser.
+pub async fn webauthn_register_start(
+    db: &DB,
+    username: &str,
+) -> AppResult<(String, WebauthnCreationOptions)> {
+    register_start(db, &*db.user_store(), &jwt_verifier()?, username).await
+}
+
+/// Same as webauthn_register_start, for a user of the store and signing the ceremony state with
+/// the verifier.
+pub(super) async fn register_start(
+    db: &DB,
+    users: &dyn UserStore,
+    verifier: &JwtVerifier,
+    username: &str,
+) -> AppResult<(String, WebauthnCreationOptions)> {
+    let userid = users
+        .find(username)
+        .await?
+        .filter(|u| u.confirmed)
+        .ok_or(StatusCode::NOT_FOUND)?
+        .userid;
+    let exclude_credentials = credentials_of(db, &userid).await?;
+    let rp = relying_party();
+    let (state, challenge) = ceremony_state(verifier, "webauthn.create", &userid)?;
+    let options = WebauthnCreationOptions {
+        challenge,
+        rp: RelyingParty {
+            id: rp.id,
+            name: rp.name,
+        },
+        user: UserEntity {
+            id: URL_SAFE_NO_PAD.encode(&userid),
+            name: username.to_string(),
+            display_name: username.to_string(),
+        },
+        pub_key_cred_params: vec![CredentialParameters {
+            kind: "public-key",
+            alg: COSE_ALG_ES256,
+        }],
+        timeout: CEREMONY_TIMEOUT_SECS * 1000,
+        attestation: "none",
+        exclude_credentials,
+    };
+    Ok((state, options))
+}
+
+/// Verifies and stores the credential created by the browser.
+pub async fn webauthn_register_finish(
+    db: &DB,
+    state: &str,
+    registration: &WebauthnRegistration,
+) -> AppResult<()> {
+    register_finish(db, &jwt_verifier()?, state, registration).await
+}
+
+/// Same as webauthn_register_finish, with the verifier that signed the ceremony state.
+pub(super) async fn register_finish(
+    db: &DB,
+    verifier: &JwtVerifier,
+    state: &str,
+    registration: &WebauthnRegistration,
+) -> AppResult<()> {
+    let failed = |e: anyhow::Error| {
+        warn!("WebAuthn registration failed: {:?}", e);
+        StatusCode::UNAUTHORIZED
+    };
+    let state = verifier
+        .own_claims::<CeremonyState>(state)
+        .map_err(failed)?;
+    let credential = verify_registration(&state, registration).map_err(failed)?;
+    finish_ceremony(db, &state).await?;
+    sqlx::query(&sql("insert into login_webauthn
+        (credential_id, userid, public_key, sign_count)
+        values(?, ?, ?, ?)"))
+    .bind(credential.credential_id)
+    .bind(credential.userid)
+    .bind(credential.public_key)
+    .bind(credential.sign_count as i64)
+    .execute(db)
+    .await
+    .map_err(conflict("Passkey already registered"))?;
+    Ok(())
+}
+
+/// Starts an authentication for the given user.
+/// Returns the ceremony state to be passed back to webauthn_login_finish, and the options
+/// for the browser.
+pub async fn webauthn_login_start(
+    db: &DB,
+    username: &str,
+) -> AppResult<(String, WebauthnRequestOptions)> {
+    login_start(db, &*db.user_store(), &jwt_verifier()?, username).await
+}
+
+/// Same as webauthn_login_start, for a user of the store and signing the ceremony state with the
+/// verifier.
+pub(super) async fn login_start(
+    db: &DB,
+    users: &dyn UserStore,
+    verifier: &JwtVerifier,
+    username: &str,
+) -> AppResult<(String, WebauthnRequestOptions)> {
+    let user = users.find(username).await?.filter(|u| u.confirmed);
+    let (userid, allow_credentials) = match user {
+        Some(user) => {
+            let credentials = credentials_of(db, &user.userid).await?;
+            (user.userid, credentials)
+        }
+        None => (random_uuid().to_string(), vec![]),
+    };
+    let allow_credentials = if allow_credentials.is_empty() {
+        decoy_credentials(username)
+    } else {
+        allow_credentials
+    };
+    let (state, challenge) = ceremony_state(verifier, "webauthn.get", &userid)?;
+    let options = WebauthnRequestOptions {
+        challenge,
+        rp_id: relying_party().id,
+        timeout: CEREMONY_TIMEOUT_SECS * 1000,
+        allow_credentials,
+        user_verification: "required",
+    };
+    Ok((state, options))
+}
+
+/// Verifies the assertion produced by the browser and returns a token for the user.
+pub async fn webauthn_login_token(
+    db: &DB,
+    state: &str,
+    assertion: &WebauthnAssertion,
+) -> AppResult<String> {
+    let claims =
+        webauthn_login_claims(db, &*db.user_store(), &jwt_verifier()?, state, assertion).await?;
+    token_from_claims(&claims).map_err(|e| {
+        warn!("WebAuthn login failed: {}", e);
+        StatusCode::UNAUTHORIZED.into()
+    })
+}
+
+/// Verifies the assertion produced by the browser and sets the login cookie.
+pub async fn webauthn_login_cookie(
+    jar: CookieJar,
+    redirect: &str,
+    db: &DB,
+    state: &str,
+    assertion: &WebauthnAssertion,
+) -> AppResult<(CookieJar, Redirect)> {
+    let claims =
+        webauthn_login_claims(db, &*db.user_store(), &jwt_verifier()?, state, assertion).await?;
+    let jar = CookieToken::set_from_claims(jar, claims).map_err(|e| {
+        warn!("WebAuthn login failed: {}", e);
+        StatusCode::UNAUTHORIZED
+    })?;
+    Ok((jar, Redirect::to(redirect)))
+}
+
+pub(super) async fn webauthn_login_claims(
+    db: &DB,
+    users: &dyn UserStore,
+    verifier: &JwtVerifier,
+    state: &str,
+    assertion: &WebauthnAssertion,
+) -> AppResult<Claims> {
+    let row: Option<(String, String, i64)> = sqlx::query_as(&sql(
+        "select userid, public_key, sign_count from login_webauthn where credential_id = ?",
+    ))
+    .bind(&assertion.id)
+    .fetch_optional(db)
+    .await?;
+    let Some((userid, public_key, stored_count)) = row else {
+        warn!("WebAuthn login failed: unknown credential");
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+    let failed = |e: anyhow::Error| {
+        warn!("WebAuthn login failed: {:?}", e);
+        StatusCode::UNAUTHORIZED
+    };
+    let state = verifier
+        .own_claims::<CeremonyState>(state)
+        .map_err(failed)?;
+    let sign_count = verify_assertion(&state, &userid, &public_key, stored_count as u32, assertion)
+        .map_err(failed)?;
+    finish_ceremony(db, &state).await?;
+    sqlx::query(&sql(
+        "update login_webauthn set sign_count = ? where credential_id = ?",
+    ))
+    .bind(sign_count as i64)
+    .bind(&assertion.id)
+    .execute(db)
+    .await?;
+    let user = users
+        .find_by_id(&userid)
+        .await?
+        .filter(|u| u.confirmed && !u.disabled)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    Ok(Claims::new(&userid, &user.username, user.roles))
+}
+
+async fn credentials_of(db: &DB, userid: &str) -> AppResult<Vec<CredentialDescriptor>> {
+    let rows: Vec<(String,)> = sqlx::query_as(&sql(
+        "select credential_id from login_webauthn where userid = ?",
+    ))
+    .bind(userid)
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(id,)| CredentialDescriptor {
+            kind: "public-key",
+            id,
+        })
+        .collect())
+}
+
+/// The same made up credentials for every login of a username without passkeys.
+fn decoy_credentials(username: &str) -> Vec<CredentialDescriptor> {
+    let id = hmac::sign(&DECOY_KEY, username.as_bytes());
+    vec![CredentialDescriptor {
+        kind: "public-key",
+        id: URL_SAFE_NO_PAD.encode(id.as_ref()),
+    }]
+}
+
+/// Records the state of a finished ceremony, failing with UNAUTHORIZED when it was already used.
+/// States expired since are cleaned up.
+async fn finish_ceremony(db: &DB, state: &CeremonyState) -> AppResult<()> {
+    sqlx::query(&sql(
+        "delete from login_webauthn_ceremony where expires < ?",
+    ))
+    .bind(now() as i64)
+    .execute(db)
+    .await?;
+    sqlx::query(&sql(
+        "insert into login_webauthn_ceremony (jti, expires) values(?, ?)",
+    ))
+    .bind(&state.jti)
+    .bind(state.exp as i64)
+    .execute(db)
+    .await
+    .map_err(|e| match &e {
+        sqlx::Error::Database(db) if db.is_unique_violation() => {
+            warn!("WebAuthn ceremony replayed");
+            AppError::new(StatusCode::UNAUTHORIZED, "WebAuthn ceremony already used")
+        }
+        _ => e.into(),
+    })?;
+    Ok(())
+}
+
+fn ceremony_state(
+    verifier: &JwtVerifier,
+    ceremony: &str,
+    userid: &str,
+) -> AppResult<(String, String)> {
+    let mut challenge = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut challenge)
+        .map_err(|_| "Could not generate WebAuthn challenge")?;
+    let challenge = URL_SAFE_NO_PAD.encode(challenge);
+    let state = verifier.sign(&CeremonyState {
+        exp: now() + CEREMONY_TIMEOUT_SECS,
+        jti: random_uuid().to_string(),
+        ceremony: ceremony.to_string(),
+        userid: userid.to_string(),
+        challenge: challenge.clone(),
+    })?;
+    Ok((state, challenge))
+}
+
+struct Credential {
+    credential_id: String,
+    userid: String,
+    public_key: String,
+    sign_count: u32,
+}
+
+fn verify_registration(
+    state: &CeremonyState,
+    registration: &WebauthnRegistration,
+) -> anyhow::Result<Credential> {
+    let rp = relying_party();
+    verify_client_data(
+        &registration.client_data_json,
+        state,
+        "webauthn.create",
+        &rp,
+    )?;
+
+    let attestation = URL_SAFE_NO_PAD.decode(&registration.attestation_object)?;
+    let attestation: Value = ciborium::from_reader(attestation.as_slice())?;
+    let auth_data = map_get(&attestation, |k| k.as_text() == Some("authData"))
+        .and_then(Value::as_bytes)
+        .ok_or(anyhow!("missing authData"))?;
+    let sign_count = verify_authenticator_data(auth_data, &rp)?;
+    ensure!(
+        auth_data[32] & FLAG_ATTESTED_CREDENTIAL != 0,
+        "no attested credential data"
+    );
+    let rest = auth_data
+        .get(37 + 16..)
+        .ok_or(anyhow!("authData too short"))?;
+    ensure!(rest.len() >= 2, "authData too short");
+    let id_len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+    let credential_id = rest
+        .get(2..2 + id_len)
+        .ok_or(anyhow!("authData too short"))?;
+    let credential_id = URL_SAFE_NO_PAD.encode(credential_id);
+    ensure!(credential_id == registration.id, "credential id mismatch");
+    let cose_key: Value = ciborium::from_reader(&rest[2 + id_len..])?;
+    let public_key = public_key_from_cose(&cose_key)?;
+
+    Ok(Credential {
+        credential_id,
+        userid: state.userid.clone(),
+        public_key: URL_SAFE_NO_PAD.encode(public_key),
+        sign_count,
+    })
+}
+
+fn verify_assertion(
+    state: &CeremonyState,
+    userid: &str,
+    public_key: &str,
+    stored_count: u32,
+    assertion: &WebauthnAssertion,
+) -> anyhow::Result<u32> {
+    ensure!(state.userid == userid, "credential belongs to another user");
+    let rp = relying_party();
+    let client_data = verify_client_data(&assertion.client_data_json, state, "webauthn.get", &rp)?;
+    let auth_data = URL_SAFE_NO_PAD.decode(&assertion.authenticator_data)?;
+    let sign_count = verify_authenticator_data(&auth_data, &rp)?;
+    // The passkey is the only factor of the login, so it needs a PIN or biometric as well.
+    ensure!(auth_data[32] & FLAG_USER_VERIFIED != 0, "user not verified");
+    if sign_count != 0 || stored_count != 0 {
+        ensure!(
+            sign_count > stored_count,
+            "signature counter did not increase"
+        );
+    }
+
+    let mut signed = auth_data;
+    signed.extend_from_slice(digest(&SHA256, &client_data).as_ref());
+    let signature = URL_SAFE_NO_PAD.decode(&assertion.signature)?;
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, URL_SAFE_NO_PAD.decode(public_key)?)
+        .verify(&signed, &signature)
+        .map_err(|_| anyhow!("invalid signature"))?;
+    Ok(sign_count)
+}
+
+/// Returns the raw client data after checking it against the ceremony.
+fn verify_client_data(
+    client_data_json: &str,
+    state: &CeremonyState,
+    ceremony: &str,
+    rp: &RelyingPartyConfig,
+) -> anyhow::Result<Vec<u8>> {
+    ensure!(state.ceremony == ceremony, "wrong ceremony state");
+    let raw = URL_SAFE_NO_PAD.decode(client_data_json)?;
+    let client_data: ClientData = serde_json::from_slice(&raw)?;
+    ensure!(client_data.kind == ceremony, "wrong client data type");
+    ensure!(
+        client_data.challenge == state.challenge,
+        "challenge mismatch"
+    );
+    ensure!(client_data.origin == rp.origin, "origin mismatch");
+    Ok(raw)
+}
+
+/// Checks the relying party and user presence, returning the signature counter.
+fn verify_authenticator_data(auth_data: &[u8], rp: &RelyingPartyConfig) -> anyhow::Result<u32> {
+    ensure!(auth_data.len() >= 37, "authData too short");
+    ensure!(
+        auth_data[..32] == *digest(&SHA256, rp.id.as_bytes()).as_ref(),
+        "relying party id mismatch"
+    );
+    ensure!(auth_data[32] & FLAG_USER_PRESENT != 0, "user not present");
+    Ok(u32::from_be_bytes([
+        auth_data[33],
+        auth_data[34],
+        auth_data[35],
+        auth_data[36],
+    ]))
+}
+
+/// Converts an EC2 P-256 COSE key into an uncompressed SEC1 point.
+fn public_key_from_cose(key: &Value) -> anyhow::Result<Vec<u8>> {
+    let int = |label: i64| map_get(key, |k| k.as_integer() == Some(label.into()));
+    let kty = int(1).and_then(Value::as_integer).map(i128::from);
+    let alg = int(3).and_then(Value::as_integer).map(i128::from);
+    let crv = int(-1).and_then(Value::as_integer).map(i128::from);
+    if (kty, alg, crv) != (Some(2), Some(COSE_ALG_ES256 as i128), Some(1)) {
+        bail!("only ES256 credentials are supported");
+    }
+    let x = int(-2)
+        .and_then(Value::as_bytes)
+        .ok_or(anyhow!("missing x"))?;
+    let y = int(-3)
+        .and_then(Value::as_bytes)
+        .ok_or(anyhow!("missing y"))?;
+    ensure!(x.len() == 32 && y.len() == 32, "invalid coordinates");
+    let mut point = vec![0x04];
+    point.extend_from_slice(x);
+    point.extend_from_slice(y);
+    Ok(point)
+}
+
+fn map_get(map: &Value, f: impl Fn(&Value) -> bool) -> Option<&Value> {
+    map.as_map()?.iter().find(|(k, _)| f(k)).map(|(_, v)| v)
+}
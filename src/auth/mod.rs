@@ -1,6 +1,8 @@
+pub mod csrf;
 pub mod jwt;
 #[cfg(feature = "login")]
 pub mod login;
+pub mod session;
 
 use axum::{
     async_trait,
@@ -12,12 +14,15 @@ use axum::{
     response::{Redirect, Response},
     Router,
 };
-use axum_extra::extract::{cookie::Cookie, CookieJar};
-use jwt::claims_for;
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
+use jwt::{jwt_verifier_for, jwt_verifier_named, VerifierName};
 use reqwest::header::AUTHORIZATION;
 use serde::Serialize;
 use serde::{de::DeserializeOwned, Deserialize};
-use std::error::Error;
+use std::{error::Error, marker::PhantomData};
 
 pub struct CookieToken(pub String);
 pub struct BearerToken(pub String);
@@ -25,6 +30,11 @@ pub struct BearerToken(pub String);
 pub struct CookieClaims<T>(pub T);
 pub struct BearerClaims<T>(pub T);
 
+/// Same as CookieClaims, verifying with the verifier of the name N instead of the default one.
+pub struct NamedCookieClaims<N: VerifierName, T>(pub T, pub PhantomData<N>);
+/// Same as BearerClaims, verifying with the verifier of the name N instead of the default one.
+pub struct NamedBearerClaims<N: VerifierName, T>(pub T, pub PhantomData<N>);
+
 pub enum AuthResult {
     OK,
     Unauthorized,
@@ -78,26 +88,81 @@ pub trait AuthorizedCookieWithRole {
     fn authorized_cookie_role(self, redirect_to_login: &'static str, role: &'static str) -> Self;
 }
 
+/// Name and attributes of the cookie holding the token.
+/// When different from the default, inject it in the application so that the cookie
+/// extractors and authorization layers will look for it.
+#[derive(Debug, Clone)]
+pub struct TokenCookie {
+    pub name: String,
+    pub path: String,
+    pub domain: Option<String>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: SameSite,
+}
+
+impl Default for TokenCookie {
+    fn default() -> Self {
+        Self {
+            name: "token".into(),
+            path: "/".into(),
+            domain: None,
+            secure: true,
+            http_only: true,
+            same_site: SameSite::Lax,
+        }
+    }
+}
+
+impl TokenCookie {
+    fn build(&self, value: String) -> Cookie<'static> {
+        let mut c = Cookie::build((self.name.clone(), value))
+            .path(self.path.clone())
+            .secure(self.secure)
+            .http_only(self.http_only)
+            .same_site(self.same_site);
+        if let Some(domain) = &self.domain {
+            c = c.domain(domain.clone());
+        }
+        c.build()
+    }
+}
+
 impl CookieToken {
     pub fn set_from_claims<T: Serialize>(
         jar: CookieJar,
         claims: T,
+    ) -> Result<CookieJar, Box<dyn Error>> {
+        Self::set_from_claims_with(jar, claims, &TokenCookie::default())
+    }
+
+    pub fn set_from_claims_with<T: Serialize>(
+        jar: CookieJar,
+        claims: T,
+        cookie: &TokenCookie,
     ) -> Result<CookieJar, Box<dyn Error>> {
         let token = jwt::token_from_claims(&claims)?;
-        Ok(CookieToken::set(jar, token))
+        Ok(CookieToken::set_with(jar, token, cookie))
     }
 
     pub fn set(jar: CookieJar, token: String) -> CookieJar {
-        let c = Cookie::build(("token", token))
-            .secure(true)
-            .http_only(true)
-            .same_site(axum_extra::extract::cookie::SameSite::Lax)
-            .build();
-        jar.add(c)
+        Self::set_with(jar, token, &TokenCookie::default())
+    }
+
+    pub fn set_with(jar: CookieJar, token: String, cookie: &TokenCookie) -> CookieJar {
+        jar.add(cookie.build(token))
     }
 
     pub fn remove(jar: CookieJar) -> CookieJar {
-        jar.remove(Cookie::build("token"))
+        Self::remove_with(jar, &TokenCookie::default())
+    }
+
+    pub fn remove_with(jar: CookieJar, cookie: &TokenCookie) -> CookieJar {
+        let mut c = Cookie::build(cookie.name.clone()).path(cookie.path.clone());
+        if let Some(domain) = &cookie.domain {
+            c = c.domain(domain.clone());
+        }
+        jar.remove(c)
     }
 }
 
@@ -113,7 +178,12 @@ where
             Ok(jar) => jar,
             Err(err) => match err {},
         };
-        let value = jar.get("token").ok_or(response_unauthorized())?.value();
+        let name = parts
+            .extensions
+            .get::<TokenCookie>()
+            .map(|c| c.name.as_str())
+            .unwrap_or("token");
+        let value = jar.get(name).ok_or(response_unauthorized())?.value();
         let value = value.to_string().trim().to_string();
         Ok(Self(value))
     }
@@ -129,7 +199,9 @@ where
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         let token = CookieToken::from_request_parts(parts, state).await?;
-        let claims = claims_for::<T>(token.0.as_str()).map_err(|_| response_unauthorized())?;
+        let claims = jwt_verifier_for(&parts.extensions)
+            .and_then(|verifier| verifier.claims::<T>(token.0.as_str()))
+            .map_err(|_| response_unauthorized())?;
         Ok(CookieClaims::<T>(claims))
     }
 }
@@ -165,12 +237,50 @@ where
     type Rejection = Response;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let token = CookieToken::from_request_parts(parts, state).await?;
-        let claims = claims_for::<T>(token.0.as_str()).map_err(|_| response_unauthorized())?;
+        let token = BearerToken::from_request_parts(parts, state).await?;
+        let claims = jwt_verifier_for(&parts.extensions)
+            .and_then(|verifier| verifier.claims::<T>(token.0.as_str()))
+            .map_err(|_| response_unauthorized())?;
         Ok(BearerClaims::<T>(claims))
     }
 }
 
+#[async_trait]
+impl<S, N, T> FromRequestParts<S> for NamedCookieClaims<N, T>
+where
+    N: VerifierName,
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let token = CookieToken::from_request_parts(parts, state).await?;
+        let claims = jwt_verifier_named(&parts.extensions, N::NAME)
+            .and_then(|verifier| verifier.claims::<T>(token.0.as_str()))
+            .map_err(|_| response_unauthorized())?;
+        Ok(NamedCookieClaims(claims, PhantomData))
+    }
+}
+
+#[async_trait]
+impl<S, N, T> FromRequestParts<S> for NamedBearerClaims<N, T>
+where
+    N: VerifierName,
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let token = BearerToken::from_request_parts(parts, state).await?;
+        let claims = jwt_verifier_named(&parts.extensions, N::NAME)
+            .and_then(|verifier| verifier.claims::<T>(token.0.as_str()))
+            .map_err(|_| response_unauthorized())?;
+        Ok(NamedBearerClaims(claims, PhantomData))
+    }
+}
+
 async fn authorize_from_bearer<F>(request: Request, next: Next, f: F) -> Response
 where
     F: Fn(&str) -> anyhow::Result<AuthResult>,
@@ -248,8 +358,15 @@ where
     FT: Send + Sync + Clone + Fn(T) -> anyhow::Result<AuthResult> + 'static,
 {
     fn authorized_bearer_claims(self, f: FT) -> Self {
-        let f2 = move |token: &str| f(jwt::claims_for::<T>(token)?);
-        let wrapper = move |r, n| authorize_from_bearer(r, n, f2.clone());
+        let wrapper = move |r: Request, n| {
+            let f = f.clone();
+            let verifier = jwt_verifier_for(r.extensions());
+            let f2 = move |token: &str| {
+                let verifier = verifier.as_ref().map_err(|e| anyhow::anyhow!("{e}"))?;
+                f(verifier.claims::<T>(token)?)
+            };
+            authorize_from_bearer(r, n, f2)
+        };
         self.layer(middleware::from_fn(wrapper))
     }
 }
@@ -286,8 +403,15 @@ where
     FT: Send + Sync + Clone + Fn(T) -> anyhow::Result<AuthResult> + 'static,
 {
     fn authorized_cookie_claims(self, redirect_to_login: &'static str, f: FT) -> Self {
-        let f2 = move |token: &str| f(jwt::claims_for::<T>(token)?);
-        let wrapper = move |r, n| authorize_from_cookie(r, n, redirect_to_login, f2.clone());
+        let wrapper = move |r: Request, n| {
+            let f = f.clone();
+            let verifier = jwt_verifier_for(r.extensions());
+            let f2 = move |token: &str| {
+                let verifier = verifier.as_ref().map_err(|e| anyhow::anyhow!("{e}"))?;
+                f(verifier.claims::<T>(token)?)
+            };
+            authorize_from_cookie(r, n, redirect_to_login, f2)
+        };
         self.layer(middleware::from_fn(wrapper))
     }
 }
@@ -0,0 +1,438 @@
+//! Server side sessions, as an alternative to keeping the whole JWT in a cookie.
+//!
+//! The browser only receives an opaque session id, signed with SESSION_SECRET (or JWT_SECRET
+//! when not set), while the session data is kept in a SessionStore.
+
+use super::AuthResult;
+use crate::errors::AppResult;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Redirect, Response},
+    Router,
+};
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const CLAIMS_KEY: &str = "claims";
+const TOUCH_INTERVAL_SECS: u64 = 60;
+
+/// A stored session, as persisted by a SessionStore.
+/// Times are in seconds from UNIX epoch.
+#[derive(Debug, Clone, Default)]
+pub struct SessionRecord {
+    pub data: String,
+    pub created: i64,
+    pub accessed: i64,
+}
+
+/// Storage backend for sessions.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn load(&self, id: &str) -> anyhow::Result<Option<SessionRecord>>;
+    async fn save(&self, id: &str, record: &SessionRecord) -> anyhow::Result<()>;
+    async fn remove(&self, id: &str) -> anyhow::Result<()>;
+}
+
+/// Session store kept in process memory.
+/// Sessions are lost on restart and not shared across replicas.
+#[derive(Clone, Default)]
+pub struct MemorySessionStore(Arc<Mutex<HashMap<String, SessionRecord>>>);
+
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn load(&self, id: &str) -> anyhow::Result<Option<SessionRecord>> {
+        Ok(self.0.lock().unwrap().get(id).cloned())
+    }
+
+    async fn save(&self, id: &str, record: &SessionRecord) -> anyhow::Result<()> {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), record.clone());
+        Ok(())
+    }
+
+    async fn remove(&self, id: &str) -> anyhow::Result<()> {
+        self.0.lock().unwrap().remove(id);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "login")]
+pub use sql::SqlSessionStore;
+
+#[cfg(feature = "login")]
+mod sql {
+    use super::{SessionRecord, SessionStore};
+    use crate::{
+        auth::login::login_setup,
+        db::{sql, DB},
+        errors::AppResult,
+    };
+    use axum::async_trait;
+
+    /// Session store kept in the `login_session` table of the database.
+    #[derive(Clone)]
+    pub struct SqlSessionStore(DB);
+
+    impl SqlSessionStore {
+        /// Creates the store, applying the login migrations that create its table.
+        pub async fn new(db: &DB) -> AppResult<Self> {
+            login_setup(db).await?;
+            Ok(Self(db.clone()))
+        }
+    }
+
+    #[async_trait]
+    impl SessionStore for SqlSessionStore {
+        async fn load(&self, id: &str) -> anyhow::Result<Option<SessionRecord>> {
+            let row: Option<(String, i64, i64)> = sqlx::query_as(&sql(
+                "select data, created, accessed from login_session where id = ?",
+            ))
+            .bind(id)
+            .fetch_optional(&self.0)
+            .await?;
+            Ok(row.map(|(data, created, accessed)| SessionRecord {
+                data,
+                created,
+                accessed,
+            }))
+        }
+
+        async fn save(&self, id: &str, record: &SessionRecord) -> anyhow::Result<()> {
+            let updated = sqlx::query(&sql(
+                "update login_session set data = ?, accessed = ? where id = ?",
+            ))
+            .bind(&record.data)
+            .bind(record.accessed)
+            .bind(id)
+            .execute(&self.0)
+            .await?;
+            if updated.rows_affected() == 0 {
+                sqlx::query(&sql(
+                    "insert into login_session (id, data, created, accessed) values(?, ?, ?, ?)",
+                ))
+                .bind(id)
+                .bind(&record.data)
+                .bind(record.created)
+                .bind(record.accessed)
+                .execute(&self.0)
+                .await?;
+            }
+            Ok(())
+        }
+
+        async fn remove(&self, id: &str) -> anyhow::Result<()> {
+            sqlx::query(&sql("delete from login_session where id = ?"))
+                .bind(id)
+                .execute(&self.0)
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+/// Session setup, to be injected in the application with App::sessions.
+///
+/// Defaults to a cookie named "session", 30 minutes of idle timeout and 24 hours of absolute
+/// timeout.
+/// Required for setup .env:
+///  - SESSION_SECRET=<secret> (or JWT_SECRET)
+#[derive(Clone)]
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    key: hmac::Key,
+    cookie_name: &'static str,
+    idle_timeout: Duration,
+    absolute_timeout: Duration,
+}
+
+impl Sessions {
+    pub fn new(store: impl SessionStore + 'static) -> Self {
+        dotenvy::dotenv().ok();
+        let secret = env::var("SESSION_SECRET")
+            .or(env::var("JWT_SECRET"))
+            .expect("env var SESSION_SECRET or JWT_SECRET required to setup sessions");
+        Self {
+            store: Arc::new(store),
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+            cookie_name: "session",
+            idle_timeout: Duration::from_secs(30 * 60),
+            absolute_timeout: Duration::from_secs(24 * 3600),
+        }
+    }
+
+    pub fn cookie_name(self, cookie_name: &'static str) -> Self {
+        Self {
+            cookie_name,
+            ..self
+        }
+    }
+
+    /// Sessions not used for longer than this are discarded.
+    pub fn idle_timeout(self, idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout,
+            ..self
+        }
+    }
+
+    /// Sessions older than this are discarded, even if in use.
+    pub fn absolute_timeout(self, absolute_timeout: Duration) -> Self {
+        Self {
+            absolute_timeout,
+            ..self
+        }
+    }
+
+    /// Loads the session referenced by the cookie, or starts a new empty one when missing,
+    /// invalid or expired.
+    pub async fn load(&self, jar: &CookieJar) -> AppResult<Session> {
+        let mut session = Session {
+            sessions: self.clone(),
+            id: None,
+            replaced: None,
+            data: HashMap::new(),
+            created: now(),
+        };
+        let Some(id) = jar
+            .get(self.cookie_name)
+            .and_then(|c| self.verified_id(c.value()))
+        else {
+            return Ok(session);
+        };
+        let Some(record) = self.store.load(&id).await? else {
+            return Ok(session);
+        };
+        let time = now();
+        if time - record.accessed > self.idle_timeout.as_secs() as i64
+            || time - record.created > self.absolute_timeout.as_secs() as i64
+        {
+            tracing::debug!("Session expired");
+            self.store.remove(&id).await?;
+            return Ok(session);
+        }
+        if time - record.accessed > TOUCH_INTERVAL_SECS as i64 {
+            let record = SessionRecord {
+                accessed: time,
+                ..record.clone()
+            };
+            self.store.save(&id, &record).await?;
+        }
+        session.data = serde_json::from_str(&record.data).unwrap_or_default();
+        session.created = record.created;
+        session.id = Some(id);
+        Ok(session)
+    }
+
+    fn sign(&self, id: &str) -> String {
+        let tag = hmac::sign(&self.key, id.as_bytes());
+        format!("{}.{}", id, URL_SAFE_NO_PAD.encode(tag.as_ref()))
+    }
+
+    fn verified_id(&self, value: &str) -> Option<String> {
+        let (id, tag) = value.split_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        hmac::verify(&self.key, id.as_bytes(), &tag).ok()?;
+        Some(id.to_string())
+    }
+}
+
+/// A server side session.
+/// Changes are persisted only when calling save.
+pub struct Session {
+    sessions: Sessions,
+    id: Option<String>,
+    replaced: Option<String>,
+    data: HashMap<String, Value>,
+    created: i64,
+}
+
+impl Session {
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.data
+            .get(key)
+            .and_then(|v| serde_json::from_value(v.clone()).ok())
+    }
+
+    pub fn insert<T: Serialize>(&mut self, key: &str, value: T) -> AppResult<()> {
+        let value = serde_json::to_value(value).map_err(anyhow::Error::from)?;
+        self.data.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.data.remove(key);
+    }
+
+    /// The claims of the logged in user, as set by login_session.
+    pub fn claims<T: DeserializeOwned>(&self) -> Option<T> {
+        self.get(CLAIMS_KEY)
+    }
+
+    pub fn set_claims<T: Serialize>(&mut self, claims: T) -> AppResult<()> {
+        self.insert(CLAIMS_KEY, claims)
+    }
+
+    /// Assigns a new id to the session on the next save, keeping its data.
+    /// Use on privilege changes such as login, to prevent session fixation.
+    pub fn rotate(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.replaced = Some(id);
+        }
+        self.created = now();
+    }
+
+    /// Persists the session and sets its cookie.
+    pub async fn save(mut self, jar: CookieJar) -> AppResult<CookieJar> {
+        let store = &self.sessions.store;
+        if let Some(replaced) = self.replaced.take() {
+            store.remove(&replaced).await?;
+        }
+        let id = match self.id.take() {
+            Some(id) => id,
+            None => new_id()?,
+        };
+        let record = SessionRecord {
+            data: serde_json::to_string(&self.data).map_err(anyhow::Error::from)?,
+            created: self.created,
+            accessed: now(),
+        };
+        store.save(&id, &record).await?;
+        let c = Cookie::build((self.sessions.cookie_name, self.sessions.sign(&id)))
+            .path("/")
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .build();
+        Ok(jar.add(c))
+    }
+
+    /// Deletes the session from the store and removes its cookie.
+    pub async fn destroy(self, jar: CookieJar) -> AppResult<CookieJar> {
+        let store = &self.sessions.store;
+        for id in self.id.iter().chain(self.replaced.iter()) {
+            store.remove(id).await?;
+        }
+        Ok(jar.remove(Cookie::build(self.sessions.cookie_name).path("/")))
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Session
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let Some(sessions) = parts.extensions.get::<Sessions>().cloned() else {
+            tracing::error!("Session used without setting up App::sessions");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        };
+        let jar = CookieJar::from_headers(&parts.headers);
+        sessions
+            .load(&jar)
+            .await
+            .map_err(IntoResponse::into_response)
+    }
+}
+
+pub trait AuthorizedSessionWithClaims<T, FT>
+where
+    T: DeserializeOwned,
+    FT: Send + Sync + Clone + Fn(T) -> anyhow::Result<AuthResult> + 'static,
+{
+    fn authorized_session_claims(self, redirect_to_login: &'static str, f: FT) -> Self;
+}
+
+pub trait AuthorizedSessionWithRole {
+    fn authorized_session_role(self, redirect_to_login: &'static str, role: &'static str) -> Self;
+}
+
+async fn authorize_from_session<T, F>(
+    request: Request,
+    next: Next,
+    redirect: &'static str,
+    f: F,
+) -> Response
+where
+    T: DeserializeOwned,
+    F: Fn(T) -> anyhow::Result<AuthResult>,
+{
+    let (mut parts, body) = request.into_parts();
+    let claims = match Session::from_request_parts(&mut parts, &()).await {
+        Ok(session) => session.claims::<T>(),
+        Err(response) => return response,
+    };
+    let Some(claims) = claims else {
+        tracing::debug!("No claims in session");
+        return Redirect::to(redirect).into_response();
+    };
+    let request = Request::from_parts(parts, body);
+    match f(claims) {
+        Ok(AuthResult::OK) => next.run(request).await,
+        Ok(AuthResult::Unauthorized) => Redirect::to(redirect).into_response(),
+        Ok(AuthResult::Redirect(target)) => Redirect::to(target.as_str()).into_response(),
+        Err(e) => {
+            tracing::debug!(?e, "Failed to verify session");
+            Redirect::to(redirect).into_response()
+        }
+    }
+}
+
+impl<T, FT> AuthorizedSessionWithClaims<T, FT> for Router
+where
+    T: DeserializeOwned + Send + 'static,
+    FT: Send + Sync + Clone + Fn(T) -> anyhow::Result<AuthResult> + 'static,
+{
+    fn authorized_session_claims(self, redirect_to_login: &'static str, f: FT) -> Self {
+        let wrapper = move |r, n| authorize_from_session(r, n, redirect_to_login, f.clone());
+        self.layer(middleware::from_fn(wrapper))
+    }
+}
+
+impl AuthorizedSessionWithRole for Router {
+    fn authorized_session_role(self, redirect_to_login: &'static str, role: &'static str) -> Self {
+        #[derive(Deserialize)]
+        struct Claims {
+            roles: Vec<String>,
+        }
+        self.authorized_session_claims(redirect_to_login, move |c: Claims| {
+            Ok(c.roles.contains(&role.to_string()).into())
+        })
+    }
+}
+
+fn new_id() -> AppResult<String> {
+    let mut id = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| "Could not generate session id")?;
+    Ok(URL_SAFE_NO_PAD.encode(id))
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
@@ -1,5 +1,176 @@
+use reqwest::{Method, RequestBuilder};
+use serde::Deserialize;
+use std::{
+    env,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
 /// Create a new http client to inject into the application.
 /// Use with the extractor Extension<Client>
 pub fn client() -> reqwest::Client {
     reqwest::Client::default()
 }
+
+/// Tokens are fetched again this long before they expire.
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
+/// Credentials of an OAuth2 client, exchanged for tokens at the token url.
+#[derive(Debug, Clone)]
+pub struct ClientCredentials {
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+    scopes: Vec<String>,
+}
+
+impl ClientCredentials {
+    pub fn new(token_url: &str, client_id: &str, client_secret: &str) -> Self {
+        Self {
+            token_url: token_url.to_string(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            scopes: vec![],
+        }
+    }
+
+    /// Credentials of the environment variables CLIENT_TOKEN_URL, CLIENT_ID, CLIENT_SECRET
+    /// and optionally CLIENT_SCOPES, separated by spaces.
+    pub fn from_env() -> anyhow::Result<Self> {
+        dotenvy::dotenv().ok();
+        let credentials = Self::new(
+            &env::var("CLIENT_TOKEN_URL")?,
+            &env::var("CLIENT_ID")?,
+            &env::var("CLIENT_SECRET")?,
+        );
+        let scopes = env::var("CLIENT_SCOPES").unwrap_or_default();
+        Ok(credentials.scopes(&scopes.split_whitespace().collect::<Vec<_>>()))
+    }
+
+    /// Scopes requested for the tokens. Default all the scopes of the client.
+    pub fn scopes(self, scopes: &[&str]) -> Self {
+        Self {
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+            ..self
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// Http client sending a client credentials token as bearer, fetched when missing or about
+/// to expire and shared by its clones.
+#[derive(Clone)]
+pub struct CredentialsClient {
+    client: reqwest::Client,
+    credentials: Arc<ClientCredentials>,
+    token: Arc<Mutex<Option<(String, Instant)>>>,
+}
+
+impl CredentialsClient {
+    /// The current token, fetching a new one when needed.
+    pub async fn token(&self) -> anyhow::Result<String> {
+        let mut token = self.token.lock().await;
+        if let Some((value, expires)) = token.as_ref() {
+            if Instant::now() + TOKEN_REFRESH_MARGIN < *expires {
+                return Ok(value.clone());
+            }
+        }
+        let credentials = &self.credentials;
+        let scope = credentials.scopes.join(" ");
+        let mut form = vec![("grant_type", "client_credentials")];
+        if !scope.is_empty() {
+            form.push(("scope", &scope));
+        }
+        let response = self
+            .client
+            .post(&credentials.token_url)
+            .basic_auth(
+                form_encode(&credentials.client_id),
+                Some(form_encode(&credentials.client_secret)),
+            )
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<TokenResponse>()
+            .await?;
+        let expires = Instant::now() + Duration::from_secs(response.expires_in);
+        *token = Some((response.access_token.clone(), expires));
+        Ok(response.access_token)
+    }
+
+    /// A request carrying the token.
+    pub async fn request(&self, method: Method, url: &str) -> anyhow::Result<RequestBuilder> {
+        Ok(self
+            .client
+            .request(method, url)
+            .bearer_auth(self.token().await?))
+    }
+
+    pub async fn get(&self, url: &str) -> anyhow::Result<RequestBuilder> {
+        self.request(Method::GET, url).await
+    }
+
+    pub async fn post(&self, url: &str) -> anyhow::Result<RequestBuilder> {
+        self.request(Method::POST, url).await
+    }
+
+    pub async fn put(&self, url: &str) -> anyhow::Result<RequestBuilder> {
+        self.request(Method::PUT, url).await
+    }
+
+    pub async fn patch(&self, url: &str) -> anyhow::Result<RequestBuilder> {
+        self.request(Method::PATCH, url).await
+    }
+
+    pub async fn delete(&self, url: &str) -> anyhow::Result<RequestBuilder> {
+        self.request(Method::DELETE, url).await
+    }
+}
+
+/// Authenticates the requests of a client with client credentials tokens.
+///
+/// ```rust,no_run
+/// use velvet_web::prelude::*;
+///
+/// # async fn f() -> anyhow::Result<()> {
+/// let orders = client().with_client_credentials(
+///     ClientCredentials::new("https://auth.example.com/api/auth/token", "billing", "secret")
+///         .scopes(&["orders:read"]),
+/// );
+/// let response = orders.get("https://orders.example.com/orders").await?.send().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait WithClientCredentials {
+    fn with_client_credentials(self, credentials: ClientCredentials) -> CredentialsClient;
+}
+
+impl WithClientCredentials for reqwest::Client {
+    fn with_client_credentials(self, credentials: ClientCredentials) -> CredentialsClient {
+        CredentialsClient {
+            client: self,
+            credentials: Arc::new(credentials),
+            token: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+/// Encodes a Basic credential as form-urlencoded, as the token endpoints decode it (RFC 6749 2.3.1).
+fn form_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'*' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
@@ -1,60 +1,759 @@
+use crate::errors::{AppError, AppResult};
+use std::time::{Duration, Instant};
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::{
+    marker::PhantomData,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+#[cfg(feature = "postgres")]
+/// Database of the enabled features, used by the login and session tables and by default for
+/// the named databases.
+/// When more than one database feature is enabled, postgres takes precedence over mysql,
+/// and mysql over sqlite.
+pub type Backend = sqlx::Postgres;
+
+#[cfg(all(feature = "mysql", not(feature = "postgres")))]
+pub type Backend = sqlx::MySql;
+
+#[cfg(all(feature = "sqlite", not(feature = "postgres"), not(feature = "mysql")))]
+pub type Backend = sqlx::Sqlite;
+
+#[cfg(feature = "postgres")]
+/// Create a new pool for the database of the enabled features (see `Backend`), configured by
+/// the environment. The login flows take this pool.
+pub async fn database() -> AppResult<sqlx::Pool<Backend>> {
+    postgres().await
+}
+
+#[cfg(all(feature = "mysql", not(feature = "postgres")))]
+pub async fn database() -> AppResult<sqlx::Pool<Backend>> {
+    mysql().await
+}
+
+#[cfg(all(feature = "sqlite", not(feature = "postgres"), not(feature = "mysql")))]
+pub async fn database() -> AppResult<sqlx::Pool<Backend>> {
+    sqlite().await
+}
+
+#[cfg(feature = "login")]
+pub(crate) type DB = sqlx::Pool<Backend>;
+
+#[cfg(feature = "login")]
+/// Maps the violation of a unique constraint to CONFLICT with the message, for the inserts of an
+/// already taken key. Other errors stay INTERNAL_SERVER_ERROR.
+pub(crate) fn conflict(message: &str) -> impl FnOnce(sqlx::Error) -> AppError + '_ {
+    move |e| match &e {
+        sqlx::Error::Database(db) if db.is_unique_violation() => {
+            AppError::new(StatusCode::CONFLICT, message)
+        }
+        _ => e.into(),
+    }
+}
+
+#[cfg(feature = "login")]
+/// Adapts a query written with `?` placeholders to the dialect of DB.
+/// Postgres expects numbered placeholders (`$1`, `$2`, ...) instead.
+/// A `?` inside a quoted string literal is kept as is.
+pub(crate) fn sql(query: &str) -> String {
+    if cfg!(feature = "postgres") {
+        let mut n = 0;
+        let mut quoted = false;
+        let mut result = String::with_capacity(query.len() + 8);
+        for c in query.chars() {
+            if c == '\'' {
+                quoted = !quoted;
+            }
+            if c == '?' && !quoted {
+                n += 1;
+                result.push_str(&format!("${n}"));
+            } else {
+                result.push(c);
+            }
+        }
+        result
+    } else {
+        query.to_string()
+    }
+}
+
+/// Settings of the database pool, read by `postgres()`, `mysql()` and `sqlite()` from:
+///  - DATABASE_URL
+///  - DATABASE_MAX_CONNECTIONS (default 1) and DATABASE_MIN_CONNECTIONS (default 0)
+///  - DATABASE_ACQUIRE_TIMEOUT (default 30), DATABASE_IDLE_TIMEOUT (default 600) and
+///    DATABASE_MAX_LIFETIME (default 1800) in seconds, 0 to keep the connections forever
+///  - DATABASE_TEST_BEFORE_ACQUIRE (default true)
+///  - DATABASE_STATEMENT_CACHE_SIZE (default 100)
+///  - DATABASE_CONNECT_RETRIES (default 5) attempts after the first failed connection,
+///    waiting from half a second and doubling up to 30 seconds in between
+///  - DATABASE_SQLITE_WAL (default false), DATABASE_SQLITE_BUSY_TIMEOUT (default 5 seconds)
+///    and DATABASE_SQLITE_FOREIGN_KEYS (default true)
+///  - DATABASE_APPLICATION_NAME and DATABASE_SEARCH_PATH for postgres
+#[derive(Clone)]
+pub struct DatabaseConfig {
+    url: String,
+    max_connections: u32,
+    min_connections: u32,
+    acquire_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    test_before_acquire: bool,
+    statement_cache_size: usize,
+    connect_retries: u32,
+    retry_backoff: Duration,
+    #[cfg(feature = "sqlite")]
+    sqlite_wal: bool,
+    #[cfg(feature = "sqlite")]
+    sqlite_busy_timeout: Duration,
+    #[cfg(feature = "sqlite")]
+    sqlite_foreign_keys: bool,
+    #[cfg(feature = "postgres")]
+    application_name: Option<String>,
+    #[cfg(feature = "postgres")]
+    search_path: Option<String>,
+}
+
+impl std::fmt::Debug for DatabaseConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut debug = f.debug_struct("DatabaseConfig");
+        debug
+            .field("url", &redacted(&self.url))
+            .field("max_connections", &self.max_connections)
+            .field("min_connections", &self.min_connections)
+            .field("acquire_timeout", &self.acquire_timeout)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_lifetime", &self.max_lifetime)
+            .field("test_before_acquire", &self.test_before_acquire)
+            .field("statement_cache_size", &self.statement_cache_size)
+            .field("connect_retries", &self.connect_retries)
+            .field("retry_backoff", &self.retry_backoff);
+        #[cfg(feature = "sqlite")]
+        debug
+            .field("sqlite_wal", &self.sqlite_wal)
+            .field("sqlite_busy_timeout", &self.sqlite_busy_timeout)
+            .field("sqlite_foreign_keys", &self.sqlite_foreign_keys);
+        #[cfg(feature = "postgres")]
+        debug
+            .field("application_name", &self.application_name)
+            .field("search_path", &self.search_path);
+        debug.finish()
+    }
+}
+
+/// The url with the password of its user info replaced, to be logged.
+fn redacted(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let Some(at) = rest[..authority_end].rfind('@') else {
+        return url.to_string();
+    };
+    match rest[..at].split_once(':') {
+        Some((user, _)) => format!("{scheme}://{user}:***{}", &rest[at..]),
+        None => url.to_string(),
+    }
+}
+
+impl DatabaseConfig {
+    /// The defaults for the database url.
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            max_connections: 1,
+            min_connections: 0,
+            acquire_timeout: Duration::from_secs(30),
+            idle_timeout: Some(Duration::from_secs(600)),
+            max_lifetime: Some(Duration::from_secs(1800)),
+            test_before_acquire: true,
+            statement_cache_size: 100,
+            connect_retries: 5,
+            retry_backoff: Duration::from_millis(500),
+            #[cfg(feature = "sqlite")]
+            sqlite_wal: false,
+            #[cfg(feature = "sqlite")]
+            sqlite_busy_timeout: Duration::from_secs(5),
+            #[cfg(feature = "sqlite")]
+            sqlite_foreign_keys: true,
+            #[cfg(feature = "postgres")]
+            application_name: None,
+            #[cfg(feature = "postgres")]
+            search_path: None,
+        }
+    }
+
+    /// The settings of the environment variables, DATABASE_URL being required.
+    pub fn from_env() -> AppResult<Self> {
+        Self::from_env_named("")
+    }
+
+    /// The settings of a named database, from the variables suffixed by the name, like
+    /// DATABASE_URL_REPLICA for "REPLICA". Settings without the suffix apply to all databases.
+    pub fn from_env_named(name: &str) -> AppResult<Self> {
+        dotenvy::dotenv().ok();
+        let suffix = if name.is_empty() {
+            String::new()
+        } else {
+            format!("_{}", name.to_uppercase())
+        };
+        let url_var = format!("DATABASE_URL{suffix}");
+        let url = std::env::var(&url_var)
+            .map_err(|_| AppError::from(format!("Missing {url_var}").as_str()))?;
+        let defaults = Self::new(&url);
+        let seconds = |name: &str, default: Option<Duration>| {
+            Ok::<_, AppError>(match var::<u64>(name, &suffix)? {
+                Some(0) => None,
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => default,
+            })
+        };
+        Ok(Self {
+            max_connections: var("DATABASE_MAX_CONNECTIONS", &suffix)?
+                .unwrap_or(defaults.max_connections),
+            min_connections: var("DATABASE_MIN_CONNECTIONS", &suffix)?
+                .unwrap_or(defaults.min_connections),
+            acquire_timeout: var("DATABASE_ACQUIRE_TIMEOUT", &suffix)?
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.acquire_timeout),
+            idle_timeout: seconds("DATABASE_IDLE_TIMEOUT", defaults.idle_timeout)?,
+            max_lifetime: seconds("DATABASE_MAX_LIFETIME", defaults.max_lifetime)?,
+            test_before_acquire: var("DATABASE_TEST_BEFORE_ACQUIRE", &suffix)?
+                .unwrap_or(defaults.test_before_acquire),
+            statement_cache_size: var("DATABASE_STATEMENT_CACHE_SIZE", &suffix)?
+                .unwrap_or(defaults.statement_cache_size),
+            connect_retries: var("DATABASE_CONNECT_RETRIES", &suffix)?
+                .unwrap_or(defaults.connect_retries),
+            #[cfg(feature = "sqlite")]
+            sqlite_wal: var("DATABASE_SQLITE_WAL", &suffix)?.unwrap_or(defaults.sqlite_wal),
+            #[cfg(feature = "sqlite")]
+            sqlite_busy_timeout: var("DATABASE_SQLITE_BUSY_TIMEOUT", &suffix)?
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.sqlite_busy_timeout),
+            #[cfg(feature = "sqlite")]
+            sqlite_foreign_keys: var("DATABASE_SQLITE_FOREIGN_KEYS", &suffix)?
+                .unwrap_or(defaults.sqlite_foreign_keys),
+            #[cfg(feature = "postgres")]
+            application_name: var("DATABASE_APPLICATION_NAME", &suffix)?,
+            #[cfg(feature = "postgres")]
+            search_path: var("DATABASE_SEARCH_PATH", &suffix)?,
+            ..defaults
+        })
+    }
+
+    pub fn max_connections(self, max_connections: u32) -> Self {
+        Self {
+            max_connections,
+            ..self
+        }
+    }
+
+    /// Connections kept open even when idle.
+    pub fn min_connections(self, min_connections: u32) -> Self {
+        Self {
+            min_connections,
+            ..self
+        }
+    }
+
+    /// Maximum wait for a connection of the pool.
+    pub fn acquire_timeout(self, acquire_timeout: Duration) -> Self {
+        Self {
+            acquire_timeout,
+            ..self
+        }
+    }
+
+    /// Idle connections above min_connections are closed after this time, never when None.
+    pub fn idle_timeout(self, idle_timeout: Option<Duration>) -> Self {
+        Self {
+            idle_timeout,
+            ..self
+        }
+    }
+
+    /// Connections are closed after this time, never when None.
+    pub fn max_lifetime(self, max_lifetime: Option<Duration>) -> Self {
+        Self {
+            max_lifetime,
+            ..self
+        }
+    }
+
+    /// Pings the connections before handing them out.
+    pub fn test_before_acquire(self, test_before_acquire: bool) -> Self {
+        Self {
+            test_before_acquire,
+            ..self
+        }
+    }
+
+    /// Prepared statements cached by each connection.
+    pub fn statement_cache_size(self, statement_cache_size: usize) -> Self {
+        Self {
+            statement_cache_size,
+            ..self
+        }
+    }
+
+    /// Attempts after a failed connection at startup, waiting `backoff` before the first and
+    /// doubling it up to 30 seconds.
+    pub fn connect_retries(self, connect_retries: u32, backoff: Duration) -> Self {
+        Self {
+            connect_retries,
+            retry_backoff: backoff,
+            ..self
+        }
+    }
+
+    /// Write-ahead journal on sqlite, letting readers work during writes.
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite_wal(self, sqlite_wal: bool) -> Self {
+        Self { sqlite_wal, ..self }
+    }
+
+    /// Wait of sqlite for a locked database.
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite_busy_timeout(self, sqlite_busy_timeout: Duration) -> Self {
+        Self {
+            sqlite_busy_timeout,
+            ..self
+        }
+    }
+
+    /// Enforcement of the foreign keys on sqlite.
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite_foreign_keys(self, sqlite_foreign_keys: bool) -> Self {
+        Self {
+            sqlite_foreign_keys,
+            ..self
+        }
+    }
+
+    /// Name of the connections shown by postgres.
+    #[cfg(feature = "postgres")]
+    pub fn application_name(self, application_name: &str) -> Self {
+        Self {
+            application_name: Some(application_name.to_string()),
+            ..self
+        }
+    }
+
+    /// Schema search path of the postgres connections.
+    #[cfg(feature = "postgres")]
+    pub fn search_path(self, search_path: &str) -> Self {
+        Self {
+            search_path: Some(search_path.to_string()),
+            ..self
+        }
+    }
+
+    fn pool_options<D: sqlx::Database>(&self) -> sqlx::pool::PoolOptions<D> {
+        sqlx::pool::PoolOptions::new()
+            .max_connections(self.max_connections)
+            .min_connections(self.min_connections)
+            .acquire_timeout(self.acquire_timeout)
+            .idle_timeout(self.idle_timeout)
+            .max_lifetime(self.max_lifetime)
+            .test_before_acquire(self.test_before_acquire)
+    }
+
+    /// Connects, retrying with backoff while the database is not reachable.
+    async fn connect<D: sqlx::Database>(
+        &self,
+        options: <D::Connection as sqlx::Connection>::Options,
+    ) -> AppResult<sqlx::Pool<D>> {
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            match self.pool_options::<D>().connect_with(options.clone()).await {
+                Ok(pool) => return Ok(pool),
+                Err(e) if attempt < self.connect_retries => {
+                    attempt += 1;
+                    tracing::warn!(
+                        ?e,
+                        attempt,
+                        ?backoff,
+                        "Database connection failed, retrying"
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The variable suffixed for a named database, or else the one shared by all the databases.
+fn var<T: std::str::FromStr>(name: &str, suffix: &str) -> AppResult<Option<T>> {
+    let value = std::env::var(format!("{name}{suffix}")).or_else(|_| std::env::var(name));
+    match value {
+        Ok(value) => value.trim().parse().map(Some).map_err(|_| {
+            AppError::new(
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("Invalid {name}: {value}"),
+            )
+        }),
+        Err(_) => Ok(None),
+    }
+}
+
 #[cfg(feature = "postgres")]
-/// Create a new pool for postgres
+/// Create a new pool for postgres, configured by the environment (see `DatabaseConfig`).
 /// Example URL for .env:
 ///   - DATABASE_URL=postgres://user:pw@localhost/db
-pub async fn postgres() -> sqlx::PgPool {
+pub async fn postgres() -> AppResult<sqlx::PgPool> {
     // May not know if app is constructed before databse, so trigger dotenvs in both situations
     dotenvy::dotenv().ok();
     crate::app::logger();
-    let database_url =
-        std::env::var("DATABASE_URL").expect("DATABASE_URL environment variable not set");
-    let max_connections = std::env::var("DATABASE_MAX_CONNECTIONS")
-        .ok()
-        .and_then(|s| s.parse::<u32>().ok())
-        .unwrap_or(1);
-    sqlx::postgres::PgPoolOptions::new()
-        .max_connections(max_connections)
-        .connect(&database_url)
-        .await
-        .unwrap()
+    postgres_with(DatabaseConfig::from_env()?).await
+}
+
+#[cfg(feature = "postgres")]
+/// Create a new pool for postgres with the given settings.
+pub async fn postgres_with(config: DatabaseConfig) -> AppResult<sqlx::PgPool> {
+    config.connect(postgres_options(&config)?).await
+}
+
+#[cfg(feature = "postgres")]
+fn postgres_options(config: &DatabaseConfig) -> AppResult<sqlx::postgres::PgConnectOptions> {
+    use std::str::FromStr;
+    let mut options = sqlx::postgres::PgConnectOptions::from_str(&config.url)?
+        .statement_cache_capacity(config.statement_cache_size);
+    if let Some(name) = &config.application_name {
+        options = options.application_name(name);
+    }
+    if let Some(path) = &config.search_path {
+        options = options.options([("search_path", path)]);
+    }
+    Ok(options)
 }
 
 #[cfg(feature = "sqlite")]
-/// Create a new pool for sqlite
+/// Create a new pool for sqlite, configured by the environment (see `DatabaseConfig`).
 /// Example URL for .env:
 ///   - DATABASE_URL=sqlite:test.db?mode=rwc
-pub async fn sqlite() -> sqlx::SqlitePool {
+pub async fn sqlite() -> AppResult<sqlx::SqlitePool> {
     dotenvy::dotenv().ok();
     crate::app::logger();
-    let database_url =
-        std::env::var("DATABASE_URL").expect("DATABASE_URL environment variable not set");
-    let max_connections = std::env::var("DATABASE_MAX_CONNECTIONS")
-        .ok()
-        .and_then(|s| s.parse::<u32>().ok())
-        .unwrap_or(1);
-    sqlx::sqlite::SqlitePoolOptions::new()
-        .max_connections(max_connections)
-        .connect(&database_url)
-        .await
-        .unwrap()
+    sqlite_with(DatabaseConfig::from_env()?).await
+}
+
+#[cfg(feature = "sqlite")]
+/// Create a new pool for sqlite with the given settings.
+pub async fn sqlite_with(config: DatabaseConfig) -> AppResult<sqlx::SqlitePool> {
+    config.connect(sqlite_options(&config)?).await
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_options(config: &DatabaseConfig) -> AppResult<sqlx::sqlite::SqliteConnectOptions> {
+    use std::str::FromStr;
+    let mut options = sqlx::sqlite::SqliteConnectOptions::from_str(&config.url)?
+        .statement_cache_capacity(config.statement_cache_size)
+        .busy_timeout(config.sqlite_busy_timeout)
+        .foreign_keys(config.sqlite_foreign_keys);
+    if config.sqlite_wal {
+        options = options.journal_mode(sqlx::sqlite::SqliteJournalMode::Wal);
+    }
+    Ok(options)
 }
 
 #[cfg(feature = "mysql")]
-/// Create a new pool for mysql
+/// Create a new pool for mysql, configured by the environment (see `DatabaseConfig`).
 /// Example URL for .env:
 ///   - DATABASE_URL=mysql://user:pw@localhost/db
-pub async fn mysql() -> sqlx::MySqlPool {
+pub async fn mysql() -> AppResult<sqlx::MySqlPool> {
     dotenvy::dotenv().ok();
     crate::app::logger();
-    let database_url =
-        std::env::var("DATABASE_URL").expect("DATABASE_URL environment variable not set");
-    let max_connections = std::env::var("DATABASE_MAX_CONNECTIONS")
-        .ok()
-        .and_then(|s| s.parse::<u32>().ok())
-        .unwrap_or(1);
-    sqlx::mysql::MySqlPoolOptions::new()
-        .max_connections(max_connections)
-        .connect(&database_url)
-        .await
-        .unwrap()
+    mysql_with(DatabaseConfig::from_env()?).await
+}
+
+#[cfg(feature = "mysql")]
+/// Create a new pool for mysql with the given settings.
+pub async fn mysql_with(config: DatabaseConfig) -> AppResult<sqlx::MySqlPool> {
+    config.connect(mysql_options(&config)?).await
+}
+
+#[cfg(feature = "mysql")]
+fn mysql_options(config: &DatabaseConfig) -> AppResult<sqlx::mysql::MySqlConnectOptions> {
+    use std::str::FromStr;
+    Ok(sqlx::mysql::MySqlConnectOptions::from_str(&config.url)?
+        .statement_cache_capacity(config.statement_cache_size))
+}
+
+#[cfg(all(feature = "mysql", not(feature = "postgres")))]
+use mysql_options as backend_options;
+#[cfg(feature = "postgres")]
+use postgres_options as backend_options;
+#[cfg(all(feature = "sqlite", not(feature = "postgres"), not(feature = "mysql")))]
+use sqlite_options as backend_options;
+
+/// Name of a database, selecting its environment variables (see `DatabaseConfig::from_env_named`).
+/// Other databases are declared with a type of their own:
+/// ```rust
+/// use velvet_web::prelude::*;
+///
+/// struct Analytics;
+/// impl DbName for Analytics {
+///     // DATABASE_URL_ANALYTICS
+///     const NAME: &'static str = "ANALYTICS";
+/// }
+/// ```
+pub trait DbName: Send + Sync + 'static {
+    const NAME: &'static str;
+}
+
+/// The main database, of DATABASE_URL.
+pub struct Primary;
+
+impl DbName for Primary {
+    const NAME: &'static str = "";
+}
+
+/// A read replica of the main database, of DATABASE_URL_REPLICA.
+pub struct Replica;
+
+impl DbName for Replica {
+    const NAME: &'static str = "REPLICA";
+}
+
+/// A pool told apart by its name, so that several pools of the same database can be injected
+/// in the App and extracted by the handlers, like `Db<Primary>` and `Db<Replica>`.
+/// Derefs to the pool.
+pub struct Db<N: DbName, D: sqlx::Database = Backend> {
+    pool: sqlx::Pool<D>,
+    name: PhantomData<N>,
+}
+
+impl<N: DbName, D: sqlx::Database> Db<N, D> {
+    pub fn new(pool: sqlx::Pool<D>) -> Self {
+        Self {
+            pool,
+            name: PhantomData,
+        }
+    }
+
+    pub fn pool(&self) -> &sqlx::Pool<D> {
+        &self.pool
+    }
+
+    pub fn into_pool(self) -> sqlx::Pool<D> {
+        self.pool
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl<N: DbName> Db<N, sqlx::Postgres> {
+    /// Connects to the postgres database of the name, configured by the environment.
+    pub async fn connect() -> AppResult<Self> {
+        crate::app::logger();
+        let config = DatabaseConfig::from_env_named(N::NAME)?;
+        Ok(Self::new(postgres_with(config).await?))
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl<N: DbName> Db<N, sqlx::MySql> {
+    /// Connects to the mysql database of the name, configured by the environment.
+    pub async fn connect() -> AppResult<Self> {
+        crate::app::logger();
+        let config = DatabaseConfig::from_env_named(N::NAME)?;
+        Ok(Self::new(mysql_with(config).await?))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl<N: DbName> Db<N, sqlx::Sqlite> {
+    /// Connects to the sqlite database of the name, configured by the environment.
+    pub async fn connect() -> AppResult<Self> {
+        crate::app::logger();
+        let config = DatabaseConfig::from_env_named(N::NAME)?;
+        Ok(Self::new(sqlite_with(config).await?))
+    }
+}
+
+impl<N: DbName, D: sqlx::Database> Clone for Db<N, D> {
+    fn clone(&self) -> Self {
+        Self::new(self.pool.clone())
+    }
+}
+
+impl<N: DbName, D: sqlx::Database> Deref for Db<N, D> {
+    type Target = sqlx::Pool<D>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.pool
+    }
+}
+
+#[async_trait]
+impl<S, N, D> FromRequestParts<S> for Db<N, D>
+where
+    S: Send + Sync,
+    N: DbName,
+    D: sqlx::Database,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<Self>().cloned().ok_or_else(|| {
+            tracing::error!(
+                "Db<{}> used without being injected",
+                std::any::type_name::<N>()
+            );
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })
+    }
+}
+
+/// Sends the writes to the primary and the reads to the replicas in turn, falling back to the
+/// primary when no replica gives a connection.
+/// A replica not giving one within the replica timeout (default 1 second) is skipped for the
+/// next 30 seconds, so that a replica down doesn't slow every read.
+/// Injected in the App, it is extracted by the handlers as well.
+pub struct DbRouter<D: sqlx::Database = Backend> {
+    primary: sqlx::Pool<D>,
+    replicas: Arc<Vec<ReplicaPool<D>>>,
+    next: Arc<AtomicUsize>,
+    replica_timeout: Duration,
+}
+
+const REPLICA_DOWN_FOR: Duration = Duration::from_secs(30);
+
+/// A replica, with the time until which it is skipped after a failure.
+struct ReplicaPool<D: sqlx::Database> {
+    pool: sqlx::Pool<D>,
+    down_until: Arc<Mutex<Option<Instant>>>,
+}
+
+impl<D: sqlx::Database> ReplicaPool<D> {
+    fn is_down(&self, now: Instant) -> bool {
+        let down_until = self.down_until.lock().unwrap();
+        down_until.is_some_and(|until| now < until)
+    }
+
+    fn set_down(&self, now: Instant) {
+        let mut down_until = self.down_until.lock().unwrap();
+        *down_until = Some(now + REPLICA_DOWN_FOR);
+    }
+}
+
+impl<D: sqlx::Database> Clone for ReplicaPool<D> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            down_until: self.down_until.clone(),
+        }
+    }
+}
+
+impl<D: sqlx::Database> DbRouter<D> {
+    pub fn new(primary: sqlx::Pool<D>) -> Self {
+        Self {
+            primary,
+            replicas: Arc::new(Vec::new()),
+            next: Arc::new(AtomicUsize::new(0)),
+            replica_timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// Adds a replica to read from.
+    pub fn replica(self, replica: sqlx::Pool<D>) -> Self {
+        let mut replicas = self.replicas.to_vec();
+        replicas.push(ReplicaPool {
+            pool: replica,
+            down_until: Arc::new(Mutex::new(None)),
+        });
+        Self {
+            replicas: Arc::new(replicas),
+            ..self
+        }
+    }
+
+    /// Maximum wait for a connection of a replica before reading from the next one.
+    pub fn replica_timeout(self, replica_timeout: Duration) -> Self {
+        Self {
+            replica_timeout,
+            ..self
+        }
+    }
+
+    /// The primary, for the writes and the reads that must see them.
+    pub fn write(&self) -> &sqlx::Pool<D> {
+        &self.primary
+    }
+
+    /// A connection of the next replica available, or else of the primary.
+    pub async fn read(&self) -> AppResult<sqlx::pool::PoolConnection<D>> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        for i in 0..self.replicas.len() {
+            let replica = &self.replicas[(start + i) % self.replicas.len()];
+            let now = Instant::now();
+            if replica.is_down(now) {
+                continue;
+            }
+            match tokio::time::timeout(self.replica_timeout, replica.pool.acquire()).await {
+                Ok(Ok(connection)) => return Ok(connection),
+                Ok(Err(e)) => tracing::warn!(?e, "Replica not available, trying the next"),
+                Err(_) => tracing::warn!("Replica not available in time, trying the next"),
+            }
+            replica.set_down(now);
+        }
+        Ok(self.primary.acquire().await?)
+    }
+}
+
+impl DbRouter {
+    /// Connects to the primary of DATABASE_URL and, when DATABASE_URL_REPLICA is set, to the
+    /// replica. The replica connects on its first read, and again after a failure, which is
+    /// logged as a warning while reading from the primary.
+    pub async fn from_env() -> AppResult<Self> {
+        let router = Self::new(Db::<Primary>::connect().await?.into_pool());
+        dotenvy::dotenv().ok();
+        if std::env::var("DATABASE_URL_REPLICA").is_err() {
+            return Ok(router);
+        }
+        let config = DatabaseConfig::from_env_named(Replica::NAME)?;
+        let options = backend_options(&config)?;
+        Ok(router.replica(config.pool_options().connect_lazy_with(options)))
+    }
+}
+
+impl<D: sqlx::Database> Clone for DbRouter<D> {
+    fn clone(&self) -> Self {
+        Self {
+            primary: self.primary.clone(),
+            replicas: self.replicas.clone(),
+            next: self.next.clone(),
+            replica_timeout: self.replica_timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl<S, D> FromRequestParts<S> for DbRouter<D>
+where
+    S: Send + Sync,
+    D: sqlx::Database,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<Self>().cloned().ok_or_else(|| {
+            tracing::error!("DbRouter used without being injected");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })
+    }
 }
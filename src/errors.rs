@@ -19,6 +19,27 @@ pub struct AppError {
     redirect: Option<Redirect>,
 }
 
+impl AppError {
+    pub fn new(status: StatusCode, message: &str) -> Self {
+        Self {
+            status,
+            error: anyhow::Error::msg(message.to_string()),
+            redirect: None,
+        }
+    }
+
+    /// The http status this error responds with.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
 impl From<&str> for AppError {
     fn from(value: &str) -> Self {
         Self {
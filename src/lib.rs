@@ -5,22 +5,22 @@ mod client;
 #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
 mod db;
 mod errors;
-mod metrics;
 mod mail;
+mod metrics;
 
 #[macro_use]
 pub mod prelude {
     pub use super::app::App;
     #[cfg(feature = "login")]
     pub use super::app::TestLoginAsCookie;
-    pub use super::client::client;
+    pub use super::client::{client, ClientCredentials, CredentialsClient, WithClientCredentials};
     pub use super::errors::AppError;
     pub use super::errors::AppResult;
     pub use super::metrics::metric_counter;
     pub use super::metrics::metric_gauge;
     pub use super::metrics::metric_histogram;
     pub use askama::Template;
-    pub use axum::extract::{Form, Json, Path, Host};
+    pub use axum::extract::{Form, Host, Json, Path};
     pub use axum::http::HeaderMap;
     pub use axum::http::HeaderName;
     pub use axum::http::HeaderValue;
@@ -37,17 +37,21 @@ pub mod prelude {
     pub use valuable::Valuable;
 
     pub use super::mail::mailer;
-    pub use lettre::SmtpTransport as MailTransport;
+    pub use lettre::message::header::ContentType as MailContentType;
     pub use lettre::Message as MailMessage;
+    pub use lettre::SmtpTransport as MailTransport;
     pub use lettre::Transport as MailTransportTrait;
-    pub use lettre::message::header::ContentType as MailContentType;
 
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    pub use super::db::{
+        database, Backend, DatabaseConfig, Db, DbName, DbRouter, Primary, Replica,
+    };
     #[cfg(feature = "mysql")]
-    pub use super::db::mysql;
+    pub use super::db::{mysql, mysql_with};
     #[cfg(feature = "postgres")]
-    pub use super::db::postgres;
+    pub use super::db::{postgres, postgres_with};
     #[cfg(feature = "sqlite")]
-    pub use super::db::sqlite;
+    pub use super::db::{sqlite, sqlite_with};
     #[cfg(feature = "mysql")]
     pub use sqlx::MySql;
     #[cfg(feature = "postgres")]
@@ -57,13 +61,35 @@ pub mod prelude {
     #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
     pub use sqlx::{query, query_as, Pool};
 
+    #[cfg(feature = "auth")]
+    pub use super::auth::csrf::CsrfToken;
     #[cfg(feature = "auth")]
     pub use super::auth::jwt::claims_for;
     #[cfg(feature = "auth")]
+    pub use super::auth::jwt::JwtValidation;
+    #[cfg(feature = "auth")]
     pub use super::auth::jwt::VerifiedClaims;
     #[cfg(feature = "auth")]
     pub use super::auth::jwt::JWT;
     #[cfg(feature = "auth")]
+    pub use super::auth::jwt::{add_jwt_verifier, set_jwt_verifier, JwtVerifier, VerifierName};
+    #[cfg(feature = "auth")]
+    pub use super::auth::session::AuthorizedSessionWithClaims;
+    #[cfg(feature = "auth")]
+    pub use super::auth::session::AuthorizedSessionWithRole;
+    #[cfg(feature = "auth")]
+    pub use super::auth::session::MemorySessionStore;
+    #[cfg(feature = "auth")]
+    pub use super::auth::session::Session;
+    #[cfg(feature = "auth")]
+    pub use super::auth::session::SessionRecord;
+    #[cfg(feature = "auth")]
+    pub use super::auth::session::SessionStore;
+    #[cfg(feature = "auth")]
+    pub use super::auth::session::Sessions;
+    #[cfg(feature = "login")]
+    pub use super::auth::session::SqlSessionStore;
+    #[cfg(feature = "auth")]
     pub use super::auth::AuthResult;
     #[cfg(feature = "auth")]
     pub use super::auth::AuthorizedBearer;
@@ -78,23 +104,113 @@ pub mod prelude {
     #[cfg(feature = "auth")]
     pub use super::auth::AuthorizedCookieWithRole;
     #[cfg(feature = "auth")]
+    pub use super::auth::BearerClaims;
+    #[cfg(feature = "auth")]
     pub use super::auth::BearerToken;
     #[cfg(feature = "auth")]
+    pub use super::auth::CookieClaims;
+    #[cfg(feature = "auth")]
     pub use super::auth::CookieToken;
     #[cfg(feature = "auth")]
-    pub use super::auth::BearerClaims;
+    pub use super::auth::NamedBearerClaims;
     #[cfg(feature = "auth")]
-    pub use super::auth::CookieClaims;
+    pub use super::auth::NamedCookieClaims;
+    #[cfg(feature = "auth")]
+    pub use super::auth::TokenCookie;
     #[cfg(feature = "auth")]
     pub use axum_extra::extract::CookieJar;
     #[cfg(feature = "auth")]
+    pub use jsonwebtoken::Algorithm as JwtAlgorithm;
+    #[cfg(feature = "auth")]
     pub use jsonwebtoken::DecodingKey;
 
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::admin::create_user;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::admin::delete_user;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::admin::get_user;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::admin::reset_user_password;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::admin::search_users;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::admin::set_user_disabled;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::admin::set_user_email;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::admin::set_user_roles;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::admin::unlock_user;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::admin::LoginUser;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::admin::UserPage;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::api_flow::Problem;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::audit::{
+        audit, search_audit, AuditContext, AuditEvent, AuditPage, AuditQuery, AuditRecord,
+    };
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::backend::{
+        set_credential_backend, CredentialBackend, PasswordBackend,
+    };
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::client_credentials::{
+        client_claims_for, client_token, create_client, delete_client, ClientBearerClaims,
+        ClientClaims, ClientScope, Scope,
+    };
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::confirm_email_change;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::default_flow::ClaimsHook;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::default_flow::Impersonation;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::default_flow::LoginConfig;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::find_username;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::introspection::IntrospectionConfig;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::invitation::{
+        create_invitation, find_invitation, list_invitations, register_user_with_invitation,
+        revoke_invitation, send_invitation, Invitation,
+    };
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "ldap")]
+    pub use super::auth::login::ldap::LdapBackend;
     #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
     #[cfg(feature = "login")]
     pub use super::auth::login::login_cookie;
     #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
     #[cfg(feature = "login")]
+    pub use super::auth::login::login_session;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
     pub use super::auth::login::login_setup;
     #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
     #[cfg(feature = "login")]
@@ -104,8 +220,89 @@ pub mod prelude {
     pub use super::auth::login::logout_cookie;
     #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
     #[cfg(feature = "login")]
+    pub use super::auth::login::logout_session;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::password::set_password_policy;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::password::PasswordPolicy;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
     pub use super::auth::login::register_user;
     #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
     #[cfg(feature = "login")]
     pub use super::auth::login::register_user_confirm;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::request_email_change;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::resend_confirmation;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::store::{
+        IntoUserStore, MemoryUserStore, SqlUserStore, StoredUser, UserStore,
+    };
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::templates::ConfirmPage;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::templates::ConfirmationMail;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::templates::DefaultTemplates;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::templates::EmailChangeMail;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::templates::EmailChangedMail;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::templates::EmailConfirmPage;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::templates::EmailPage;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::templates::ImpersonationBanner;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::templates::InvitationMail;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::templates::LoginPage;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::templates::LoginTemplates;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::templates::RegisterPage;
+    #[cfg(feature = "webauthn")]
+    pub use super::auth::login::templates::WebauthnLoginPage;
+    #[cfg(feature = "webauthn")]
+    pub use super::auth::login::templates::WebauthnRegisterPage;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::Claims as LoginClaims;
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+    #[cfg(feature = "login")]
+    pub use super::auth::login::LoginWith;
+
+    #[cfg(feature = "webauthn")]
+    pub use super::auth::login::webauthn::webauthn_login_cookie;
+    #[cfg(feature = "webauthn")]
+    pub use super::auth::login::webauthn::webauthn_login_start;
+    #[cfg(feature = "webauthn")]
+    pub use super::auth::login::webauthn::webauthn_login_token;
+    #[cfg(feature = "webauthn")]
+    pub use super::auth::login::webauthn::webauthn_register_finish;
+    #[cfg(feature = "webauthn")]
+    pub use super::auth::login::webauthn::webauthn_register_start;
+    #[cfg(feature = "webauthn")]
+    pub use super::auth::login::webauthn::WebauthnAssertion;
+    #[cfg(feature = "webauthn")]
+    pub use super::auth::login::webauthn::WebauthnRegistration;
 }
@@ -49,53 +49,180 @@ pub fn mailer() -> SmtpTransport {
 #[cfg(feature = "login")]
 #[derive(askama::Template)]
 #[template(path = "mail_confirm.html")]
-struct HtmlMail {
-    username: String,
-    code: String,
-    link: String,
-    site: String,
+struct HtmlMail<'a> {
+    username: &'a str,
+    code: &'a str,
+    link: &'a str,
+    site: &'a str,
 }
 
 #[cfg(feature = "login")]
 #[derive(askama::Template)]
 #[template(path = "mail_confirm.txt")]
-struct TextMail {
-    username: String,
-    code: String,
-    link: String,
-    site: String,
+struct TextMail<'a> {
+    username: &'a str,
+    code: &'a str,
+    link: &'a str,
+    site: &'a str,
 }
 
 #[cfg(feature = "login")]
-/// Sends a confirmation email for registration.
-/// This is already used internally when the mail flow is chosen.
-pub fn send_confirmation_email(
+/// Context of the registration confirmation mail.
+pub struct ConfirmationMail {
+    /// Base url of the site.
+    pub site: String,
+    /// Link to the confirmation page.
+    pub link: String,
+    pub username: String,
+    pub code: String,
+}
+
+#[cfg(feature = "login")]
+impl ConfirmationMail {
+    pub(crate) fn text(&self) -> crate::prelude::AppResult<String> {
+        crate::auth::login::templates::render(&TextMail {
+            username: &self.username,
+            code: &self.code,
+            link: &self.link,
+            site: &self.site,
+        })
+    }
+
+    pub(crate) fn html(&self) -> crate::prelude::AppResult<String> {
+        crate::auth::login::templates::render(&HtmlMail {
+            username: &self.username,
+            code: &self.code,
+            link: &self.link,
+            site: &self.site,
+        })
+    }
+}
+
+#[cfg(feature = "login")]
+/// Context of the verification mail sent to a new email address.
+pub struct EmailChangeMail {
+    /// Base url of the site.
+    pub site: String,
+    /// Link verifying the new address.
+    pub link: String,
+    pub username: String,
+    /// The new address.
+    pub email: String,
+    pub code: String,
+}
+
+#[cfg(feature = "login")]
+#[derive(askama::Template)]
+#[template(path = "mail_email_change.html")]
+struct EmailChangeHtml<'a> {
+    mail: &'a EmailChangeMail,
+}
+
+#[cfg(feature = "login")]
+#[derive(askama::Template)]
+#[template(path = "mail_email_change.txt")]
+struct EmailChangeText<'a> {
+    mail: &'a EmailChangeMail,
+}
+
+#[cfg(feature = "login")]
+impl EmailChangeMail {
+    pub(crate) fn text(&self) -> crate::prelude::AppResult<String> {
+        crate::auth::login::templates::render(&EmailChangeText { mail: self })
+    }
+
+    pub(crate) fn html(&self) -> crate::prelude::AppResult<String> {
+        crate::auth::login::templates::render(&EmailChangeHtml { mail: self })
+    }
+}
+
+#[cfg(feature = "login")]
+/// Context of the notification sent to the old address after an email change.
+pub struct EmailChangedMail {
+    /// Base url of the site.
+    pub site: String,
+    pub username: String,
+    /// The new address.
+    pub email: String,
+}
+
+#[cfg(feature = "login")]
+#[derive(askama::Template)]
+#[template(path = "mail_email_changed.html")]
+struct EmailChangedHtml<'a> {
+    mail: &'a EmailChangedMail,
+}
+
+#[cfg(feature = "login")]
+#[derive(askama::Template)]
+#[template(path = "mail_email_changed.txt")]
+struct EmailChangedText<'a> {
+    mail: &'a EmailChangedMail,
+}
+
+#[cfg(feature = "login")]
+impl EmailChangedMail {
+    pub(crate) fn text(&self) -> crate::prelude::AppResult<String> {
+        crate::auth::login::templates::render(&EmailChangedText { mail: self })
+    }
+
+    pub(crate) fn html(&self) -> crate::prelude::AppResult<String> {
+        crate::auth::login::templates::render(&EmailChangedHtml { mail: self })
+    }
+}
+
+#[cfg(feature = "login")]
+/// Context of the invitation to register.
+pub struct InvitationMail {
+    /// Base url of the site.
+    pub site: String,
+    /// Link to the registration page, carrying the invitation.
+    pub link: String,
+    /// The invited address.
+    pub email: String,
+    /// Who sent the invitation, if known.
+    pub invited_by: Option<String>,
+}
+
+#[cfg(feature = "login")]
+#[derive(askama::Template)]
+#[template(path = "mail_invitation.html")]
+struct InvitationHtml<'a> {
+    mail: &'a InvitationMail,
+}
+
+#[cfg(feature = "login")]
+#[derive(askama::Template)]
+#[template(path = "mail_invitation.txt")]
+struct InvitationText<'a> {
+    mail: &'a InvitationMail,
+}
+
+#[cfg(feature = "login")]
+impl InvitationMail {
+    pub(crate) fn text(&self) -> crate::prelude::AppResult<String> {
+        crate::auth::login::templates::render(&InvitationText { mail: self })
+    }
+
+    pub(crate) fn html(&self) -> crate::prelude::AppResult<String> {
+        crate::auth::login::templates::render(&InvitationHtml { mail: self })
+    }
+}
+
+#[cfg(feature = "login")]
+/// Sends a mail with a plain text and an html alternative.
+/// This is already used internally for the confirmation mail of the mail flow.
+pub(crate) fn send_multipart(
     mailer: crate::prelude::MailTransport,
-    host: &str,
-    link: &str,
-    username: &str,
     email: &str,
-    code: &str,
+    plain: String,
+    html: String,
 ) -> crate::prelude::AppResult<()> {
     use lettre::{message::MultiPart, Message, Transport};
 
     let Ok(from) = env::var("MAIL_FROM") else {
         return Err("no MAIL_FROM env found".into());
     };
-    let plain: String = TextMail {
-        username: username.to_string(),
-        code: code.to_string(),
-        site: host.to_string(),
-        link: link.to_string(),
-    }
-    .to_string();
-    let html = HtmlMail {
-        username: username.to_string(),
-        code: code.to_string(),
-        site: host.to_string(),
-        link: link.to_string(),
-    }
-    .to_string();
     let message = Message::builder()
         .from(from.parse()?)
         .to(email.parse()?)
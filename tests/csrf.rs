@@ -0,0 +1,62 @@
+#![cfg(feature = "auth")]
+
+use serial_test::serial;
+use velvet_web::prelude::*;
+
+#[derive(Serialize, Deserialize)]
+struct Input {
+    csrf_token: String,
+    value: String,
+}
+
+#[tokio::test]
+#[serial]
+async fn test_csrf() -> AppResult<()> {
+    let mut server = App::new()
+        .route(
+            "/",
+            get(|CsrfToken(token): CsrfToken| async move { token })
+                .post(|Form(form): Form<Input>| async move { form.value }),
+        )
+        .csrf()
+        .as_test_server()
+        .await;
+    server.save_cookies();
+    let token = server.get("/").await.text();
+    assert!(!token.is_empty());
+    assert_eq!(server.get("/").await.text(), token);
+
+    let form = |csrf_token: &str| Input {
+        csrf_token: csrf_token.to_string(),
+        value: "value".to_string(),
+    };
+    server
+        .post("/")
+        .form(&form("wrong"))
+        .await
+        .assert_status(StatusCode::FORBIDDEN);
+    assert_eq!(server.post("/").form(&form(&token)).await.text(), "value");
+    server
+        .post("/")
+        .add_header(
+            HeaderName::from_static("x-csrf-token"),
+            HeaderValue::from_str(&token).unwrap(),
+        )
+        .form(&form(""))
+        .await
+        .assert_status_ok();
+
+    server.clear_cookies();
+    server
+        .post("/")
+        .form(&form(&token))
+        .await
+        .assert_status(StatusCode::FORBIDDEN);
+    server
+        .post("/")
+        .authorization_bearer("token")
+        .form(&form(""))
+        .await
+        .assert_status_ok();
+    Ok(())
+}
@@ -0,0 +1,25 @@
+#![cfg(feature = "postgres")]
+
+// Runs against the postgres of POSTGRES_TEST_URL, see tests/login_db.
+
+use serial_test::serial;
+use velvet_web::prelude::*;
+
+#[tokio::test]
+#[serial]
+async fn test_postgres_config() -> AppResult<()> {
+    dotenvy::dotenv().ok();
+    let url = std::env::var("POSTGRES_TEST_URL").expect("POSTGRES_TEST_URL is required");
+    let db = postgres_with(
+        DatabaseConfig::new(&url)
+            .max_connections(2)
+            .application_name("velvet-test")
+            .search_path("velvet_test,public"),
+    )
+    .await?;
+    let (name,): (String,) = query_as("show application_name").fetch_one(&db).await?;
+    assert_eq!(name, "velvet-test");
+    let (search_path,): (String,) = query_as("show search_path").fetch_one(&db).await?;
+    assert_eq!(search_path, "velvet_test,public");
+    Ok(())
+}
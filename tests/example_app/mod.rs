@@ -10,7 +10,7 @@ pub async fn app() -> AppResult<App> {
     JWT::Secret.setup().await?;
 
     #[cfg(feature = "sqlite")]
-    let db = sqlite().await;
+    let db = sqlite().await?;
     #[cfg(feature = "sqlite")]
     sqlx::migrate!().run(&db).await?;
 
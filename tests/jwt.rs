@@ -0,0 +1,186 @@
+#![cfg(feature = "auth")]
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{encode, jwk::JwkSet, EncodingKey, Header};
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde_json::{json, Value};
+use serial_test::serial;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use velvet_web::prelude::*;
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+fn token(algorithm: JwtAlgorithm, claims: Value) -> String {
+    encode(
+        &Header::new(algorithm),
+        &claims,
+        &EncodingKey::from_secret(b"secret"),
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+#[serial]
+async fn test_jwt_validation() -> AppResult<()> {
+    let valid = |token: &str| claims_for::<Value>(token).is_ok();
+    JWT::Secret.setup_with(JwtValidation::new()).await?;
+    let exp = now() + 3600;
+    assert!(valid(&token(JwtAlgorithm::HS256, json!({ "exp": exp }))));
+    // The header algorithm is only used when allowed.
+    let hs384 = token(JwtAlgorithm::HS384, json!({ "exp": exp }));
+    assert!(!valid(&hs384));
+    // Expiration is required, with a leeway.
+    assert!(!valid(&token(JwtAlgorithm::HS256, json!({}))));
+    let expired = token(JwtAlgorithm::HS256, json!({ "exp": now() - 30 }));
+    assert!(valid(&expired));
+
+    JWT::Secret
+        .setup_with(
+            JwtValidation::new()
+                .algorithms(&[JwtAlgorithm::HS256, JwtAlgorithm::HS384])
+                .issuers(&["velvet", "other"])
+                .audiences(&["app"])
+                .required_claims(&["exp", "sub", "tenant"])
+                .leeway(Duration::ZERO),
+        )
+        .await?;
+    let claims = json!({ "exp": exp, "iss": "other", "aud": "app", "sub": "1", "tenant": "a" });
+    assert!(valid(&token(JwtAlgorithm::HS384, claims.clone())));
+    assert!(!valid(&expired));
+    for (claim, value) in [("iss", json!("unknown")), ("aud", json!("another"))] {
+        let mut wrong = claims.clone();
+        wrong[claim] = value;
+        assert!(!valid(&token(JwtAlgorithm::HS256, wrong)));
+    }
+    for claim in ["iss", "aud", "sub", "tenant"] {
+        let mut missing = claims.clone();
+        missing.as_object_mut().unwrap().remove(claim);
+        assert!(!valid(&token(JwtAlgorithm::HS256, missing)));
+    }
+
+    // The extractors apply the same rules.
+    let server = App::new()
+        .route(
+            "/",
+            get(|BearerClaims(claims): BearerClaims<Value>| async move {
+                claims["tenant"].as_str().unwrap_or_default().to_string()
+            }),
+        )
+        .as_test_server()
+        .await;
+    let response = server
+        .get("/")
+        .authorization_bearer(token(JwtAlgorithm::HS256, claims.clone()))
+        .await;
+    assert_eq!(response.text(), "a");
+    server
+        .get("/")
+        .authorization_bearer(token(JwtAlgorithm::HS256, json!({ "exp": exp })))
+        .await
+        .assert_status_unauthorized();
+
+    // Not requiring exp still rejects the expired tokens.
+    JWT::Secret
+        .setup_with(JwtValidation::new().required_claims(&["sub"]))
+        .await?;
+    assert!(valid(&token(JwtAlgorithm::HS256, json!({ "sub": "1" }))));
+    let expired = json!({ "sub": "1", "exp": now() - 3600 });
+    assert!(!valid(&token(JwtAlgorithm::HS256, expired)));
+    let not_yet = json!({ "sub": "1", "nbf": now() + 3600 });
+    assert!(!valid(&token(JwtAlgorithm::HS256, not_yet)));
+
+    JWT::Secret.setup_with(JwtValidation::new()).await?;
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_jwt_verifiers() -> AppResult<()> {
+    // Key pair of an external identity provider, published as a JWK set.
+    let rng = SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+    let pair =
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+    let point = pair.public_key().as_ref();
+    let set: JwkSet = serde_json::from_value(json!({ "keys": [{
+        "kty": "EC",
+        "crv": "P-256",
+        "kid": "idp-1",
+        "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+        "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+    }]}))
+    .unwrap();
+    let mut header = Header::new(JwtAlgorithm::ES256);
+    header.kid = Some("idp-1".to_string());
+    let idp_key = EncodingKey::from_ec_der(pkcs8.as_ref());
+    let idp_token = |claims: Value| encode(&header, &claims, &idp_key).unwrap();
+
+    let own = JwtVerifier::new("own").secret(b"own-secret");
+    let idp = JwtVerifier::new("idp")
+        .jwk_set(&set)?
+        .jwk_validation(JwtValidation::new().issuers(&["idp"]));
+    let exp = now() + 3600;
+    let claims = json!({ "exp": exp, "iss": "idp", "username": "partner", "roles": ["partner"] });
+    let partner = idp_token(claims);
+    let user = own.sign(&json!({ "exp": exp, "username": "user", "roles": ["user"] }))?;
+    assert_eq!(idp.name(), "idp");
+    assert!(idp.claims::<Value>(&partner).is_ok());
+    assert!(idp.claims::<Value>(&user).is_err());
+    assert!(own.claims::<Value>(&partner).is_err());
+    assert!(idp
+        .claims::<Value>(&idp_token(json!({ "exp": exp })))
+        .is_err());
+
+    set_jwt_verifier(own);
+    add_jwt_verifier(idp.clone());
+    assert!(claims_for::<Value>(&user).is_ok());
+    async fn username(BearerClaims(claims): BearerClaims<Value>) -> String {
+        claims["username"].as_str().unwrap_or_default().to_string()
+    }
+    struct Idp;
+    impl VerifierName for Idp {
+        const NAME: &'static str = "idp";
+    }
+    struct Own;
+    impl VerifierName for Own {
+        const NAME: &'static str = "own";
+    }
+    async fn idp_username(NamedBearerClaims(claims, ..): NamedBearerClaims<Idp, Value>) -> String {
+        claims["username"].as_str().unwrap_or_default().to_string()
+    }
+    async fn own_username(NamedBearerClaims(claims, ..): NamedBearerClaims<Own, Value>) -> String {
+        claims["username"].as_str().unwrap_or_default().to_string()
+    }
+    let partners = Router::new()
+        .route("/partners", get(username))
+        .authorized_bearer_role("partner".to_string())
+        .layer(Extension(idp));
+    let server = App::new()
+        .route("/", get(username))
+        .route("/idp", get(idp_username))
+        .route("/own", get(own_username))
+        .router(partners)
+        .as_test_server()
+        .await;
+    let get = |path: &'static str, token: &str| server.get(path).authorization_bearer(token);
+    assert_eq!(get("/", &user).await.text(), "user");
+    get("/", &partner).await.assert_status_unauthorized();
+    assert_eq!(get("/partners", &partner).await.text(), "partner");
+    get("/partners", &user).await.assert_status_unauthorized();
+    assert_eq!(get("/idp", &partner).await.text(), "partner");
+    get("/idp", &user).await.assert_status_unauthorized();
+    assert_eq!(get("/own", &user).await.text(), "user");
+    get("/own", &partner).await.assert_status_unauthorized();
+
+    set_jwt_verifier(JwtVerifier::new("default"));
+    JWT::Secret.setup().await?;
+    Ok(())
+}
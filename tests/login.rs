@@ -2,8 +2,14 @@
 #![cfg(feature = "login")]
 #![cfg(feature = "auth")]
 
+mod login_db;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use login_db::{sql, test_db};
 use serde::Deserialize;
+use serde_json::{json, Value};
 use serial_test::serial;
+use sqlx::Database;
 use velvet_web::prelude::*;
 
 #[derive(Deserialize)]
@@ -14,13 +20,940 @@ struct Claims {
 #[tokio::test]
 #[serial]
 async fn test() -> AppResult<()> {
-    let db = sqlite().await;
+    let db = test_db().await?;
     JWT::Secret.setup().await?;
     login_setup(&db).await?;
     let code = register_user(&db, "user", "email", "password").await?;
+    let taken = register_user(&db, "user", "other", "password")
+        .await
+        .unwrap_err();
+    assert_eq!(taken.status(), StatusCode::CONFLICT);
     register_user_confirm(&db, "user", &code).await?;
     let token = login_token(&db, "user", "password").await?;
     let claims = claims_for::<Claims>(&token)?;
     assert_eq!(claims.username, "user");
     Ok(())
 }
+
+#[tokio::test]
+#[serial]
+async fn test_migrations() -> AppResult<()> {
+    let db = test_db().await?;
+    // instances starting together wait for each other's migrations
+    let (first, second) = tokio::join!(login_setup(&db), login_setup(&db));
+    first?;
+    second?;
+    let (applied,): (i64,) = query_as("select count(*) from login_migrations")
+        .fetch_one(&db)
+        .await?;
+    login_setup(&db).await?;
+    let (reapplied,): (i64,) = query_as("select count(*) from login_migrations")
+        .fetch_one(&db)
+        .await?;
+    assert!(applied > 0);
+    assert_eq!(applied, reapplied);
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_email_migration_with_duplicates() -> AppResult<()> {
+    let db = test_db().await?;
+    login_setup(&db).await?;
+    // back to the schema before emails were unique
+    let drop_index = match Backend::NAME {
+        "MySQL" => "drop index login_email on login",
+        _ => "drop index login_email",
+    };
+    for statement in [
+        "delete from login_migrations where version = 6",
+        drop_index,
+        "alter table login drop column pending_email",
+        "alter table login drop column email_code",
+        "alter table login drop column email_code_expires",
+    ] {
+        query(statement).execute(&db).await?;
+    }
+    for (userid, username, email) in [
+        ("1", "first", "same@test.com"),
+        ("2", "second", "same@test.com"),
+        ("3", "third", ""),
+        ("4", "fourth", ""),
+    ] {
+        query(&sql(
+            "insert into login (userid, username, email, password, confirmation_code) \
+            values(?, ?, ?, '', '')",
+        ))
+        .bind(userid)
+        .bind(username)
+        .bind(email)
+        .execute(&db)
+        .await?;
+    }
+    login_setup(&db).await?;
+    let emails: Vec<(String, Option<String>, Option<String>)> =
+        query_as("select username, email, pending_email from login order by userid")
+            .fetch_all(&db)
+            .await?;
+    assert_eq!(
+        emails,
+        vec![
+            ("first".into(), Some("same@test.com".into()), None),
+            ("second".into(), None, Some("same@test.com".into())),
+            ("third".into(), None, None),
+            ("fourth".into(), None, None),
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_login_config() -> AppResult<()> {
+    #[derive(Serialize)]
+    struct LoginForm {
+        username: &'static str,
+        password: &'static str,
+    }
+    let db = test_db().await?;
+    let config = LoginConfig::new()
+        .prefix("/auth")
+        .redirect_after_login("/home")
+        .registration_open(false)
+        .cookie(TokenCookie {
+            name: "auth".into(),
+            ..Default::default()
+        });
+    let server = App::new()
+        .login_flow(&db, config)
+        .await
+        .inject(db.clone())
+        .as_test_server()
+        .await;
+    let code = register_user(&db, "user", "email", "password").await?;
+    register_user_confirm(&db, "user", &code).await?;
+
+    server.get("/auth/login").await.assert_status_ok();
+    server.get("/login").await.assert_status_not_found();
+    server.get("/auth/register").await.assert_status_not_found();
+
+    let login = server
+        .post("/auth/login")
+        .form(&LoginForm {
+            username: "user",
+            password: "wrong",
+        })
+        .await;
+    login.assert_status_unauthorized();
+    assert!(login.text().contains("Invalid username or password"));
+
+    let login = server
+        .post("/auth/login")
+        .form(&LoginForm {
+            username: "user",
+            password: "password",
+        })
+        .await;
+    assert_eq!(login.header("location"), "/home");
+    let token = login.cookie("auth");
+    assert_eq!(claims_for::<Claims>(token.value())?.username, "user");
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_login_templates() -> AppResult<()> {
+    struct Templates;
+    impl LoginTemplates for Templates {
+        fn login(&self, page: &LoginPage) -> AppResult<String> {
+            Ok(format!(
+                "custom {} {}",
+                page.username,
+                page.error.clone().unwrap_or_default()
+            ))
+        }
+    }
+    #[derive(Serialize)]
+    struct LoginForm {
+        username: &'static str,
+        password: &'static str,
+    }
+    let db = test_db().await?;
+    let server = App::new()
+        .login_flow(&db, LoginConfig::new().templates(Templates))
+        .await
+        .inject(db.clone())
+        .as_test_server()
+        .await;
+
+    assert_eq!(server.get("/login").await.text(), "custom  ");
+    let login = server
+        .post("/login")
+        .form(&LoginForm {
+            username: "nobody",
+            password: "password",
+        })
+        .await;
+    login.assert_status_unauthorized();
+    assert_eq!(login.text(), "custom nobody Invalid username or password");
+    assert!(server.get("/register").await.text().contains("csrf_token"));
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_confirmation() -> AppResult<()> {
+    let db = test_db().await?;
+    login_setup(&db).await?;
+    let code = register_user(&db, "user", "email", "password").await?;
+    let wrong = register_user_confirm(&db, "user", "wrong")
+        .await
+        .unwrap_err();
+    assert_eq!(wrong.status(), StatusCode::BAD_REQUEST);
+
+    // the previous code was just sent
+    let resend = resend_confirmation(&db, "user").await.unwrap_err();
+    assert_eq!(resend.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    query("update login set confirmation_expires = 0, confirmation_sent = 0")
+        .execute(&db)
+        .await?;
+    let expired = register_user_confirm(&db, "user", &code).await.unwrap_err();
+    assert_eq!(expired.status(), StatusCode::GONE);
+
+    let (email, new_code) = resend_confirmation(&db, "user").await?;
+    assert_eq!(email, "email");
+    assert!(register_user_confirm(&db, "user", &code).await.is_err());
+    register_user_confirm(&db, "user", &new_code).await?;
+
+    // codes are single use
+    let reused = register_user_confirm(&db, "user", &new_code)
+        .await
+        .unwrap_err();
+    assert_eq!(reused.status(), StatusCode::BAD_REQUEST);
+    assert!(resend_confirmation(&db, "user").await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_email() -> AppResult<()> {
+    let db = test_db().await?;
+    JWT::Secret.setup().await?;
+    login_setup(&db).await?;
+    let code = register_user(&db, "user", "user@test.com", "password").await?;
+    register_user_confirm(&db, "user", &code).await?;
+    assert!(register_user(&db, "other", "user@test.com", "password")
+        .await
+        .is_err());
+
+    assert_eq!(
+        find_username(&db, LoginWith::Email, "user@test.com").await?,
+        "user"
+    );
+    assert!(find_username(&db, LoginWith::Email, "user").await.is_err());
+    assert!(find_username(&db, LoginWith::Username, "user@test.com")
+        .await
+        .is_err());
+    assert_eq!(
+        find_username(&db, LoginWith::UsernameOrEmail, "user@test.com").await?,
+        "user"
+    );
+
+    let code = request_email_change(&db, "user", "new@test.com").await?;
+    let wrong = confirm_email_change(&db, "user", "wrong")
+        .await
+        .unwrap_err();
+    assert_eq!(wrong.status(), StatusCode::BAD_REQUEST);
+    let (old, new) = confirm_email_change(&db, "user", &code).await?;
+    assert_eq!(old.as_deref(), Some("user@test.com"));
+    assert_eq!(new, "new@test.com");
+    assert_eq!(
+        find_username(&db, LoginWith::Email, "new@test.com").await?,
+        "user"
+    );
+    assert!(confirm_email_change(&db, "user", &code).await.is_err());
+
+    let used = request_email_change(&db, "user", "new@test.com")
+        .await
+        .unwrap_err();
+    assert_eq!(used.status(), StatusCode::CONFLICT);
+
+    // the address was free when requested, but taken before the confirmation
+    let code = register_user(&db, "other", "other@test.com", "password").await?;
+    register_user_confirm(&db, "other", &code).await?;
+    let first = request_email_change(&db, "user", "both@test.com").await?;
+    let second = request_email_change(&db, "other", "both@test.com").await?;
+    confirm_email_change(&db, "user", &first).await?;
+    let taken = confirm_email_change(&db, "other", &second)
+        .await
+        .unwrap_err();
+    assert_eq!(taken.status(), StatusCode::CONFLICT);
+
+    // users registered without an email don't collide
+    register_user(&db, "without", "", "password").await?;
+    register_user(&db, "without2", "", "password").await?;
+    assert!(get_user(&db, "without").await?.unwrap().email.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_login_with_email() -> AppResult<()> {
+    #[derive(Serialize)]
+    struct LoginForm {
+        username: &'static str,
+        password: &'static str,
+    }
+    let db = test_db().await?;
+    let config = LoginConfig::new().login_with(LoginWith::Email);
+    let server = App::new()
+        .login_flow(&db, config)
+        .await
+        .inject(db.clone())
+        .as_test_server()
+        .await;
+    let code = register_user(&db, "user", "user@test.com", "password").await?;
+    register_user_confirm(&db, "user", &code).await?;
+
+    assert!(server.get("/login").await.text().contains("Email"));
+    let login = server
+        .post("/login")
+        .form(&LoginForm {
+            username: "user",
+            password: "password",
+        })
+        .await;
+    login.assert_status_unauthorized();
+    let login = server
+        .post("/login")
+        .form(&LoginForm {
+            username: "user@test.com",
+            password: "password",
+        })
+        .await;
+    let token = login.cookie("token");
+    assert_eq!(claims_for::<Claims>(token.value())?.username, "user");
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_password_policy() -> AppResult<()> {
+    let db = test_db().await?;
+    JWT::Secret.setup().await?;
+    login_setup(&db).await?;
+    let breached = std::env::temp_dir().join("velvet_breached.txt");
+    std::fs::write(&breached, "123456\nsecret123\n")?;
+    set_password_policy(
+        PasswordPolicy::new()
+            .min_length(6)
+            .breached_list_file(breached.to_str().unwrap())?
+            .argon2(8192, 1, 1),
+    );
+    let short = register_user(&db, "user", "user@test.com", "pass").await;
+    assert_eq!(short.unwrap_err().status(), StatusCode::BAD_REQUEST);
+    let common = register_user(&db, "user", "user@test.com", "secret123").await;
+    assert_eq!(common.unwrap_err().to_string(), "Password is too common");
+    let similar = register_user(&db, "user", "user@test.com", "User2024").await;
+    assert_eq!(
+        similar.unwrap_err().to_string(),
+        "Password is too similar to the username"
+    );
+
+    let code = register_user(&db, "user", "user@test.com", "password").await?;
+    register_user_confirm(&db, "user", &code).await?;
+    let hash = || async {
+        let (hash,): (String,) =
+            sqlx::query_as("select password from login where username = 'user'")
+                .fetch_one(&db)
+                .await
+                .unwrap();
+        hash
+    };
+    assert!(hash().await.starts_with("$argon2id$v=19$m=8192,t=1,p=1$"));
+    login_token(&db, "user", "password").await?;
+    assert!(hash().await.starts_with("$argon2id$v=19$m=8192,t=1,p=1$"));
+
+    set_password_policy(PasswordPolicy::new().argon2(16384, 2, 1));
+    assert!(login_token(&db, "user", "wrong").await.is_err());
+    assert!(hash().await.starts_with("$argon2id$v=19$m=8192,t=1,p=1$"));
+    login_token(&db, "user", "password").await?;
+    assert!(hash().await.starts_with("$argon2id$v=19$m=16384,t=2,p=1$"));
+    login_token(&db, "user", "password").await?;
+
+    let reset = reset_user_password(&db, &db, "user", "short")
+        .await
+        .unwrap_err();
+    assert_eq!(reset.status(), StatusCode::BAD_REQUEST);
+    set_password_policy(PasswordPolicy::new());
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_audit() -> AppResult<()> {
+    #[derive(Serialize)]
+    struct LoginForm {
+        username: &'static str,
+        password: &'static str,
+    }
+    let db = test_db().await?;
+    let server = App::new()
+        .login_flow(&db, LoginConfig::new())
+        .await
+        .inject(db.clone())
+        .as_test_server()
+        .await;
+    let code = register_user(&db, "user", "user@test.com", "password").await?;
+    register_user_confirm(&db, "user", &code).await?;
+
+    let failed_login = || {
+        server
+            .post("/login")
+            .add_header("user-agent", "tester")
+            .add_header("x-forwarded-for", "10.0.0.1, 10.0.0.2")
+            .form(&LoginForm {
+                username: "user",
+                password: "wrong",
+            })
+    };
+    // X-Forwarded-For is only read behind trusted proxies
+    failed_login().await.assert_status_unauthorized();
+    std::env::set_var("TRUSTED_PROXIES", "*");
+    failed_login().await.assert_status_unauthorized();
+    std::env::remove_var("TRUSTED_PROXIES");
+    let long = "x".repeat(300);
+    server
+        .post("/login")
+        .form(&[("username", long.as_str()), ("password", "wrong")])
+        .await
+        .assert_status_unauthorized();
+    let login = server
+        .post("/login")
+        .form(&LoginForm {
+            username: "user",
+            password: "password",
+        })
+        .await;
+    let token = login.cookie("token");
+    server
+        .get("/logout")
+        .add_cookie(token)
+        .await
+        .assert_status_see_other();
+
+    let query = AuditQuery {
+        username: Some("user".to_string()),
+        ..Default::default()
+    };
+    let page = search_audit(&db, &query).await?;
+    let mut events = page
+        .records
+        .iter()
+        .map(|r| r.event.as_str())
+        .collect::<Vec<_>>();
+    events.sort();
+    assert_eq!(
+        events,
+        vec!["login_failure", "login_failure", "login_success", "logout"]
+    );
+    let query = AuditQuery {
+        event: Some(AuditEvent::LoginFailure.to_string()),
+        ..Default::default()
+    };
+    let failures = search_audit(&db, &query).await?;
+    assert_eq!(failures.total, 3);
+    let mut ips = failures
+        .records
+        .iter()
+        .filter(|r| r.username == "user")
+        .map(|r| (r.ip.as_deref(), r.user_agent.as_deref()))
+        .collect::<Vec<_>>();
+    ips.sort();
+    assert_eq!(
+        ips,
+        vec![(None, Some("tester")), (Some("10.0.0.1"), Some("tester"))]
+    );
+
+    audit(
+        &db,
+        AuditEvent::PasswordChange,
+        "user",
+        &AuditContext::default(),
+        None,
+    )
+    .await?;
+    let query = AuditQuery {
+        since: Some(0),
+        per_page: Some(2),
+        ..Default::default()
+    };
+    let page = search_audit(&db, &query).await?;
+    assert_eq!((page.total, page.records.len()), (6, 2));
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_lockout() -> AppResult<()> {
+    #[derive(Serialize)]
+    struct LoginForm {
+        username: &'static str,
+        password: &'static str,
+    }
+    let db = test_db().await?;
+    let config = LoginConfig::new().lockout(2, std::time::Duration::from_secs(2));
+    let server = App::new()
+        .login_flow(&db, config)
+        .await
+        .inject(db.clone())
+        .as_test_server()
+        .await;
+    let code = register_user(&db, "user", "user@test.com", "password").await?;
+    register_user_confirm(&db, "user", &code).await?;
+    let login = |password| {
+        server.post("/login").form(&LoginForm {
+            username: "user",
+            password,
+        })
+    };
+
+    login("wrong").await.assert_status_unauthorized();
+    login("password").await.assert_status_see_other();
+    login("wrong").await.assert_status_unauthorized();
+    login("wrong").await.assert_status_unauthorized();
+    login("password").await.assert_status_unauthorized();
+    unlock_user(&db, "user").await?;
+    login("password").await.assert_status_see_other();
+
+    login("wrong").await.assert_status_unauthorized();
+    login("wrong").await.assert_status_unauthorized();
+    login("password").await.assert_status_unauthorized();
+    tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+    login("password").await.assert_status_see_other();
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_impersonation() -> AppResult<()> {
+    #[derive(Serialize)]
+    struct LoginForm {
+        username: &'static str,
+        password: &'static str,
+    }
+    #[derive(Serialize)]
+    struct ImpersonateForm {
+        username: &'static str,
+    }
+    #[derive(Deserialize)]
+    struct Act {
+        sub: String,
+    }
+    #[derive(Deserialize)]
+    struct ActClaims {
+        username: String,
+        act: Option<Act>,
+    }
+    std::env::set_var("MAIL_FROM", "test@test.com");
+    std::env::set_var("MAIL_HOST", "localhost");
+    let db = test_db().await?;
+    let admin_only = Router::new()
+        .route("/admin", get(|| async { "admin" }))
+        .authorized_cookie_role("/login", "admin");
+    let mut server = App::new()
+        .router(admin_only)
+        .route(
+            "/banner",
+            get(|impersonation: Option<Impersonation>| async move {
+                match impersonation {
+                    Some(impersonation) => impersonation.banner().unwrap(),
+                    None => "none".to_string(),
+                }
+            }),
+        )
+        .login_flow(
+            &db,
+            LoginConfig::new()
+                .impersonation("admin")
+                .protected_roles(&["owner"]),
+        )
+        .await
+        .login_api_flow_with_mail(&db)
+        .await
+        .inject(db.clone())
+        .as_test_server()
+        .await;
+    server.save_cookies();
+    create_user(&db, "root", "root@test.com", "password", &["admin"]).await?;
+    create_user(&db, "admin", "admin@test.com", "password", &["admin"]).await?;
+    create_user(&db, "owner", "owner@test.com", "password", &["owner"]).await?;
+    create_user(&db, "user", "user@test.com", "password", &["user"]).await?;
+
+    server
+        .post("/login")
+        .form(&LoginForm {
+            username: "root",
+            password: "password",
+        })
+        .await;
+    assert_eq!(server.get("/admin").await.text(), "admin");
+    assert_eq!(server.get("/banner").await.text(), "none");
+    // users with the impersonation role or a protected role can't be impersonated
+    for username in ["admin", "owner"] {
+        server
+            .post("/impersonate")
+            .form(&ImpersonateForm { username })
+            .await
+            .assert_status_forbidden();
+    }
+    let impersonate = server
+        .post("/impersonate")
+        .form(&ImpersonateForm { username: "user" })
+        .await;
+    impersonate.assert_status_see_other();
+    let claims = claims_for::<ActClaims>(impersonate.cookie("token").value())?;
+    assert_eq!(claims.username, "user");
+    assert_eq!(claims.act.unwrap().sub, "root");
+
+    // the impersonated user's roles apply
+    server.get("/admin").await.assert_status_see_other();
+    let banner = server.get("/banner").await.text();
+    assert!(banner.contains("Viewing as <strong>user</strong>, signed in as root"));
+    assert!(banner.contains(r#"action="/impersonate/end""#));
+    server
+        .post("/impersonate")
+        .form(&ImpersonateForm { username: "root" })
+        .await
+        .assert_status_forbidden();
+
+    // the impersonation token can't change the email through the api either
+    let email = server
+        .post("/api/auth/email")
+        .authorization_bearer(impersonate.cookie("token").value())
+        .json(&json!({"email": "root@test.com"}))
+        .await;
+    email.assert_status_forbidden();
+    assert_eq!(
+        email.json::<Value>()["detail"],
+        "Impersonations can't change the email"
+    );
+
+    let end = server.post("/impersonate/end").await;
+    end.assert_status_see_other();
+    let claims = claims_for::<ActClaims>(end.cookie("token").value())?;
+    assert_eq!(claims.username, "root");
+    assert!(claims.act.is_none());
+    assert_eq!(server.get("/admin").await.text(), "admin");
+
+    let query = AuditQuery {
+        username: Some("user".to_string()),
+        ..Default::default()
+    };
+    let mut events = search_audit(&db, &query)
+        .await?
+        .records
+        .into_iter()
+        .map(|r| (r.event, r.detail))
+        .collect::<Vec<_>>();
+    events.sort();
+    let by_root = Some("by root".to_string());
+    assert_eq!(
+        events,
+        vec![
+            ("impersonation_end".to_string(), by_root.clone()),
+            ("impersonation_start".to_string(), by_root),
+        ]
+    );
+
+    server
+        .post("/login")
+        .form(&LoginForm {
+            username: "user",
+            password: "password",
+        })
+        .await;
+    server
+        .post("/impersonate")
+        .form(&ImpersonateForm { username: "root" })
+        .await
+        .assert_status_forbidden();
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_invitation() -> AppResult<()> {
+    #[derive(Serialize)]
+    struct RegisterForm {
+        username: &'static str,
+        password: &'static str,
+        invitation: String,
+    }
+    #[derive(Deserialize)]
+    struct UserRoles {
+        roles: Vec<String>,
+    }
+    let db = test_db().await?;
+    let config = LoginConfig::new().invitation_only(true);
+    let server = App::new()
+        .login_flow(&db, config)
+        .await
+        .inject(db.clone())
+        .as_test_server()
+        .await;
+    let lifetime = std::time::Duration::from_secs(3600);
+    let invitation = create_invitation(
+        &db,
+        "new@test.com",
+        &["user", "staff"],
+        lifetime,
+        Some("root"),
+    )
+    .await?;
+    let token = invitation.token()?;
+    assert_eq!(list_invitations(&db).await?.len(), 1);
+
+    assert!(!server.get("/login").await.text().contains("/register"));
+    let closed = server.get("/register").await;
+    closed.assert_status_forbidden();
+    assert!(closed.text().contains("Registration is by invitation only"));
+    let form = server.get(&format!("/register?invitation={token}")).await;
+    assert!(form.text().contains("new@test.com"));
+    let forged = format!("{}.forged", invitation.id);
+    server
+        .get(&format!("/register?invitation={forged}"))
+        .await
+        .assert_status_bad_request();
+
+    let register = server
+        .post("/register")
+        .form(&RegisterForm {
+            username: "new",
+            password: "password",
+            invitation: token.clone(),
+        })
+        .await;
+    register.assert_status_see_other();
+    let claims = claims_for::<UserRoles>(&login_token(&db, "new", "password").await?)?;
+    assert_eq!(claims.roles, vec!["user", "staff"]);
+    assert_eq!(
+        get_user(&db, "new").await?.unwrap().email.unwrap(),
+        "new@test.com"
+    );
+    assert!(list_invitations(&db).await?.is_empty());
+
+    // invitations are single use
+    let reused = server
+        .post("/register")
+        .form(&RegisterForm {
+            username: "other",
+            password: "password",
+            invitation: token,
+        })
+        .await;
+    reused.assert_status_bad_request();
+    assert!(reused.text().contains("Invalid invitation"));
+
+    let expired =
+        create_invitation(&db, "late@test.com", &["user"], Default::default(), None).await?;
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    let error = find_invitation(&db, &expired.token()?).await.unwrap_err();
+    assert_eq!(error.status(), StatusCode::GONE);
+    revoke_invitation(&db, &expired.id).await?;
+    assert!(revoke_invitation(&db, &expired.id).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_claims_hook() -> AppResult<()> {
+    #[derive(Serialize)]
+    struct LoginForm {
+        username: &'static str,
+        password: &'static str,
+    }
+    #[derive(Deserialize)]
+    struct TokenClaims {
+        sub: String,
+        iat: u64,
+        exp: u64,
+        jti: String,
+        iss: Option<String>,
+        roles: Vec<String>,
+        email: String,
+    }
+    struct Email;
+    #[axum::async_trait]
+    impl ClaimsHook for Email {
+        async fn claims(
+            &self,
+            db: &Pool<Backend>,
+            mut claims: LoginClaims,
+        ) -> AppResult<LoginClaims> {
+            let (email,): (String,) = query_as(&sql("select email from login where username = ?"))
+                .bind(&claims.username)
+                .fetch_one(db)
+                .await?;
+            claims.roles = vec!["member".to_string()];
+            claims.extra.insert("email".into(), email.into());
+            claims.extra.insert("sub".into(), "spoofed".into());
+            Ok(claims)
+        }
+    }
+    let db = test_db().await?;
+    JWT::Secret.setup().await?;
+    std::env::set_var("JWT_ISSUER", "velvet");
+    let server = App::new()
+        .login_flow(&db, LoginConfig::new().claims_hook(Email))
+        .await
+        .login_api_flow_with_config(&db, LoginConfig::new().claims_hook(Email))
+        .await
+        .inject(db.clone())
+        .as_test_server()
+        .await;
+    let code = register_user(&db, "user", "user@test.com", "password").await?;
+    register_user_confirm(&db, "user", &code).await?;
+
+    let login = || {
+        server.post("/login").form(&LoginForm {
+            username: "user",
+            password: "password",
+        })
+    };
+    let first = login().await;
+    let claims = claims_for::<TokenClaims>(first.cookie("token").value())?;
+    let second = claims_for::<TokenClaims>(login().await.cookie("token").value())?;
+    assert_eq!(claims.email, "user@test.com");
+    assert_eq!(claims.roles, vec!["member"]);
+    assert_eq!(claims.sub, get_user(&db, "user").await?.unwrap().userid);
+    assert_eq!(claims.exp - claims.iat, 3600 * 24);
+    assert_eq!(claims.iss.as_deref(), Some("velvet"));
+    assert_ne!(claims.jti, second.jti);
+    let token = first.cookie("token");
+    let payload = token.value().split('.').nth(1).unwrap();
+    let payload = String::from_utf8(URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap();
+    assert_eq!(payload.matches("\"sub\"").count(), 1);
+
+    let tokens = server
+        .post("/api/auth/login")
+        .json(&json!({"username": "user", "password": "password"}))
+        .await
+        .json::<Value>();
+    let api = claims_for::<TokenClaims>(tokens["access_token"].as_str().unwrap())?;
+    assert_eq!(api.email, "user@test.com");
+    assert_eq!(api.roles, vec!["member"]);
+    let refreshed = server
+        .post("/api/auth/refresh")
+        .json(&json!({"refresh_token": tokens["refresh_token"]}))
+        .await
+        .json::<Value>();
+    let refreshed = claims_for::<TokenClaims>(refreshed["access_token"].as_str().unwrap())?;
+    assert_eq!(refreshed.email, "user@test.com");
+    assert_eq!(refreshed.sub, claims.sub);
+    std::env::remove_var("JWT_ISSUER");
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_login_verifier() -> AppResult<()> {
+    #[derive(Serialize)]
+    struct LoginForm {
+        username: &'static str,
+        password: &'static str,
+    }
+    let db = test_db().await?;
+    JWT::Secret.setup().await?;
+    let verifier = JwtVerifier::new("login").secret(b"login-secret");
+    let config = LoginConfig::new().jwt_verifier(verifier.clone());
+    let server = App::new()
+        .login_flow(&db, config.clone())
+        .await
+        .login_api_flow_with_config(&db, config)
+        .await
+        .login_introspection(
+            &db,
+            IntrospectionConfig::new()
+                .api_key("api-key")
+                .jwt_verifier(verifier.clone()),
+        )
+        .await
+        .inject(db.clone())
+        .as_test_server()
+        .await;
+    let code = register_user(&db, "user", "user@test.com", "password").await?;
+    register_user_confirm(&db, "user", &code).await?;
+
+    let login = server
+        .post("/login")
+        .form(&LoginForm {
+            username: "user",
+            password: "password",
+        })
+        .await;
+    let token = login.cookie("token");
+    assert_eq!(verifier.claims::<Claims>(token.value())?.username, "user");
+    assert!(claims_for::<Claims>(token.value()).is_err());
+
+    let tokens = server
+        .post("/api/auth/login")
+        .json(&json!({"username": "user", "password": "password"}))
+        .await
+        .json::<Value>();
+    let access_token = tokens["access_token"].as_str().unwrap();
+    assert!(verifier.claims::<Claims>(access_token).is_ok());
+    let me = server
+        .get("/api/auth/me")
+        .authorization_bearer(access_token)
+        .await
+        .json::<Value>();
+    assert_eq!(me["username"], "user");
+    let introspection = server
+        .post("/api/auth/introspect")
+        .add_header(
+            HeaderName::from_static("x-api-key"),
+            HeaderValue::from_static("api-key"),
+        )
+        .form(&json!({ "token": access_token }))
+        .await
+        .json::<Value>();
+    assert_eq!(introspection["active"], true);
+    assert_eq!(introspection["token_type"], "Bearer");
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_login_flow_keeps_the_jwt_rules() -> AppResult<()> {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    let token = |claims: Value| {
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap()
+    };
+    let exp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 3600;
+    JWT::Secret
+        .setup_with(JwtValidation::new().issuers(&["velvet"]))
+        .await?;
+    let db = test_db().await?;
+    let _server = App::new()
+        .login_flow(&db, LoginConfig::new())
+        .await
+        .inject(db.clone())
+        .as_test_server()
+        .await;
+    assert!(claims_for::<Value>(&token(json!({ "exp": exp, "iss": "velvet" }))).is_ok());
+    assert!(claims_for::<Value>(&token(json!({ "exp": exp }))).is_err());
+
+    JWT::Secret.setup_with(JwtValidation::new()).await?;
+    Ok(())
+}
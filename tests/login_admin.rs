@@ -0,0 +1,195 @@
+#![cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+#![cfg(feature = "login")]
+
+mod login_db;
+
+use login_db::test_db;
+use serde_json::{json, Value};
+use serial_test::serial;
+use velvet_web::prelude::*;
+
+#[tokio::test]
+#[serial]
+async fn test_admin_functions() -> AppResult<()> {
+    let db = test_db().await?;
+    login_setup(&db).await?;
+    JWT::Secret.setup().await?;
+    create_user(&db, "alice", "alice@test.com", "password", &["user"]).await?;
+    create_user(&db, "bob", "bob@test.com", "password", &["user"]).await?;
+
+    let page = search_users(&db, Some("test.com"), 1, 1).await?;
+    assert_eq!(page.total, 2);
+    assert_eq!(page.users[0].username, "alice");
+    let page = search_users(&db, Some("test.com"), 2, 1).await?;
+    assert_eq!(page.users[0].username, "bob");
+    assert_eq!(search_users(&db, Some("_"), 1, 10).await?.total, 0);
+    let page = search_users(&db, Some("BOB@Test"), 1, 10).await?;
+    assert_eq!(page.total, 1);
+    assert_eq!(page.users[0].username, "bob");
+    assert!(search_users(&db, None, u32::MAX, u32::MAX)
+        .await?
+        .users
+        .is_empty());
+
+    set_user_roles(&db, "alice", &["user", "editor"]).await?;
+    set_user_email(&db, "alice", "alice@example.com").await?;
+    let alice = get_user(&db, "alice").await?.unwrap();
+    assert_eq!(alice.roles, vec!["user", "editor"]);
+    assert_eq!(alice.email.as_deref(), Some("alice@example.com"));
+
+    set_user_disabled(&db, &db, "alice", true).await?;
+    assert!(login_token(&db, "alice", "password").await.is_err());
+    set_user_disabled(&db, &db, "alice", false).await?;
+    assert!(login_token(&db, "alice", "password").await.is_ok());
+
+    for _ in 0..5 {
+        assert!(login_token(&db, "bob", "wrong").await.is_err());
+    }
+    assert_eq!(get_user(&db, "bob").await?.unwrap().failed_logins, 5);
+    unlock_user(&db, "bob").await?;
+    assert_eq!(get_user(&db, "bob").await?.unwrap().failed_logins, 0);
+    assert!(login_token(&db, "bob", "password").await.is_ok());
+    reset_user_password(&db, &db, "bob", "new password").await?;
+    assert!(login_token(&db, "bob", "new password").await.is_ok());
+
+    delete_user(&db, &db, "bob").await?;
+    assert!(get_user(&db, "bob").await?.is_none());
+    assert!(delete_user(&db, &db, "bob").await.is_err());
+    assert!(set_user_roles(&db, "bob", &["user"]).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_admin_api() -> AppResult<()> {
+    let db = test_db().await?;
+    let server = App::new()
+        .login_admin_api(&db, "admin")
+        .await
+        .inject(db.clone())
+        .as_test_server()
+        .await;
+    create_user(&db, "admin", "admin@test.com", "password", &["admin"]).await?;
+    create_user(&db, "user", "user@test.com", "password", &["user"]).await?;
+    let admin = login_token(&db, "admin", "password").await?;
+    let user = login_token(&db, "user", "password").await?;
+
+    server
+        .get("/api/admin/users")
+        .authorization_bearer(&user)
+        .await
+        .assert_status_unauthorized();
+    let page = server
+        .get("/api/admin/users?q=user")
+        .authorization_bearer(&admin)
+        .await
+        .json::<Value>();
+    assert_eq!(page["total"], 1);
+
+    server
+        .post("/api/admin/users")
+        .authorization_bearer(&admin)
+        .json(&json!({"username": "new", "email": "new@test.com", "password": "password", "roles": ["user"]}))
+        .await
+        .assert_status(StatusCode::CREATED);
+    server
+        .post("/api/admin/users")
+        .authorization_bearer(&admin)
+        .json(&json!({"username": "new", "email": "other@test.com", "password": "password", "roles": []}))
+        .await
+        .assert_status(StatusCode::CONFLICT);
+    server
+        .put("/api/admin/users/new/roles")
+        .authorization_bearer(&admin)
+        .json(&json!({"roles": ["admin"]}))
+        .await
+        .assert_status(StatusCode::NO_CONTENT);
+    let new = server
+        .get("/api/admin/users/new")
+        .authorization_bearer(&admin)
+        .await
+        .json::<Value>();
+    assert_eq!(new["roles"], json!(["admin"]));
+    let audit = server
+        .get("/api/admin/audit?username=new")
+        .authorization_bearer(&admin)
+        .await
+        .json::<Value>();
+    assert_eq!(audit["total"], 2);
+    let roles = server
+        .get("/api/admin/audit?event=roles_change")
+        .authorization_bearer(&admin)
+        .await
+        .json::<Value>();
+    assert_eq!(roles["records"][0]["username"], "new");
+    assert_eq!(roles["records"][0]["detail"], "admin by admin");
+    server
+        .get("/api/admin/audit")
+        .authorization_bearer(&user)
+        .await
+        .assert_status_unauthorized();
+
+    let invited = server
+        .post("/api/admin/invitations")
+        .authorization_bearer(&admin)
+        .json(&json!({"email": "invited@test.com", "roles": ["staff"]}))
+        .await;
+    invited.assert_status(StatusCode::CREATED);
+    let invited = invited.json::<Value>();
+    assert_eq!(invited["invited_by"], "admin");
+    let link = invited["link"].as_str().unwrap();
+    let (page, token) = link.split_once("?invitation=").unwrap();
+    assert!(page.ends_with("/register"));
+    assert_eq!(find_invitation(&db, token).await?.roles, vec!["staff"]);
+    let pending = server
+        .get("/api/admin/invitations")
+        .authorization_bearer(&admin)
+        .await
+        .json::<Value>();
+    assert_eq!(pending[0]["email"], "invited@test.com");
+    server
+        .delete(&format!(
+            "/api/admin/invitations/{}",
+            invited["id"].as_str().unwrap()
+        ))
+        .authorization_bearer(&admin)
+        .await
+        .assert_status(StatusCode::NO_CONTENT);
+    assert!(find_invitation(&db, token).await.is_err());
+    let uninvited = server
+        .get("/api/admin/audit?event=uninvite")
+        .authorization_bearer(&admin)
+        .await
+        .json::<Value>();
+    assert_eq!(uninvited["records"][0]["username"], "invited@test.com");
+    assert_eq!(
+        uninvited["records"][0]["detail"],
+        format!("{} by admin", invited["id"].as_str().unwrap())
+    );
+
+    server
+        .delete("/api/admin/users/new")
+        .authorization_bearer(&admin)
+        .await
+        .assert_status(StatusCode::NO_CONTENT);
+    let deleted = server
+        .get("/api/admin/audit?event=delete")
+        .authorization_bearer(&admin)
+        .await
+        .json::<Value>();
+    assert_eq!(deleted["records"][0]["username"], "new");
+    assert_eq!(deleted["records"][0]["detail"], "by admin");
+    let missing = server
+        .get("/api/admin/users/new")
+        .authorization_bearer(&admin)
+        .await;
+    missing.assert_status_not_found();
+    assert_eq!(missing.json::<Value>()["detail"], "User not found");
+    server
+        .put("/api/admin/users/new/disabled")
+        .authorization_bearer(&admin)
+        .json(&json!({"disabled": true}))
+        .await
+        .assert_status_not_found();
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use sqlx::Database;
+use velvet_web::prelude::*;
+
+// The login tests run on the database of the enabled features (see `Backend`): sqlite in memory,
+// or a new schema (postgres) or database (mysql) of POSTGRES_TEST_URL or MYSQL_TEST_URL, so that
+// every test starts from empty tables. For example:
+//   docker run --rm -p 5432:5432 -e POSTGRES_PASSWORD=pw postgres
+//   POSTGRES_TEST_URL=postgres://postgres:pw@localhost/postgres cargo test --features postgres,login
+//   docker run --rm -p 3306:3306 -e MYSQL_ROOT_PASSWORD=pw mysql
+//   MYSQL_TEST_URL=mysql://root:pw@localhost/mysql cargo test --features mysql,login
+
+#[cfg(all(feature = "sqlite", not(feature = "postgres"), not(feature = "mysql")))]
+pub async fn test_db() -> AppResult<Pool<Backend>> {
+    sqlite().await
+}
+
+#[cfg(feature = "postgres")]
+pub async fn test_db() -> AppResult<Pool<Backend>> {
+    let url = test_url("POSTGRES_TEST_URL");
+    let schema = unique_name();
+    let admin = postgres_with(DatabaseConfig::new(&url)).await?;
+    query(&format!("create schema {schema}"))
+        .execute(&admin)
+        .await?;
+    admin.close().await;
+    postgres_with(
+        DatabaseConfig::new(&url)
+            .max_connections(5)
+            .search_path(&schema),
+    )
+    .await
+}
+
+#[cfg(all(feature = "mysql", not(feature = "postgres")))]
+pub async fn test_db() -> AppResult<Pool<Backend>> {
+    let url = test_url("MYSQL_TEST_URL");
+    let database = unique_name();
+    let admin = mysql_with(DatabaseConfig::new(&url)).await?;
+    query(&format!("create database {database}"))
+        .execute(&admin)
+        .await?;
+    admin.close().await;
+    let (server, _) = url
+        .rsplit_once('/')
+        .expect("MYSQL_TEST_URL needs a database");
+    mysql_with(DatabaseConfig::new(&format!("{server}/{database}")).max_connections(5)).await
+}
+
+#[cfg(any(feature = "postgres", feature = "mysql"))]
+fn test_url(var: &str) -> String {
+    dotenvy::dotenv().ok();
+    std::env::var(var).unwrap_or_else(|_| panic!("{var} is required by the login tests"))
+}
+
+#[cfg(any(feature = "postgres", feature = "mysql"))]
+fn unique_name() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    format!("velvet_test_{nanos}")
+}
+
+/// The query with numbered placeholders on postgres, like the login module writes its own.
+#[allow(dead_code)]
+pub fn sql(query: &str) -> String {
+    if Backend::NAME != "PostgreSQL" {
+        return query.to_string();
+    }
+    let mut n = 0;
+    query
+        .split('?')
+        .enumerate()
+        .map(|(i, part)| {
+            if i == 0 {
+                part.to_string()
+            } else {
+                n += 1;
+                format!("${n}{part}")
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,266 @@
+#![cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+#![cfg(feature = "ldap")]
+
+mod login_db;
+
+use ldap3::asn1::{parse_tag, StructureTag, PL};
+use login_db::test_db;
+use serde::Deserialize;
+use serial_test::serial;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use velvet_web::prelude::*;
+
+#[derive(Deserialize)]
+struct Claims {
+    username: String,
+    roles: Vec<String>,
+}
+
+const USER_DN: &str = "uid={username},ou=people,dc=test";
+const GROUPS: &str = "ou=groups,dc=test";
+
+/// Directory of the LDAP stand-in: (dn, password, groups).
+const PEOPLE: &[(&str, &str, &[&str])] = &[
+    (
+        "uid=alice,ou=people,dc=test",
+        "alice-secret",
+        &["cn=admins,ou=groups,dc=test", "cn=staff,ou=groups,dc=test"],
+    ),
+    (
+        "uid=bob,ou=people,dc=test",
+        "bob-secret",
+        &["cn=staff,ou=groups,dc=test"],
+    ),
+];
+
+#[tokio::test]
+#[serial]
+async fn test_ldap_backend() -> AppResult<()> {
+    let db = test_db().await?;
+    login_setup(&db).await?;
+    let users = SqlUserStore::new(&db);
+    let url = ldap_stand_in().await;
+
+    let backend = LdapBackend::new(&url, USER_DN);
+    let roles = backend
+        .authenticate(&users, "alice", "alice-secret")
+        .await?;
+    assert_eq!(roles, vec!["user", "admins", "staff"]);
+    assert!(backend
+        .authenticate(&users, "alice", "wrong")
+        .await
+        .is_err());
+    assert!(backend.authenticate(&users, "alice", "").await.is_err());
+    assert!(backend
+        .authenticate(&users, "carol", "secret")
+        .await
+        .is_err());
+
+    let backend = LdapBackend::new(&url, USER_DN)
+        .group_search(GROUPS, "(member={dn})")
+        .group_role("admins", "admin")
+        .group_role("cn=staff,ou=groups,dc=test", "staff");
+    let roles = backend
+        .authenticate(&users, "alice", "alice-secret")
+        .await?;
+    assert_eq!(roles, vec!["user", "admin", "staff"]);
+    let roles = backend.authenticate(&users, "bob", "bob-secret").await?;
+    assert_eq!(roles, vec!["user", "staff"]);
+
+    let alice = get_user(&db, "alice").await?.unwrap();
+    assert_eq!(alice.roles, vec!["user", "admin", "staff"]);
+    // Provisioned users have no local password.
+    assert!(PasswordBackend
+        .authenticate(&users, "alice", "alice-secret")
+        .await
+        .is_err());
+    set_user_disabled(&db, &db, "alice", true).await?;
+    let disabled = backend
+        .authenticate(&users, "alice", "alice-secret")
+        .await
+        .unwrap_err();
+    assert_eq!(disabled.status(), StatusCode::UNAUTHORIZED);
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_ldap_login_flow() -> AppResult<()> {
+    #[derive(Serialize)]
+    struct LoginForm {
+        username: &'static str,
+        password: &'static str,
+    }
+    let db = test_db().await?;
+    JWT::Secret.setup().await?;
+    let url = ldap_stand_in().await;
+    set_credential_backend(
+        LdapBackend::new(&url, USER_DN)
+            .group_search(GROUPS, "(member={dn})")
+            .group_role("admins", "admin"),
+    );
+    let server = App::new()
+        .login_flow(&db, LoginConfig::new())
+        .await
+        .inject(db.clone())
+        .as_test_server()
+        .await;
+
+    server
+        .post("/login")
+        .form(&LoginForm {
+            username: "alice",
+            password: "wrong",
+        })
+        .await
+        .assert_status_unauthorized();
+    let login = server
+        .post("/login")
+        .form(&LoginForm {
+            username: "alice",
+            password: "alice-secret",
+        })
+        .await;
+    let claims = claims_for::<Claims>(login.cookie("token").value())?;
+    assert_eq!(claims.username, "alice");
+    assert_eq!(claims.roles, vec!["user", "admin"]);
+
+    let token = login_token(&db, "bob", "bob-secret").await?;
+    assert_eq!(claims_for::<Claims>(&token)?.roles, vec!["user"]);
+    set_credential_backend(PasswordBackend);
+    assert!(login_token(&db, "bob", "bob-secret").await.is_err());
+    Ok(())
+}
+
+/// Serves the PEOPLE directory with simple binds, base searches returning `memberOf`, and
+/// subtree searches of the groups having a `member`.
+async fn ldap_stand_in() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ldap://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(stream));
+        }
+    });
+    url
+}
+
+async fn serve(mut stream: TcpStream) {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        let Ok(read) = stream.read(&mut chunk).await else {
+            return;
+        };
+        if read == 0 {
+            return;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        while let Ok((rest, message)) = parse_tag(&buffer) {
+            let consumed = buffer.len() - rest.len();
+            let Some(reply) = reply(message) else {
+                return;
+            };
+            if stream.write_all(&reply).await.is_err() {
+                return;
+            }
+            buffer.drain(..consumed);
+        }
+    }
+}
+
+fn reply(message: StructureTag) -> Option<Vec<u8>> {
+    let mut parts = constructed(message).into_iter();
+    let id = primitive(parts.next()?);
+    let operation = parts.next()?;
+    match operation.id {
+        // Bind
+        0 => {
+            let mut bind = constructed(operation).into_iter().skip(1);
+            let dn = string(bind.next()?);
+            let password = string(bind.next()?);
+            let valid = PEOPLE
+                .iter()
+                .any(|(person, secret, _)| *person == dn && *secret == password);
+            Some(message_of(&id, 0x61, &result(if valid { 0 } else { 49 })))
+        }
+        // Search
+        3 => {
+            let mut search = constructed(operation).into_iter();
+            let base = string(search.next()?);
+            let filter = search.nth(5)?;
+            let mut reply = Vec::new();
+            if base == GROUPS {
+                let mut assertion = constructed(filter).into_iter().skip(1);
+                let member = string(assertion.next()?);
+                let groups = PEOPLE
+                    .iter()
+                    .filter(|(dn, _, _)| *dn == member)
+                    .flat_map(|(_, _, groups)| groups.iter());
+                for group in groups {
+                    let cn = group.split(',').next()?.trim_start_matches("cn=");
+                    reply.extend(message_of(&id, 0x64, &entry(group, "cn", &[cn])));
+                }
+            } else if let Some((dn, _, groups)) = PEOPLE.iter().find(|(dn, _, _)| *dn == base) {
+                reply.extend(message_of(&id, 0x64, &entry(dn, "memberOf", groups)));
+            }
+            reply.extend(message_of(&id, 0x65, &result(0)));
+            Some(reply)
+        }
+        // Unbind, or anything else closes the connection.
+        _ => None,
+    }
+}
+
+fn constructed(tag: StructureTag) -> Vec<StructureTag> {
+    match tag.payload {
+        PL::C(tags) => tags,
+        PL::P(_) => vec![],
+    }
+}
+
+fn primitive(tag: StructureTag) -> Vec<u8> {
+    match tag.payload {
+        PL::P(bytes) => bytes,
+        PL::C(_) => vec![],
+    }
+}
+
+fn string(tag: StructureTag) -> String {
+    String::from_utf8(primitive(tag)).unwrap()
+}
+
+fn ber(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    if content.len() < 0x80 {
+        encoded.push(content.len() as u8);
+    } else {
+        encoded.extend([0x82, (content.len() >> 8) as u8, content.len() as u8]);
+    }
+    encoded.extend_from_slice(content);
+    encoded
+}
+
+fn message_of(id: &[u8], operation: u8, content: &[u8]) -> Vec<u8> {
+    ber(0x30, &[ber(0x02, id), ber(operation, content)].concat())
+}
+
+fn result(code: u8) -> Vec<u8> {
+    [ber(0x0a, &[code]), ber(0x04, b""), ber(0x04, b"")].concat()
+}
+
+fn entry(dn: &str, attribute: &str, values: &[&str]) -> Vec<u8> {
+    let values = values
+        .iter()
+        .map(|v| ber(0x04, v.as_bytes()))
+        .collect::<Vec<_>>()
+        .concat();
+    let attribute = ber(
+        0x30,
+        &[ber(0x04, attribute.as_bytes()), ber(0x31, &values)].concat(),
+    );
+    [ber(0x04, dn.as_bytes()), ber(0x30, &attribute)].concat()
+}
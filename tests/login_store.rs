@@ -0,0 +1,105 @@
+#![cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+#![cfg(feature = "login")]
+
+mod login_db;
+
+use login_db::test_db;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use serial_test::serial;
+use velvet_web::prelude::*;
+
+#[derive(Deserialize)]
+struct Claims {
+    username: String,
+    roles: Vec<String>,
+}
+
+#[tokio::test]
+#[serial]
+async fn test_memory_user_store() -> AppResult<()> {
+    #[derive(Serialize)]
+    struct LoginForm {
+        username: &'static str,
+        password: &'static str,
+    }
+    let db = test_db().await?;
+    JWT::Secret.setup().await?;
+    let store = MemoryUserStore::default();
+    let config = LoginConfig::new().user_store(store.clone());
+    let server = App::new()
+        .login_flow(&db, config.clone())
+        .await
+        .login_admin_api_with_config(&db, "admin", config)
+        .await
+        .inject(db.clone())
+        .as_test_server()
+        .await;
+
+    let code = register_user(&store, "user", "user@test.com", "password").await?;
+    let taken = register_user(&store, "user", "other@test.com", "password")
+        .await
+        .unwrap_err();
+    assert_eq!(taken.status(), StatusCode::CONFLICT);
+    let resend = resend_confirmation(&store, "user").await.unwrap_err();
+    assert_eq!(resend.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(login_token(&store, "user", "password").await.is_err());
+    register_user_confirm(&store, "user", &code).await?;
+    assert!(register_user_confirm(&store, "user", &code).await.is_err());
+
+    let login = server
+        .post("/login")
+        .form(&LoginForm {
+            username: "user",
+            password: "password",
+        })
+        .await;
+    let claims = claims_for::<Claims>(login.cookie("token").value())?;
+    assert_eq!(claims.username, "user");
+    assert_eq!(claims.roles, vec!["user"]);
+
+    let code = request_email_change(&store, "user", "new@test.com").await?;
+    confirm_email_change(&store, "user", &code).await?;
+    assert_eq!(
+        find_username(&store, LoginWith::Email, "new@test.com").await?,
+        "user"
+    );
+
+    for _ in 0..5 {
+        assert!(login_token(&store, "user", "wrong").await.is_err());
+    }
+    let user = store.find("user").await?.unwrap();
+    assert_eq!(user.failed_logins, 5);
+    assert!(user.last_failed_login.is_some());
+    assert!(login_token(&store, "user", "password").await.is_ok());
+    assert_eq!(store.find("user").await?.unwrap().failed_logins, 0);
+
+    create_user(&store, "admin", "admin@test.com", "password", &["admin"]).await?;
+    let admin = login_token(&store, "admin", "password").await?;
+    let user = server
+        .get("/api/admin/users/user")
+        .authorization_bearer(&admin)
+        .await
+        .json::<Value>();
+    assert_eq!(user["email"], "new@test.com");
+    server
+        .put("/api/admin/users/user/disabled")
+        .authorization_bearer(&admin)
+        .json(&json!({ "disabled": true }))
+        .await
+        .assert_status(StatusCode::NO_CONTENT);
+    assert!(store.find("user").await?.unwrap().disabled);
+    assert!(login_token(&store, "user", "password").await.is_err());
+    set_user_disabled(&db, &store, "user", false).await?;
+    reset_user_password(&db, &store, "user", "new password").await?;
+    assert!(login_token(&store, "user", "new password").await.is_ok());
+    delete_user(&db, &store, "user").await?;
+    assert!(store.find("user").await?.is_none());
+
+    // Nothing was written to the login table.
+    let (users,): (i64,) = query_as("select count(*) from login")
+        .fetch_one(&db)
+        .await?;
+    assert_eq!(users, 0);
+    Ok(())
+}
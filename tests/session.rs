@@ -0,0 +1,85 @@
+#![cfg(feature = "auth")]
+
+#[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+#[cfg(feature = "login")]
+mod login_db;
+
+use serial_test::serial;
+use velvet_web::prelude::*;
+
+#[tokio::test]
+#[serial]
+async fn test_memory_session() -> AppResult<()> {
+    async fn count(jar: CookieJar, session: Session) -> AppResult<(CookieJar, String)> {
+        let mut session = session;
+        let count = session.get::<u32>("count").unwrap_or_default() + 1;
+        session.insert("count", count)?;
+        Ok((session.save(jar).await?, count.to_string()))
+    }
+    let mut server = App::new()
+        .route("/", get(count))
+        .sessions(Sessions::new(MemorySessionStore::default()))
+        .as_test_server()
+        .await;
+    server.save_cookies();
+    assert_eq!(server.get("/").await.text(), "1");
+    assert_eq!(server.get("/").await.text(), "2");
+    server.clear_cookies();
+    assert_eq!(server.get("/").await.text(), "1");
+    Ok(())
+}
+
+#[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+#[cfg(feature = "login")]
+#[tokio::test]
+#[serial]
+async fn test_login_session() -> AppResult<()> {
+    #[derive(Deserialize)]
+    struct Claims {
+        username: String,
+    }
+    #[derive(Serialize)]
+    struct LoginForm {
+        username: &'static str,
+        password: &'static str,
+    }
+    let db = login_db::test_db().await?;
+    let router = Router::new()
+        .route(
+            "/",
+            get(|session: Session| async move { session.claims::<Claims>().unwrap().username }),
+        )
+        .authorized_session_claims("/login", |_: Claims| Ok(AuthResult::OK));
+    let app = App::new()
+        .router(router)
+        .login_flow(&db, LoginConfig::new())
+        .await;
+    let sessions = Sessions::new(SqlSessionStore::new(&db).await?);
+    let mut server = app
+        .sessions(sessions)
+        .inject(db.clone())
+        .as_test_server()
+        .await;
+    server.save_cookies();
+    let code = register_user(&db, "user", "email", "password").await?;
+    register_user_confirm(&db, "user", &code).await?;
+
+    server.get("/").await.assert_status(StatusCode::SEE_OTHER);
+    let login = server
+        .post("/login")
+        .form(&LoginForm {
+            username: "user",
+            password: "password",
+        })
+        .await;
+    assert!(login.maybe_cookie("token").is_none());
+    assert_eq!(server.get("/").await.text(), "user");
+    let (sessions,): (i64,) = query_as("select count(*) from login_session")
+        .fetch_one(&db)
+        .await?;
+    assert!(sessions > 0);
+
+    server.get("/logout").await;
+    server.get("/").await.assert_status(StatusCode::SEE_OTHER);
+    Ok(())
+}
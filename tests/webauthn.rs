@@ -0,0 +1,293 @@
+#![cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+#![cfg(feature = "webauthn")]
+
+mod login_db;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::Value;
+use login_db::test_db;
+use ring::{
+    digest::{digest, SHA256},
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+};
+use serial_test::serial;
+use velvet_web::prelude::*;
+
+#[derive(Deserialize)]
+struct Claims {
+    username: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Options {
+    challenge: String,
+}
+
+/// A software authenticator holding a single ES256 credential.
+struct Authenticator {
+    key: EcdsaKeyPair,
+    credential_id: Vec<u8>,
+    counter: u32,
+    /// Passkeys synced between devices don't count their signatures.
+    counting: bool,
+    /// Whether the user gave a PIN or biometric.
+    verifying: bool,
+}
+
+impl Authenticator {
+    fn new() -> Self {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        Self {
+            key,
+            credential_id: b"software-credential".to_vec(),
+            counter: 0,
+            counting: true,
+            verifying: true,
+        }
+    }
+
+    fn without_counter(credential_id: &[u8]) -> Self {
+        Self {
+            credential_id: credential_id.to_vec(),
+            counting: false,
+            ..Self::new()
+        }
+    }
+
+    fn id(&self) -> String {
+        URL_SAFE_NO_PAD.encode(&self.credential_id)
+    }
+
+    fn auth_data(&mut self, flags: u8) -> Vec<u8> {
+        if self.counting {
+            self.counter += 1;
+        }
+        let mut data = digest(&SHA256, b"localhost").as_ref().to_vec();
+        data.push(flags);
+        data.extend_from_slice(&self.counter.to_be_bytes());
+        data
+    }
+
+    fn create(&mut self, challenge: &str) -> WebauthnRegistration {
+        let point = self.key.public_key().as_ref();
+        let cose_key = Value::Map(vec![
+            (Value::from(1), Value::from(2)),
+            (Value::from(3), Value::from(-7)),
+            (Value::from(-1), Value::from(1)),
+            (Value::from(-2), Value::Bytes(point[1..33].to_vec())),
+            (Value::from(-3), Value::Bytes(point[33..].to_vec())),
+        ]);
+        let mut auth_data = self.auth_data(0x41);
+        auth_data.extend_from_slice(&[0; 16]);
+        auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(&self.credential_id);
+        ciborium::into_writer(&cose_key, &mut auth_data).unwrap();
+        let attestation = Value::Map(vec![
+            (Value::from("fmt"), Value::from("none")),
+            (Value::from("attStmt"), Value::Map(vec![])),
+            (Value::from("authData"), Value::Bytes(auth_data)),
+        ]);
+        let mut attestation_object = vec![];
+        ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+        WebauthnRegistration {
+            id: self.id(),
+            client_data_json: client_data("webauthn.create", challenge),
+            attestation_object: URL_SAFE_NO_PAD.encode(attestation_object),
+        }
+    }
+
+    fn get(&mut self, challenge: &str) -> WebauthnAssertion {
+        let client_data_json = client_data("webauthn.get", challenge);
+        let flags = if self.verifying { 0x05 } else { 0x01 };
+        let auth_data = self.auth_data(flags);
+        let mut signed = auth_data.clone();
+        let raw_client_data = URL_SAFE_NO_PAD.decode(&client_data_json).unwrap();
+        signed.extend_from_slice(digest(&SHA256, &raw_client_data).as_ref());
+        let signature = self.key.sign(&SystemRandom::new(), &signed).unwrap();
+        WebauthnAssertion {
+            id: self.id(),
+            client_data_json,
+            authenticator_data: URL_SAFE_NO_PAD.encode(auth_data),
+            signature: URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        }
+    }
+}
+
+fn client_data(kind: &str, challenge: &str) -> String {
+    let json = format!(
+        r#"{{"type":"{kind}","challenge":"{challenge}","origin":"http://localhost:8080"}}"#
+    );
+    URL_SAFE_NO_PAD.encode(json)
+}
+
+fn challenge_of<T: Serialize>(options: &T) -> String {
+    let json = serde_json::to_string(options).unwrap();
+    serde_json::from_str::<Options>(&json).unwrap().challenge
+}
+
+fn allowed_of<T: Serialize>(options: &T) -> Vec<serde_json::Value> {
+    let json = serde_json::to_value(options).unwrap();
+    json["allowCredentials"].as_array().unwrap().clone()
+}
+
+#[tokio::test]
+#[serial]
+async fn test_register_and_login() -> AppResult<()> {
+    let db = test_db().await?;
+    JWT::Secret.setup().await?;
+    login_setup(&db).await?;
+    let code = register_user(&db, "user", "email", "password").await?;
+    register_user_confirm(&db, "user", &code).await?;
+    let mut authenticator = Authenticator::new();
+
+    let (state, options) = webauthn_register_start(&db, "user").await?;
+    let registration = authenticator.create(&challenge_of(&options));
+    webauthn_register_finish(&db, &state, &registration).await?;
+
+    let (state, options) = webauthn_login_start(&db, "user").await?;
+    let assertion = authenticator.get(&challenge_of(&options));
+    let token = webauthn_login_token(&db, &state, &assertion).await?;
+    assert_eq!(claims_for::<Claims>(&token)?.username, "user");
+
+    // a replayed assertion is rejected
+    assert!(webauthn_login_token(&db, &state, &assertion).await.is_err());
+
+    // an assertion signed over a different challenge is rejected
+    let (state, _) = webauthn_login_start(&db, "user").await?;
+    let assertion = authenticator.get("another-challenge");
+    assert!(webauthn_login_token(&db, &state, &assertion).await.is_err());
+
+    // an assertion without user verification is rejected
+    let (state, options) = webauthn_login_start(&db, "user").await?;
+    authenticator.verifying = false;
+    let assertion = authenticator.get(&challenge_of(&options));
+    let unverified = webauthn_login_token(&db, &state, &assertion).await;
+    assert_eq!(unverified.unwrap_err().status(), StatusCode::UNAUTHORIZED);
+    authenticator.verifying = true;
+
+    // the same credential can't be registered twice
+    let (state, options) = webauthn_register_start(&db, "user").await?;
+    let registration = authenticator.create(&challenge_of(&options));
+    let duplicate = webauthn_register_finish(&db, &state, &registration).await;
+    assert_eq!(duplicate.unwrap_err().status(), StatusCode::CONFLICT);
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_ceremony_used_once() -> AppResult<()> {
+    let db = test_db().await?;
+    JWT::Secret.setup().await?;
+    login_setup(&db).await?;
+    let code = register_user(&db, "user", "email", "password").await?;
+    register_user_confirm(&db, "user", &code).await?;
+    let mut authenticator = Authenticator::without_counter(b"synced-credential");
+
+    let (state, options) = webauthn_register_start(&db, "user").await?;
+    let registration = authenticator.create(&challenge_of(&options));
+    webauthn_register_finish(&db, &state, &registration).await?;
+    let replayed = webauthn_register_finish(&db, &state, &registration).await;
+    assert_eq!(replayed.unwrap_err().status(), StatusCode::UNAUTHORIZED);
+
+    let (state, options) = webauthn_login_start(&db, "user").await?;
+    let assertion = authenticator.get(&challenge_of(&options));
+    webauthn_login_token(&db, &state, &assertion).await?;
+    // the signature counter stays 0, the state itself can't be used again
+    let replayed = webauthn_login_token(&db, &state, &assertion).await;
+    assert_eq!(replayed.unwrap_err().status(), StatusCode::UNAUTHORIZED);
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_login_options_of_unknown_users() -> AppResult<()> {
+    let db = test_db().await?;
+    JWT::Secret.setup().await?;
+    login_setup(&db).await?;
+    let code = register_user(&db, "user", "email", "password").await?;
+    register_user_confirm(&db, "user", &code).await?;
+
+    // unknown users and users without passkeys get the same kind of options
+    let (_, unknown) = webauthn_login_start(&db, "nobody").await?;
+    let (_, again) = webauthn_login_start(&db, "nobody").await?;
+    let (_, without) = webauthn_login_start(&db, "user").await?;
+    assert_eq!(allowed_of(&unknown).len(), 1);
+    assert_eq!(allowed_of(&unknown), allowed_of(&again));
+    assert_eq!(allowed_of(&without).len(), 1);
+    assert_ne!(allowed_of(&unknown), allowed_of(&without));
+
+    let mut authenticator = Authenticator::new();
+    let (state, options) = webauthn_login_start(&db, "nobody").await?;
+    let assertion = authenticator.get(&challenge_of(&options));
+    assert!(webauthn_login_token(&db, &state, &assertion).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_impersonation_can_not_change_the_account() -> AppResult<()> {
+    #[derive(Serialize)]
+    struct LoginForm {
+        username: &'static str,
+        password: &'static str,
+    }
+    #[derive(Serialize)]
+    struct UsernameForm {
+        username: &'static str,
+    }
+    #[derive(Serialize)]
+    struct EmailForm {
+        email: &'static str,
+    }
+    std::env::set_var("MAIL_FROM", "test@test.com");
+    std::env::set_var("MAIL_HOST", "localhost");
+    let db = test_db().await?;
+    let mut server = App::new()
+        .login_flow_with_mail(&db, LoginConfig::new().impersonation("admin"))
+        .await
+        .inject(db.clone())
+        .as_test_server()
+        .await;
+    server.save_cookies();
+    create_user(&db, "root", "root@test.com", "password", &["admin"]).await?;
+    create_user(&db, "user", "user@test.com", "password", &["user"]).await?;
+
+    server
+        .post("/login")
+        .form(&LoginForm {
+            username: "root",
+            password: "password",
+        })
+        .await;
+    server
+        .post("/webauthn/register/options")
+        .await
+        .assert_status_ok();
+    server
+        .post("/impersonate")
+        .form(&UsernameForm { username: "user" })
+        .await
+        .assert_status_see_other();
+
+    server
+        .post("/webauthn/register/options")
+        .await
+        .assert_status_forbidden();
+    server
+        .post("/email")
+        .form(&EmailForm {
+            email: "root@test.com",
+        })
+        .await
+        .assert_status_forbidden();
+    assert_eq!(
+        get_user(&db, "user").await?.unwrap().email.as_deref(),
+        Some("user@test.com")
+    );
+    Ok(())
+}